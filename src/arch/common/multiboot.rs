@@ -315,6 +315,19 @@ pub fn parse_mboot_info(ptr: *const u32) -> Result<()> {
             );
         }
 
+        if info.flags & MULTIBOOT_INFO_CMDLINE != 0 {
+            let cmdline = core::ffi::CStr::from_ptr(info.cmdline as *const core::ffi::c_char);
+            crate::klib::cmdline::save(cmdline.to_bytes());
+            dbg!("Command line: {}", crate::klib::cmdline::raw());
+        }
+
+        // Only the first module is used, as the initrd
+        if info.flags & MULTIBOOT_INFO_MODS != 0 && info.mods_count > 0 {
+            let module = &*(info.mods_addr as *const MultibootModList);
+            dbg!("Initrd at {:x}-{:x}", { module.mod_start }, { module.mod_end });
+            crate::fs::initrd::set_image(module.mod_start as usize, module.mod_end as usize);
+        }
+
         if info.flags & MULTIBOOT_INFO_MEM_MAP != 0 {
             parse_memory_map(info);
         } else {
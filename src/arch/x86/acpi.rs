@@ -2,6 +2,7 @@ use crate::dbg;
 use crate::memory::vmm::{self, mapper};
 
 use super::apic;
+use super::hpet;
 use core::mem::size_of;
 use core::ptr::addr_of;
 use core::ptr;
@@ -107,6 +108,9 @@ pub fn init() -> Result<(), &'static str> {
                 "APIC" => {
                     apic::parse_madt(entry);
                 }
+                "HPET" => {
+                    hpet::init(entry);
+                }
                 _ => {
                     // TODO Unsupported tables
                 }
@@ -1,7 +1,9 @@
+use crate::klib::cmdline::Param;
 use crate::memory::pmm::Frame;
 use crate::memory::vmm;
 use crate::{dbg, PAGE_SIZE};
 
+use super::smp;
 use super::util;
 use core::mem::size_of;
 
@@ -13,6 +15,8 @@ enum RegLapic {
     ApicId = 0x20,
     Eoi = 0xb0,
     Spurious = 0xf0,  // interrupts which have no source
+    IcrLow = 0x300,   // interrupt command, writing it sends the IPI
+    IcrHigh = 0x310,  // destination of the IPI in the highest byte
     LVTTimer = 0x320, // timer and local interrupts
     InitTimer = 0x380,
     CurrentTimer = 0x390,
@@ -31,6 +35,14 @@ struct EntryHeader {
     length: u8,
 }
 
+#[repr(C, packed)]
+struct EntryLocalApic {
+    processor_id: u8,
+    apic_id: u8,
+    // bit 0 set if the processor is enabled
+    flags: u32,
+}
+
 #[repr(C, packed)]
 struct EntryIOAPIC {
     id: u8,
@@ -49,6 +61,9 @@ struct EntrySourceOverride {
     flags: u16,
 }
 
+/// Only use the bootstrap processor
+pub static NOSMP: Param<bool> = Param::new("nosmp", false);
+
 static mut IOAPIC_REMAP: usize = 0x0;
 #[inline(always)]
 fn ioapic_read_reg(reg: u32) -> u32 {
@@ -93,6 +108,45 @@ pub fn end_of_interrupt() {
     lapic_write_reg(RegLapic::Eoi, 0);
 }
 
+/// LAPIC id of the CPU running this
+pub fn id() -> u8 {
+    (lapic_read_reg(RegLapic::ApicId) >> 24) as u8
+}
+
+// Interrupt command register fields
+const ICR_INIT: u32 = 0x500;
+const ICR_STARTUP: u32 = 0x600;
+const ICR_ASSERT: u32 = 0x4000;
+const ICR_PENDING: u32 = 0x1000;
+
+/// Send an inter-processor interrupt to the CPU `apic_id`
+fn send_ipi(apic_id: u8, command: u32) {
+    lapic_write_reg(RegLapic::IcrHigh, (apic_id as u32) << 24);
+    lapic_write_reg(RegLapic::IcrLow, command);
+    while lapic_read_reg(RegLapic::IcrLow) & ICR_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Interrupt another CPU on `vector`
+pub fn send_fixed_ipi(apic_id: u8, vector: u32) {
+    send_ipi(apic_id, ICR_ASSERT | vector);
+}
+
+/// Start an application processor with INIT then two STARTUP IPIs, it begins in real mode at
+/// the physical address `page << 12`
+/// Only at boot, the delays spin on the PIT
+pub fn start_ap(apic_id: u8, page: u8) {
+    let pit_wait = super::timer::pit_wait;
+    send_ipi(apic_id, ICR_INIT | ICR_ASSERT);
+    pit_wait(10_000);
+    // The second one in case the first was missed, as the Intel MP spec says
+    for _ in 0..2 {
+        send_ipi(apic_id, ICR_STARTUP | ICR_ASSERT | page as u32);
+        pit_wait(200);
+    }
+}
+
 // TODO only for test purposes
 pub fn enable_ioapic_interrupts() {
     let low = ioapic_read_reg(0x12);
@@ -105,7 +159,9 @@ pub fn enable_ioapic_interrupts() {
     dbg!("LAPIC id {:x}", id);
 
     // TODO reserve ISA IRQs
-    ioapic_write_reg(0x12, 42);
+    ioapic_write_reg(0x12, crate::irq::KEYBOARD);
+    ioapic_write_reg(0x10 + 2 * 14, crate::irq::IDE_PRIMARY);
+    ioapic_write_reg(0x10 + 2 * 15, crate::irq::IDE_SECONDARY);
 
     // setting 11th bit of apic base to enable apic TODO sould be done
     // let (low, high) = util::readmsr(0x1b);
@@ -120,17 +176,28 @@ pub fn enable_lapic() {
     let (low, _high) = util::readmsr(util::msrid::LOCAL_APIC_BASE);
     let base = low as u32 & !0xfff;
     dbg!("MSR READING FOR LAPIC {:x}", base);
-    match vmm::mapper::phys_to_virt(base as usize) {
+    match vmm::mapper::io_remap(base as usize, PAGE_SIZE) {
         Some(ptr) => unsafe {
             LAPIC_REMAP = ptr;
         },
         None => panic!("Could not translate IOAPIC base address: {}", base),
     }
+    enable_local();
+    lapic_write_reg(RegLapic::LVTTimer, 0x43);
+}
+
+fn enable_local() {
     // Setting the last entry in IDT for the spurious interrupts with 0xff
     // setting the 8th bit to enable the local APIC
     let r = lapic_read_reg(RegLapic::Spurious) | 0xff | (1 << 8);
     lapic_write_reg(RegLapic::Spurious, r);
-    lapic_write_reg(RegLapic::LVTTimer, 0x43);
+}
+
+/// Local APIC of an application processor, its address is the same as the bootstrap one
+/// The IOAPIC keeps sending the device interrupts to the bootstrap processor
+pub fn init_ap() {
+    enable_local();
+    timer::start();
 }
 
 // Parse the MADT table
@@ -152,10 +219,16 @@ pub fn parse_madt(address: *const ACPISDTHeader) {
             let entry_header = &*eptr;
             let entry_addr = eptr as usize + size_of::<EntryHeader>();
             match entry_header.etype {
-                // 0x00 => {
-                //     // Local APIC
-
-                // }
+                0x00 => {
+                    // Local APIC, one per processor
+                    let entry: &EntryLocalApic = &*(entry_addr as *const EntryLocalApic);
+                    dbg!("Processor {} APIC id {}", { entry.processor_id }, {
+                        entry.apic_id
+                    });
+                    if entry.flags & 1 != 0 {
+                        smp::add_cpu(entry.apic_id);
+                    }
+                }
                 0x01 => {
                     // TODO manage this case
                     if IOAPIC_REMAP != 0 {
@@ -167,7 +240,8 @@ pub fn parse_madt(address: *const ACPISDTHeader) {
                     dbg!("IOAPIC gib {:x}", { ioptr.gib });
                     dbg!("IOAPIC phys address {:x}", { ioptr.address });
                     // IO APIC
-                    vmm::mapper::io_remap({ioptr.address} as usize, 1);
+                    IOAPIC_REMAP = vmm::mapper::io_remap({ ioptr.address } as usize, 0x20)
+                        .expect("Could not map the IOAPIC");
                 }
                 0x02 => {
                     let source: &EntrySourceOverride = &*(entry_addr as *const EntrySourceOverride);
@@ -189,53 +263,72 @@ pub fn parse_madt(address: *const ACPISDTHeader) {
 
 pub mod timer {
     use super::*;
-    use crate::io::{self, port};
+
+    /// Periodic mode in the LVT timer register, one-shot otherwise
+    const LVT_PERIODIC: u32 = 0x20000;
+
+    /// Timer count between two interrupts of the periodic tick, 0 if it is not used
+    static mut TICKS_PER_JIFFY: u32 = 0;
 
     #[allow(dead_code)]
     pub fn poll() -> u32 {
         lapic_read_reg(RegLapic::CurrentTimer)
     }
-    pub fn init() {
-        // Reseting the input gate
-        // let mut input_gate = io::inb(port::PITGate) & 0xfe;
-        // input_gate |= 1;
-        // io::outb(port::PITGate, input_gate);
-        // io::wait();
-
-        // set PIT chan 2 to mode 1 for one-shot
-        io::outb(port::PITCONTROL, 0b10110010);
-        io::wait();
-
-        // Sending number of ticks corresponding to 1/100s given PIT frequency
-        // Sending 2 bytes starting with LSB
-        io::outb(port::PITCHAN2, 0x9b);
-        io::wait();
-        io::outb(port::PITCHAN2, 0x2e);
-        io::wait();
 
-        lapic_write_reg(RegLapic::InitTimer, u32::MAX);
-
-        // dbg!("PIT TIMER STARTING COUNTDOWN");
-        let mut input_gate = io::inb(port::PITGATE) & 0xfe;
-        io::outb(port::PITGATE, input_gate);
-        input_gate |= 1;
-        io::outb(port::PITGATE, input_gate);
-        io::wait();
-
-        loop {
-            // 5th bit to 1 indicate that the counter reached 0
-            let b = io::inb(port::PITGATE) & 0b100000;
-            io::wait();
-            if b > 0 {
-                break;
-            };
+    /// Interrupt once after `jiffies` instead of periodically, fewer if they do not fit in the
+    /// counter, returns false if the LAPIC timer is not the tick
+    pub fn oneshot(jiffies: u64) -> bool {
+        let per_jiffy = unsafe { TICKS_PER_JIFFY } as u64;
+        if per_jiffy == 0 {
+            return false;
         }
+        let jiffies = jiffies.clamp(1, u32::MAX as u64 / per_jiffy);
+        lapic_write_reg(RegLapic::InitTimer, 0x0);
+        lapic_write_reg(RegLapic::LVTTimer, crate::irq::TIMER);
+        lapic_write_reg(RegLapic::InitTimer, (jiffies * per_jiffy) as u32);
+        true
+    }
+
+    /// Part of a jiffy left when the last idle period ended, by CPU
+    static mut LEFTOVER: [u32; smp::MAX_CPUS] = [0; smp::MAX_CPUS];
+
+    /// Back to the periodic tick, returns the whole jiffies that went by since `oneshot`
+    /// What is left of a jiffy is carried to the next idle period, so that jiffies do not
+    /// fall behind when interrupts end them early
+    pub fn periodic() -> u64 {
+        let per_jiffy = unsafe { TICKS_PER_JIFFY } as u64;
+        let elapsed = lapic_read_reg(RegLapic::InitTimer) - lapic_read_reg(RegLapic::CurrentTimer);
+        set_periodic(per_jiffy as u32);
+        // Interrupts are disabled, the CPU does not change
+        let leftover = unsafe { &mut LEFTOVER[smp::cpu_id()] };
+        let total = elapsed as u64 + *leftover as u64;
+        *leftover = (total % per_jiffy) as u32;
+        total / per_jiffy
+    }
+
+    fn set_periodic(per_jiffy: u32) {
+        lapic_write_reg(RegLapic::InitTimer, 0x0);
+        lapic_write_reg(RegLapic::LVTTimer, LVT_PERIODIC | crate::irq::TIMER);
+        lapic_write_reg(RegLapic::InitTimer, per_jiffy);
+    }
+
+    /// Periodic tick of an application processor, the LAPIC timers all run at the bus
+    /// frequency so the count calibrated by the bootstrap processor is used
+    pub fn start() {
+        set_periodic(unsafe { TICKS_PER_JIFFY });
+    }
+
+    pub fn init() {
+        lapic_write_reg(RegLapic::InitTimer, u32::MAX);
+        // Only at boot, with interrupts still disabled there is nothing to sleep on
+        super::super::timer::pit_wait(10_000);
         let ticks = u32::MAX - lapic_read_reg(RegLapic::CurrentTimer);
         // STOP THE COUNT !!!
-        lapic_write_reg(RegLapic::InitTimer, 0x0);
         // Init with calculated ticks
-        lapic_write_reg(RegLapic::InitTimer, ticks);
-        lapic_write_reg(RegLapic::LVTTimer, 0x20000 | 0x32);
+        set_periodic(ticks);
+        unsafe {
+            TICKS_PER_JIFFY = ticks;
+        }
         dbg!("LAPIC INIT TIMER {} ", lapic_read_reg(RegLapic::InitTimer));
     }
 }
@@ -1,9 +1,25 @@
 use core::{arch::asm, mem::{self}, mem::offset_of, usize};
 
 use alloc::sync::Arc;
+use alloc::vec;
+
+use super::gdt;
+use super::paging;
+use super::trap::TrapFrame;
 
 // TODO better stack size management, at higher level if possible
-pub const STACK_SIZE: usize = 4096;
+// Syscalls run on this stack for user tasks, filesystem code needs some room
+pub const STACK_SIZE: usize = 0x4000;
+
+/// Reserved bit 1 and interrupt enable flag
+const EFLAGS_USER: u32 = 0x202;
+
+extern "C" {
+    /// Defined in int.S, loads the user data segments and irets to ring 3
+    fn enter_user_mode();
+    /// Defined in int.S, pops a TrapFrame and irets
+    fn trap_return();
+}
 
 #[repr(C)]
 #[derive(Clone)]
@@ -22,6 +38,8 @@ pub struct Context {
     pub eip: u32,
     // pub cs: u32,
     pub eflags: u32,
+    // Physical address of the page directory
+    pub cr3: u32,
 
     // Segment registers
     // es: u32,
@@ -31,7 +49,7 @@ pub struct Context {
     // fs: u32,
     // gs: u32,
 
-    pub stack: Arc<[u8; STACK_SIZE]>
+    pub stack: Arc<[u8]>
     // TODO FPU, SSE et tutti quanti
 }
 
@@ -48,16 +66,22 @@ impl Default for Context {
             esp: 0,
             eip: 0,
             eflags: 0,
-            stack: Arc::new([0 as u8; STACK_SIZE]),
+            cr3: paging::kernel_pd_phys() as u32,
+            stack: new_stack(),
         }
     }
 }
 
+/// Allocate a kernel stack, without building it on the current stack first
+fn new_stack() -> Arc<[u8]> {
+    Arc::from(vec![0 as u8; STACK_SIZE])
+}
+
 impl Context {
 
     pub fn init_stack(&mut self) {
         // TODO remove, causes double allocation, only there to track down a bug in the list allocator
-        self.stack = Arc::new([0 as u8; STACK_SIZE]);
+        self.stack = new_stack();
         // klog!("New stack allocated at {:x}", self.stack.as_ptr() as usize);
         self.ebp = self.stack.as_ptr() as u32 + STACK_SIZE as u32;
         self.esp = self.ebp;
@@ -70,6 +94,40 @@ impl Context {
             // klog!("NEW ESP {:x}, stored value {:x}", self.esp, *((self.esp + 4) as *mut u32) );
         }
     }
+
+    /// Address right above the kernel stack
+    #[inline]
+    pub fn kernel_stack_top(&self) -> u32 {
+        self.stack.as_ptr() as u32 + STACK_SIZE as u32
+    }
+
+    /// Build the frame that will drop the task to ring 3 at `entry`, using `user_stack`
+    /// The first switch to this context returns into `enter_user_mode`
+    pub fn init_user_frame(&mut self, entry: u32, user_stack: u32) {
+        self.push(gdt::USER_DS as u32); // SS
+        self.push(user_stack); // ESP
+        self.push(EFLAGS_USER); // EFLAGS
+        self.push(gdt::USER_CS as u32); // CS
+        self.push(entry); // EIP
+        self.push(enter_user_mode as u32); // Return address from context_switch
+    }
+
+    /// Frame saved when the user task entered the kernel, at the top of its kernel stack
+    /// Only meaningful while a user task is in a syscall or an exception
+    pub fn user_frame(&self) -> &mut TrapFrame {
+        let address = self.kernel_stack_top() as usize - mem::size_of::<TrapFrame>();
+        unsafe { &mut *(address as *mut TrapFrame) }
+    }
+
+    /// Build a stack that returns to ring 3 with the registers of `frame`
+    /// Used by fork, the first switch to this context returns into `trap_return`
+    pub fn init_fork_frame(&mut self, frame: &TrapFrame) {
+        self.esp -= mem::size_of::<TrapFrame>() as u32;
+        unsafe {
+            *(self.esp as *mut TrapFrame) = *frame;
+        }
+        self.push(trap_return as u32); // Return address from context_switch
+    }
 }
 
 use crate::schedule;
@@ -93,8 +151,8 @@ extern "cdecl" fn switch_inner(prev: &mut Context, next: &Context) {
                 mov [ecx + {off_esi}], esi
                 mov [ecx + {off_edi}], edi
                 mov ebx, [edx + {off_ebx}]
-                mov esi, [edx + {off_ebx}]
-                mov edi, [edx + {off_ebx}]
+                mov esi, [edx + {off_esi}]
+                mov edi, [edx + {off_edi}]
                 
                 mov [ecx + {off_esp}], esp
                 mov [ecx + {off_ebp}], ebp
@@ -120,8 +178,14 @@ extern "cdecl" fn switch_inner(prev: &mut Context, next: &Context) {
 
 
 #[no_mangle]
-pub fn switch(prev: &mut Context, next: Context) {
+pub fn switch(prev: &mut Context, next: &Context) {
     // klog!("PREV EBP {:x} ESP {:x}", prev.ebp, prev.esp);
     // klog!("NEXT EBP {:x} ESP {:x}", next.ebp, next.esp);
-    switch_inner(prev, &next);
+    // Interrupts and syscalls from ring 3 will land on the next task's kernel stack
+    gdt::set_kernel_stack(next.kernel_stack_top());
+    // The kernel half is shared, so the stacks stay valid accross the switch
+    if paging::current_page_dir() != next.cr3 as usize {
+        paging::load_page_dir(next.cr3 as usize);
+    }
+    switch_inner(prev, next);
 }
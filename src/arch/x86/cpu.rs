@@ -132,3 +132,40 @@ pub fn cpuid_fetch() {
         }
     }
 }
+
+/// Read the time stamp counter
+#[inline(always)]
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+/// Whether the time stamp counter runs at the same rate whatever the power state
+pub fn invariant_tsc() -> bool {
+    let max: u32;
+    let edx: u32;
+    unsafe {
+        asm!(
+            "cpuid",
+            inout("eax") 0x8000_0000u32 => max,
+            out("ebx") _,
+            out("ecx") _,
+            out("edx") _,
+        );
+        if max < 0x8000_0007 {
+            return false;
+        }
+        asm!(
+            "cpuid",
+            inout("eax") 0x8000_0007u32 => _,
+            out("ebx") _,
+            out("ecx") _,
+            out("edx") edx,
+        );
+    }
+    edx & (1 << 8) != 0
+}
@@ -1,7 +1,9 @@
 // use crate::VGA_INSTANCE;
 // use core::fmt::Write;
+use core::arch::asm;
 use core::ptr::addr_of;
 use crate::klog;
+use super::smp::{self, MAX_CPUS};
 
 
 // TODO refacor using bitflags ?
@@ -17,6 +19,8 @@ pub mod af // aligned for 4kb
     pub const DPLM : u8 = 2 << 5;
     pub const DPLL : u8 = 1 << 5;
     pub const P : u8 = 1 << 7;
+    /// System segment type for an available 32 bit TSS, S must be cleared
+    pub const TSS32 : u8 = 0x9;
 }
 
 #[allow(dead_code)]
@@ -38,10 +42,91 @@ pub struct GdtEntry {
     base_high: u8,
 }
 
-const NENTRIES : usize = 5;
+const NENTRIES : usize = 7;
 
-static mut GDT : [GdtEntry; NENTRIES] = [
-    GdtEntry { limit_low: 0, base_low: 0, base_mid: 0, access: 0, flags_limit_high: 0, base_high: 0, }; NENTRIES];
+// Segment selectors, index in the GDT shifted by 3, ored with the requested privilege level
+pub const KERNEL_CS : u16 = 1 << 3;
+pub const KERNEL_DS : u16 = 2 << 3;
+pub const USER_CS : u16 = 3 << 3 | 3;
+pub const USER_DS : u16 = 4 << 3 | 3;
+pub const TSS_SELECTOR : u16 = 5 << 3;
+/// Loaded in FS in the kernel, its base is the number of the CPU, see `cpu_number`
+/// int.S loads it on each entry
+pub const PERCPU_SELECTOR : u16 = 6 << 3;
+
+/// Task State Segment
+/// Hardware task switching is not used, the TSS is only there to give the CPU the kernel stack
+/// to use when an interrupt or a syscall comes from ring 3
+#[repr(C, packed)]
+#[derive(Default)]
+pub struct Tss {
+    link: u32,
+    esp0: u32,
+    ss0: u32,
+    esp1: u32,
+    ss1: u32,
+    esp2: u32,
+    ss2: u32,
+    cr3: u32,
+    eip: u32,
+    eflags: u32,
+    eax: u32,
+    ecx: u32,
+    edx: u32,
+    ebx: u32,
+    esp: u32,
+    ebp: u32,
+    esi: u32,
+    edi: u32,
+    es: u32,
+    cs: u32,
+    ss: u32,
+    ds: u32,
+    fs: u32,
+    gs: u32,
+    ldtr: u32,
+    trap: u16,
+    iomap_base: u16,
+}
+
+const _: [u8; 104] = [0; core::mem::size_of::<Tss>()];
+
+const EMPTY_TSS : Tss = Tss {
+    link: 0, esp0: 0, ss0: 0, esp1: 0, ss1: 0, esp2: 0, ss2: 0, cr3: 0, eip: 0, eflags: 0,
+    eax: 0, ecx: 0, edx: 0, ebx: 0, esp: 0, ebp: 0, esi: 0, edi: 0,
+    es: 0, cs: 0, ss: 0, ds: 0, fs: 0, gs: 0, ldtr: 0, trap: 0, iomap_base: 0,
+};
+
+/// One per CPU, each one enters the kernel on the stack of the task it runs
+static mut TSS : [Tss; MAX_CPUS] = [EMPTY_TSS; MAX_CPUS];
+
+/// Set the stack the CPU switches to when entering the kernel from ring 3
+#[inline(always)]
+pub fn set_kernel_stack(esp0: u32)
+{
+    unsafe {
+        TSS[smp::cpu_id()].esp0 = esp0;
+    }
+}
+
+/// Number of each CPU, at the base of its per CPU segment
+static mut CPU_NUMBER : [usize; MAX_CPUS] = [0; MAX_CPUS];
+
+/// Number of the CPU running this, once `load` was called on it
+/// A single read through FS, without going to the LAPIC
+#[inline(always)]
+pub fn cpu_number() -> usize
+{
+    let cpu: usize;
+    unsafe {
+        asm!("mov {0}, fs:[0]", out(reg) cpu, options(nostack, readonly, preserves_flags));
+    }
+    cpu
+}
+
+/// One per CPU as well, the TSS descriptors differ and the busy bit is set by ltr
+static mut GDT : [[GdtEntry; NENTRIES]; MAX_CPUS] = [[
+    GdtEntry { limit_low: 0, base_low: 0, base_mid: 0, access: 0, flags_limit_high: 0, base_high: 0, }; NENTRIES]; MAX_CPUS];
 
 #[repr(C,packed)]
 struct Gdtr {
@@ -49,7 +134,7 @@ struct Gdtr {
     offset : u32, // offset of GDT (linear address, paging applies)
 }
 
-static mut GDTR : Gdtr = Gdtr {size : 0, offset : 0}; // pointer to the gdt table
+static mut GDTR : [Gdtr; MAX_CPUS] = [const { Gdtr {size : 0, offset : 0} }; MAX_CPUS]; // pointers to the gdt tables
 
 pub fn format_entry(base: u32, limit : u32, access_byte: u8, flags: u8) -> GdtEntry
 {
@@ -76,39 +161,62 @@ extern "C" {
     // fn load_gdt(gdtr: *const Gdtr);
     fn load_gdt(size: u16, offset: u32);
     fn reload_segments();
+    fn load_tss(selector: u16);
 }
 
-pub fn load()
+/// Set up and load the GDT of the CPU `cpu`, its number and not its APIC id
+pub fn load(cpu: usize)
 {
     // setup basic segments
     unsafe {
-        GDT[0] = format_entry(0, 0, 0, 0);
+        let gdt = &mut GDT[cpu];
+        let tss = &mut TSS[cpu];
+        let gdtr = &mut GDTR[cpu];
+        gdt[0] = format_entry(0, 0, 0, 0);
         // kernel code
-        GDT[1] = format_entry(0, 0xffffffff,
+        gdt[1] = format_entry(0, 0xffffffff,
                                 af::P | af::S | af::E | af::RW,
                                 f::DB | f::G);
         // kernel data
-        GDT[2] = format_entry(0, 0xffffffff,
+        gdt[2] = format_entry(0, 0xffffffff,
                                 af::P | af::S | af::RW,
                                 f::DB | f::G);
-        // user code TODO change permissions
-        GDT[1] = format_entry(0, 0xffffffff,
-                                af::P | af::S | af::E | af::RW,
+        // user code
+        gdt[3] = format_entry(0, 0xffffffff,
+                                af::P | af::DPLH | af::S | af::E | af::RW,
                                 f::DB | f::G);
-        // user data  TODO change permissions
-        GDT[2] = format_entry(0, 0xffffffff,
-                                af::P | af::S | af::RW,
+        // user data
+        gdt[4] = format_entry(0, 0xffffffff,
+                                af::P | af::DPLH | af::S | af::RW,
                                 f::DB | f::G);
-        GDTR.size = 8 * NENTRIES as u16 - 1;
-        GDTR.offset = GDT.as_ptr() as *const _ as u32;
+        // task state segment, the limit is in bytes
+        tss.ss0 = KERNEL_DS as u32;
+        // no IO permission bitmap
+        tss.iomap_base = core::mem::size_of::<Tss>() as u16;
+        gdt[5] = format_entry(addr_of!(*tss) as u32,
+                                core::mem::size_of::<Tss>() as u32 - 1,
+                                af::P | af::TSS32,
+                                0);
+        // per CPU data, kernel only
+        CPU_NUMBER[cpu] = cpu;
+        gdt[6] = format_entry(addr_of!(CPU_NUMBER[cpu]) as u32,
+                                core::mem::size_of::<usize>() as u32 - 1,
+                                af::P | af::S | af::RW,
+                                f::DB);
+        gdtr.size = 8 * NENTRIES as u16 - 1;
+        gdtr.offset = gdt.as_ptr() as *const _ as u32;
 
-        klog!("GDT poitner : {:x}", GDT.as_ptr() as *const _ as u32);
-        klog!("GDTR poitner : {:x}", addr_of!(GDTR) as *const _ as u32);
-        klog!("GDT size : {}", { GDTR.size });
-        klog!("GDT offset : {:x}", { GDTR.offset });
         // load_gdt(&GDTR);
-        load_gdt(GDTR.size, GDTR.offset);
-        reload_segments()
+        load_gdt(gdtr.size, gdtr.offset);
+        reload_segments();
+        load_tss(TSS_SELECTOR);
+        // Before logging, which looks up the CPU number
+        asm!("mov fs, {0:x}", in(reg) PERCPU_SELECTOR, options(nostack, preserves_flags));
+
+        klog!("GDT poitner : {:x}", gdt.as_ptr() as *const _ as u32);
+        klog!("GDTR poitner : {:x}", addr_of!(*gdtr) as *const _ as u32);
+        klog!("GDT size : {}", { gdtr.size });
+        klog!("GDT offset : {:x}", { gdtr.offset });
     }
     // unsafe {
     //     write!(VGA_INSTANCE.as_mut().unwrap(), "GDT poitner : {:x}\n", &GDT as *const _ as u32).unwrap();
@@ -0,0 +1,116 @@
+//! High Precision Event Timer, only used as a clock source for now
+//! TODO use its comparators for timer interrupts
+
+use super::acpi::ACPISDTHeader;
+use crate::dbg;
+use crate::klib::time::{self, ClockSource};
+use crate::memory::vmm::mapper;
+use core::ptr;
+
+#[repr(C, packed)]
+struct HpetTable {
+    h: ACPISDTHeader,
+    event_timer_block_id: u32,
+    // Generic address structure of the registers
+    address_space_id: u8,
+    register_bit_width: u8,
+    register_bit_offset: u8,
+    reserved: u8,
+    address: u64,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+const REG_CAPABILITIES: usize = 0x0;
+const REG_CONFIG: usize = 0x10;
+const REG_COUNTER: usize = 0xf0;
+
+/// Counter period in femtoseconds, in the high half of the capabilities
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+/// The spec does not allow slower counters
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const CAP_COUNTER_64: u64 = 1 << 13;
+const CONFIG_ENABLE: u64 = 1;
+
+struct Hpet;
+
+// Only written at boot, before the source is registered
+static mut HPET_BASE: usize = 0;
+static mut HPET_FREQUENCY: u64 = 0;
+
+fn read_reg(reg: usize) -> u32 {
+    unsafe { ptr::read_volatile((HPET_BASE + reg) as *const u32) }
+}
+
+fn write_reg(reg: usize, value: u32) {
+    unsafe { ptr::write_volatile((HPET_BASE + reg) as *mut u32, value) }
+}
+
+/// 64 bits registers take two reads on i386
+fn read_reg64(reg: usize) -> u64 {
+    (read_reg(reg + 4) as u64) << 32 | read_reg(reg) as u64
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn read(&self) -> u64 {
+        // The low half may wrap between the two reads
+        loop {
+            let high = read_reg(REG_COUNTER + 4);
+            let low = read_reg(REG_COUNTER);
+            if read_reg(REG_COUNTER + 4) == high {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+
+    fn frequency(&self) -> u64 {
+        unsafe { HPET_FREQUENCY }
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+}
+
+static HPET: Hpet = Hpet;
+
+/// Start the main counter described by the ACPI HPET table
+pub fn init(table: *const ACPISDTHeader) {
+    let table = unsafe { &*(table as *const HpetTable) };
+    // 0 is system memory, the other ones are not worth it
+    if table.address_space_id != 0 {
+        dbg!("HPET registers not in memory, ignored");
+        return;
+    }
+    let base = match mapper::io_remap(table.address as usize, 0x400) {
+        Some(base) => base,
+        None => {
+            dbg!("Could not map the HPET registers");
+            return;
+        }
+    };
+    unsafe {
+        HPET_BASE = base;
+    }
+
+    let capabilities = read_reg64(REG_CAPABILITIES);
+    let period = capabilities >> 32;
+    // A 32 bits counter wraps in a few minutes, too short for the clock
+    if capabilities & CAP_COUNTER_64 == 0 || period == 0 || period > MAX_PERIOD_FS {
+        dbg!("HPET not usable, capabilities {:x}", capabilities);
+        return;
+    }
+    unsafe {
+        HPET_FREQUENCY = FS_PER_SEC / period;
+    }
+
+    // Starts counting, without the legacy replacement routing
+    let config = read_reg(REG_CONFIG);
+    write_reg(REG_CONFIG, config | CONFIG_ENABLE as u32);
+    time::register(&HPET);
+}
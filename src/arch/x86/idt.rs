@@ -1,3 +1,5 @@
+use super::trap::TrapFrame;
+use crate::proc::process;
 use crate::{irq, dbg};
 use core::arch::asm;
 use core::ptr::addr_of;
@@ -32,6 +34,22 @@ static mut IDTR: IDTDESC = IDTDESC { size: 0, offset: 0 };
 
 extern "C" {
     fn fill_idt(idt_address: usize);
+    fn syscall_wrapper();
+}
+
+/// Vector used by programs to request kernel services
+pub const SYSCALL_VECTOR: usize = 0x80;
+
+/// Gate descriptor flags
+#[allow(dead_code)]
+pub mod gate {
+    pub const P: u8 = 1 << 7;
+    /// Gate can be reached with `int` from ring 3
+    pub const DPL3: u8 = 3 << 5;
+    /// Interrupts are disabled on entry
+    pub const INTERRUPT: u8 = 0xe;
+    /// Interrupts flag is left untouched on entry
+    pub const TRAP: u8 = 0xf;
 }
 
 #[repr(C)]
@@ -51,9 +69,39 @@ struct IRetExceptionFrame {
     code: u32
 }
 
+/// Page fault exception vector
+pub const PAGE_FAULT_VECTOR: u32 = 14;
+
+/// Called from the exception wrappers in int.S
 #[no_mangle]
-pub unsafe extern "C" fn exception_handler(code: u32, err_code: u32) {
-    dbg!("EXCEPTION! Irq={}(0x{:x}) Code={:x}", code, code, err_code);
+pub unsafe extern "C" fn exception_handler(frame: &mut TrapFrame) {
+    if frame.vector == PAGE_FAULT_VECTOR {
+        super::paging::page_fault_handler(frame);
+        return;
+    }
+    dbg!(
+        "EXCEPTION! Irq={}(0x{:x}) Code={:x} at {:x}",
+        frame.vector,
+        frame.vector,
+        frame.error_code,
+        frame.eip
+    );
+    // No signal handlers yet, the process is killed by the signal it would get
+    // NMI and machine checks are not the task's doing
+    if frame.from_user() && frame.vector != 2 && frame.vector != 18 {
+        let signal = match frame.vector {
+            // Divide error, x87 and SIMD floating point
+            0 | 16 | 19 => process::SIGFPE,
+            // Debug, breakpoint
+            1 | 3 => process::SIGTRAP,
+            // Invalid opcode
+            6 => process::SIGILL,
+            _ => process::SIGSEGV,
+        };
+        // Releasing the process may sleep
+        super::enable_interrupts();
+        process::exit_current(process::signaled(signal));
+    }
     loop{}
 }
 
@@ -67,7 +115,11 @@ pub unsafe extern "C" fn generic_handler(interrupt_code: u32) {
     }
     // Not Exception
     else {
-        super::apic::end_of_interrupt();
+        if super::pic::active() {
+            super::pic::eoi(interrupt_code - irq::ISA_BASE);
+        } else {
+            super::apic::end_of_interrupt();
+        }
         // TODO error handling here
         let _ = irq::top_handlers(interrupt_code);
     }
@@ -75,13 +127,17 @@ pub unsafe extern "C" fn generic_handler(interrupt_code: u32) {
 
 #[no_mangle]
 pub extern "C" fn fill_idt_entry(i: usize, handler_address: u32) {
+    set_gate(i, handler_address, gate::P | gate::INTERRUPT);
+}
+
+/// Install a gate in the kernel code segment
+pub fn set_gate(i: usize, handler_address: u32, flags: u8) {
     unsafe {
         IDT[i].offset_low = (handler_address & 0xffff) as u16;
         // index must be shifted by 3 bits because
         IDT[i].selector = 1 << 3;
         IDT[i].zero = 0;
-        // 8 is for present, e is for interrupt gate type
-        IDT[i].flags = 0x8e;
+        IDT[i].flags = flags;
         IDT[i].offset_high = (handler_address >> 16) as u16;
     }
 }
@@ -89,19 +145,31 @@ pub extern "C" fn fill_idt_entry(i: usize, handler_address: u32) {
 pub fn setup() {
     unsafe {
         fill_idt(IDT.as_ptr() as usize);
+        // Programs enter the kernel through a trap gate, so that interrupts stay enabled
+        set_gate(
+            SYSCALL_VECTOR,
+            syscall_wrapper as u32,
+            gate::P | gate::DPL3 | gate::TRAP,
+        );
 
         IDTR.size = (IDT.len() * core::mem::size_of::<IdtEntry>() - 1) as u16; // TODO why remove 1
         IDTR.offset = addr_of!(IDT) as *const _ as u32;
+        dbg!(
+            "IDT pointer : {:x}, size : {:x}",
+            addr_of!(IDTR) as *const _ as u32,
+            { IDTR.size }
+        );
+    }
+    load();
+}
 
+/// Load the IDT on this CPU, they all share the same one
+pub fn load() {
+    unsafe {
         asm!(
             "lidt [{0}]",
             in(reg) addr_of!(IDTR),
             options(nostack, preserves_flags)
         );
-        dbg!(
-            "IDT pointer : {:x}, size : {:x}",
-            addr_of!(IDTR) as *const _ as u32,
-            IDT.len() * core::mem::size_of::<IdtEntry>() - 1
-        );
     }
 }
@@ -73,7 +73,7 @@ pub mod port {
     // PS2 ports
     pub const PS2DATA: u16 = 0x60;
     // Read to get status, write to send command
-    pub const PS2CONTROL: u16 = 0x24;
+    pub const PS2CONTROL: u16 = 0x64;
 
     // PIC
     pub const PICMASTERCOMMAND: u16 = 0x20;
@@ -89,6 +89,10 @@ pub mod port {
     // bit 0
     pub const PITGATE: u16 = 0x61;
 
+    // CMOS, the register index then its value
+    pub const CMOSADDRESS: u16 = 0x70;
+    pub const CMOSDATA: u16 = 0x71;
+
     // PCI
     pub const PCICONFIG_ADDRESS: u16 = 0xCF8;
     pub const PCICONFIG_DATA: u16 = 0xCFC;
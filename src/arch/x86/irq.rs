@@ -1,5 +1,17 @@
 use alloc::vec::Vec;
 
+/// The ISA interrupts get the same vectors whether the PIC or the IOAPIC delivers them
+pub const ISA_BASE: u32 = 0x20;
+pub const TIMER: u32 = ISA_BASE;
+pub const KEYBOARD: u32 = ISA_BASE + 1;
+/// IDE channels in compatibility mode
+pub const IDE_PRIMARY: u32 = ISA_BASE + 14;
+pub const IDE_SECONDARY: u32 = ISA_BASE + 15;
+/// Sent to an idle CPU when there is a task for it
+pub const WAKEUP: u32 = 0xf0;
+/// Sent to the other CPUs when a kernel page is unmapped
+pub const TLB_SHOOTDOWN: u32 = 0xf1;
+
 // Used for initialization
 const ARRAY_REPEAT_VALUE: Vec<fn() -> Result<(), ()>> = Vec::new();
 /// Vector of all the registered interrupt handlers
@@ -8,12 +8,19 @@ use crate::memory;
 use crate::memory::pmm;
 use crate::memory::pmm::{Frame, FrameRange};
 use crate::memory::vmm;
+use crate::fs::initrd;
 use super::PAGE_SIZE;
 use super::idt;
 use super::gdt;
 use super::cpu;
 use super::pic;
 use super::acpi;
+use super::timer;
+use crate::klib::cmdline::Param;
+use crate::klib::log;
+
+/// Legacy PIC and PIT instead of the APIC, also skips ACPI
+static NOAPIC: Param<bool> = Param::new("noapic", false);
 
 extern "C" {
     /// Defined in linker file
@@ -64,6 +71,7 @@ pub extern "C" fn kstart(_magic: u32, mboot: *const u32) -> !
     if multiboot::parse_mboot_info(mboot).is_err() {
             panic!("Multiboot config error");
     }
+    log::init();
 
     dbg!("Physical Memory regions:");
     for _entry in memory::phys_mem().regions  {
@@ -72,7 +80,7 @@ pub extern "C" fn kstart(_magic: u32, mboot: *const u32) -> !
 
     // TODO should it be there
     dbg!("Loading GDT");
-    gdt::load();
+    gdt::load(0);
     dbg!("Loading IDT");
     idt::setup();
 
@@ -85,23 +93,41 @@ pub extern "C" fn kstart(_magic: u32, mboot: *const u32) -> !
     // Blocking out the first 4MB as they are already mapped and always will be
     // setting the first 4MB of PMM bitmap TODO api seems dirty
     pmm::fill_range(FrameRange{start: Frame(0), size: ROUND_PAGE_UP!(kend) / super::PAGE_SIZE});
+    // The initrd is unpacked once the kernel heap works
+    if let Some(frames) = initrd::frames() {
+        pmm::fill_range(frames);
+    }
+
+    dbg!("Allocating kernel page tables");
+    paging::init().expect("Could not allocate kernel page tables");
 
     dbg!("Initializing kernel allocator");
     // Sets up the virtual memory manager
     let memstart = ROUND_PAGE_UP!(kend);
     // TODO temp size
     vmm::init(memstart, crate::MB!(40));
+    pmm::init_refcounts(memory::phys_mem());
+
+    NOAPIC.register();
+    apic::NOSMP.register();
+    if NOAPIC.get() {
+        dbg!("APIC disabled, using the PIC and the PIT");
+        pic::enable_legacy();
+        timer::init_pit(100);
+    } else {
+        dbg!("Disabling PIC");
+        pic::disable();
+
+        // TODO this sets up the APIC behind the scene, make it more transparent
+        dbg!("Reading ACPI information");
+        acpi::init().unwrap();
+
+        dbg!("Setup APIC timer");
+        apic::timer::init();
+    }
 
-    dbg!("Disabling PIC");
-    pic::disable();
-
-    // TODO this sets up the APIC behind the scene, make it more transparent
-    dbg!("Reading ACPI information");
-    acpi::init().unwrap();
-
-    dbg!("Setup APIC timer");
-    apic::timer::init();
+    // The HPET registers itself with ACPI if there is one
+    timer::init_tsc();
 
-    loop{}
     crate::kmain();
 }
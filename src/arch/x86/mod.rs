@@ -8,10 +8,13 @@ pub mod kstart;
 pub mod lock;
 pub mod paging;
 pub mod pic;
+pub mod smp;
 pub mod timer;
+pub mod trap;
 
 mod acpi;
 mod apic;
+mod hpet;
 // mod bootmem;
 mod util;
 
@@ -58,7 +61,7 @@ pub const KERNEL_LINEAR_START: usize = 0xC0000000;
 /// The higher mapping will be swapped as needed, CF linux x86 memory model
 pub const KERNEL_TEMP_START: usize = 0xf0000000;
 // The last 4MB of virtual space will be used to remap some addresses for IO devices (eg. IOAPIC) 
-pub const KERNEL_IO_REMAP: usize = 0xffbfffff;
+pub const KERNEL_IO_REMAP: usize = 0xffc00000;
 
 pub const PAGE_SIZE: usize = 0x1000;
 pub const N_PAGES: usize = 1 << 20;
@@ -71,3 +74,28 @@ pub fn disable_interrupts() {
 pub fn enable_interrupts() {
     unsafe { asm!("sti") };
 }
+/// Wait for the next interrupt
+pub fn halt() {
+    unsafe { asm!("hlt") };
+}
+/// Enable interrupts and wait for the next one
+/// sti only takes effect after the next instruction, none can come in before the hlt
+pub fn enable_interrupts_and_halt() {
+    unsafe { asm!("sti", "hlt") };
+}
+/// Whether the interrupt flag is set
+pub fn interrupts_enabled() -> bool {
+    let eflags: u32;
+    unsafe { asm!("pushfd", "pop {0}", out(reg) eflags) };
+    eflags & (1 << 9) != 0
+}
+/// Run `f` with interrupts disabled, they are restored as they were afterwards
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = interrupts_enabled();
+    disable_interrupts();
+    let ret = f();
+    if enabled {
+        enable_interrupts();
+    }
+    ret
+}
@@ -1,11 +1,16 @@
+use super::trap::TrapFrame;
 use super::{KERNEL_LINEAR_START, PAGE_SIZE};
 use crate::error::{codes::*, Result};
-use crate::memory::pmm::{Frame, FrameRange};
-use crate::memory::vmm::mapper;
+use crate::klib::mem::{memcpy, memset};
+use crate::memory::pmm::{self, Frame, FrameRange, Zone};
+use crate::memory::vmm::mapper::{self, Prot};
+use crate::proc::process;
+use crate::schedule;
 use crate::MB;
 use crate::{dbg, klog, kprint};
 use bitflags::bitflags;
 use core::arch::asm;
+use core::ffi::c_void;
 
 // pub static mut MAPPER: RawBox<PageDir> = RawBox {
 //     data: 0 as *mut PageDir,
@@ -51,8 +56,6 @@ macro_rules! is_page_aligned {
 
 #[no_mangle]
 static mut KERNEL_PD: PageDir = PageDir::new();
-// Where IO will be remapped
-static mut IO_REMAP_PT: PageTable = PageTable::new();
 
 pub(crate) use ROUND_PAGE_UP;
 
@@ -84,6 +87,8 @@ bitflags! {
         const Dirty = 1 << 6;
         const PageAttribute = 1 << 7;
         const Global = 1 << 8;
+        /// Available to software, the page is shared copy on write
+        const Cow = 1 << 9;
         const _ = !0;
     }
 }
@@ -115,7 +120,7 @@ const _: [u8; 1024 * 4] = [0; core::mem::size_of::<PageDir>()];
 
 impl PDE {
     const fn new(address: u32, flags: PDEF) -> PDE {
-        PDE((address & !0x1fff) | flags.bits())
+        PDE((address & !0xfff) | flags.bits())
     }
 
     const fn has_flag(&mut self, flag: PDEF) -> bool {
@@ -154,9 +159,11 @@ impl PageDir {
                     continue;
                 }
 
-                let special = (0x3ff << 22) | i << 12;
                 dbg!("  Page table");
-                let pt: &mut PageTable = unsafe { &mut *(special as *mut PageTable) };
+                let pt = match self.page_table(i) {
+                    Some(pt) => pt,
+                    None => continue,
+                };
 
                 let mut count = -1;
                 let mut prev: u32 = entry;
@@ -197,74 +204,291 @@ fn flush_tlb() {
     }
 }
 
+/// Invalidate the TLB entry of a single page
+#[inline(always)]
+pub(super) fn invlpg(address: usize) {
+    unsafe {
+        asm!("invlpg [{0}]", in(reg) address, options(nostack, preserves_flags));
+    }
+}
+
 #[repr(C, packed)]
 struct PageTable {
     pub entries: [PTE; 1024],
 }
 
-impl PageTable {
-    const fn new() -> Self {
-        PageTable { entries: [0; 1024] }
-    }
-}
-
 #[inline(always)]
 pub fn kernel_mapper() -> &'static mut PageDir {
     // TODO clean up
     unsafe { &mut KERNEL_PD }
 }
 
+/// Physical address of the kernel page directory
+#[inline(always)]
+pub fn kernel_pd_phys() -> usize {
+    core::ptr::addr_of!(KERNEL_PD) as usize - KERNEL_LINEAR_START
+}
+
+/// Load a page directory, TLB entries for non global pages are flushed
+#[inline(always)]
+pub fn load_page_dir(phys: usize) {
+    unsafe {
+        asm!("mov cr3, {0}", in(reg) phys, options(nostack, preserves_flags));
+    }
+}
+
+/// Physical address of the active page directory
+#[inline(always)]
+pub fn current_page_dir() -> usize {
+    let phys: usize;
+    unsafe {
+        asm!("mov {0}, cr3", out(reg) phys, options(nomem, nostack, preserves_flags));
+    }
+    phys
+}
+
+impl PageDir {
+    /// Allocate a page directory with an empty user half
+    /// The kernel half entries are copied from the kernel page directory, so every kernel
+    /// page table must be in place before user address spaces are created
+    /// Returns the directory, accessed through the linear mapping, and its physical address
+    pub fn new_user() -> Result<(&'static mut PageDir, usize)> {
+        let frame = pmm::alloc_page(Zone::Normal)?;
+        let phys = frame.0 * PAGE_SIZE;
+        let pd = unsafe { &mut *((phys + KERNEL_LINEAR_START) as *mut PageDir) };
+        let kernel_start = pde_index!(KERNEL_LINEAR_START);
+        for i in 0..kernel_start {
+            pd.entries[i] = PDE(0);
+        }
+        for i in kernel_start..1024 {
+            pd.entries[i] = kernel_mapper().entries[i];
+        }
+        Ok((pd, phys))
+    }
+
+    /// Unmap the whole user half, releasing the frames and the page tables
+    /// The directory itself is left to the caller
+    pub fn clear_user(&mut self) {
+        for i in 0..pde_index!(KERNEL_LINEAR_START) {
+            if let Some(pt) = self.page_table(i) {
+                for j in 0..1024 {
+                    let pte = pt.entries[j];
+                    if pte & PTEF::Present.bits() != 0 {
+                        pmm::free_page(Frame((pte >> 12) as usize));
+                    }
+                }
+                pmm::free_page(Frame((self.entries[i].0 >> 12) as usize));
+            }
+            self.entries[i] = PDE(0);
+        }
+    }
+
+    /// Share the user half with `child`, copy on write
+    /// Writable pages become read-only in both directories and are copied on the first write
+    /// fault, every shared frame gets an extra reference
+    /// Read-only pages are marked too, so that mprotect cannot make a shared frame writable
+    /// Must be called on the active directory
+    pub fn clone_cow(&mut self, child: &mut PageDir) -> Result<()> {
+        for i in 0..pde_index!(KERNEL_LINEAR_START) {
+            let pt = match self.page_table(i) {
+                Some(pt) => pt,
+                None => continue,
+            };
+            let child_pt = child.page_table_alloc(i << 22)?;
+            for j in 0..1024 {
+                let mut pte = pt.entries[j];
+                if pte & PTEF::Present.bits() == 0 {
+                    continue;
+                }
+                pte = (pte & !PTEF::Write.bits()) | PTEF::Cow.bits();
+                pt.entries[j] = pte;
+                pmm::share_page(&Frame((pte >> 12) as usize));
+                child_pt.entries[j] = pte;
+            }
+        }
+        // Our own writable entries were downgraded
+        flush_tlb();
+        Ok(())
+    }
+
+    /// Resolve a write fault on a copy on write page
+    /// The last owner of a frame gets it back writable, the others get a private copy
+    /// Returns Ok(false) if the page is not copy on write
+    pub fn resolve_cow(&mut self, address: usize) -> Result<bool> {
+        let page = address & !(PAGE_SIZE - 1);
+        let pt = match self.page_table(pde_index!(page)) {
+            Some(pt) => pt,
+            None => return Ok(false),
+        };
+        let pte_index = pte_index!(page);
+        let pte = pt.entries[pte_index];
+        if pte & PTEF::Present.bits() == 0 || pte & PTEF::Cow.bits() == 0 {
+            return Ok(false);
+        }
+
+        let old = Frame((pte >> 12) as usize);
+        let flags = (pte & 0xfff & !PTEF::Cow.bits()) | PTEF::Write.bits();
+        if pmm::page_refcount(&old) == 1 {
+            pt.entries[pte_index] = (pte & !0xfff) | flags;
+        } else {
+            dbg!("Copy on write of {:x}", page);
+            let frame = pmm::alloc_page(Zone::Normal)?;
+            let phys = frame.0 * PAGE_SIZE;
+            memcpy(
+                (phys + KERNEL_LINEAR_START) as *mut c_void,
+                (old.0 * PAGE_SIZE + KERNEL_LINEAR_START) as *const c_void,
+                PAGE_SIZE,
+            );
+            pt.entries[pte_index] = phys as u32 | flags;
+            pmm::free_page(old);
+        }
+        invlpg(page);
+        Ok(true)
+    }
+
+    /// Get the page table of a directory entry through the linear mapping
+    /// Returns None if the entry is not present or maps a 4MB page
+    fn page_table(&self, pde_index: usize) -> Option<&'static mut PageTable> {
+        let pde = self.entries[pde_index];
+        if pde.0 & PDEF::Present.bits() == 0 || pde.0 & PDEF::PageSize.bits() != 0 {
+            return None;
+        }
+        let pt_phys = (pde.0 & !0xfff) as usize;
+        Some(unsafe { &mut *((pt_phys + KERNEL_LINEAR_START) as *mut PageTable) })
+    }
+
+    /// Get the page table for the address, allocating it if needed
+    fn page_table_alloc(&mut self, address: usize) -> Result<&'static mut PageTable> {
+        let pde_index = pde_index!(address);
+        if self.entries[pde_index].0 & PDEF::Present.bits() == 0 {
+            let frame = pmm::alloc_page(Zone::Normal)?;
+            let pt_phys = frame.0 * PAGE_SIZE;
+            // Avoid the page table being filled with junk
+            memset(
+                (pt_phys + KERNEL_LINEAR_START) as *mut c_void,
+                0,
+                PAGE_SIZE,
+            );
+            let mut flags = PDEF::Present | PDEF::Write;
+            if address < KERNEL_LINEAR_START {
+                flags |= PDEF::User;
+            }
+            self.entries[pde_index] = PDE::new(pt_phys as u32, flags);
+        }
+        // 4MB pages of the linear mapping cannot be split
+        self.page_table(pde_index).ok_or(EEXIST)
+    }
+}
+
 impl mapper::MapperInterface for PageDir {
     /// Map a single physical frame to a virtual address
+    /// Pages below the kernel linear mapping are accessible from ring 3
     fn map_single(&mut self, f: Frame, address: usize) -> Result<()> {
-        todo!();
-        // let phys_address = f.0 * PAGE_SIZE;
-        // dbg!("Mapping virt {:x} to phys {:x}", address, phys_address);
-        // if let Some(mapped) = self.virt_to_phys(address) {
-        //     dbg!(
-        //         "Mapping already mapped address {:x}, currently mapped to {:x} ",
-        //         address,
-        //         mapped
-        //     );
-        //     panic!("Mapping already mapped page");
-        // }
-        // if !is_page_aligned!(address) {
-        //     return Err(());
-        // }
-        // let pde_index = pde_index!(address);
-        // let pt = unsafe { &mut (*get_kernel_pt(pde_index)) };
-        // if self.entries[pde_index] == 0 {
-        //     self.entries[pde_index] =
-        //         self.virt_to_phys(pt as *const PageTable as usize).unwrap() | 3;
-        //     // Avoid the page table being filled with junk
-        //     memset(pt as *const PageTable as *mut c_void, 0, 4096);
-        // }
-        // pt.entries[pte_index!(address)] = phys_address | 3;
-
-        // // self.dump_dbg();
-        // flush_tlb();
-        // Ok(())
-    }
-
-    /// Map a single page and release its physical frame
+        let mut prot = Prot::READ | Prot::WRITE | Prot::EXEC;
+        if address < KERNEL_LINEAR_START {
+            prot |= Prot::USER;
+        }
+        self.map_single_prot(f, address, prot)
+    }
+
+    /// Map a single physical frame to a virtual address
+    /// Without PAE, pages are always readable and executable
+    fn map_single_prot(&mut self, f: Frame, address: usize, prot: Prot) -> Result<()> {
+        if !is_page_aligned!(address) {
+            return Err(EFAULT);
+        }
+        let phys_address = f.0 * PAGE_SIZE;
+        dbg!("Mapping virt {:x} to phys {:x}", address, phys_address);
+
+        // Addresses in the linear mapping are already mapped to their frame
+        if self.entries[pde_index!(address)].0 & PDEF::PageSize.bits() != 0 {
+            return match self.virt_to_phys(address) {
+                Some(mapped) if mapped == phys_address => Ok(()),
+                _ => Err(EEXIST),
+            };
+        }
+
+        let pt = self.page_table_alloc(address)?;
+        let pte_index = pte_index!(address);
+        if pt.entries[pte_index] & PTEF::Present.bits() != 0 {
+            dbg!("Mapping already mapped address {:x}", address);
+            return Err(EEXIST);
+        }
+        let mut flags = PTEF::Present;
+        if prot.contains(Prot::WRITE) {
+            flags |= PTEF::Write;
+        }
+        if prot.contains(Prot::USER) {
+            flags |= PTEF::User;
+        } else if address >= KERNEL_LINEAR_START {
+            flags |= PTEF::Global;
+        }
+        pt.entries[pte_index] = phys_address as u32 | flags.bits();
+        invlpg(address);
+        Ok(())
+    }
+
+    /// Unmap a single page and release its physical frame
     fn unmap_single(&mut self, address: usize) -> Result<()> {
         if !is_page_aligned!(address) {
             return Err(EFAULT);
         }
-        dbg!("Unmapping a single frame");
-        // Making sure that the page is mapped
-        let address = self.virt_to_phys(address).ok_or(EFAULT)?;
+        dbg!("Unmapping virt {:x}", address);
 
-        // free the entry in the page directory
-        // Use recursive mapping to get to the corresponding page table
-        // The offset is to 0 so we have the start of the table
-        let special = (0x3ff << 22) | pde_index!(address) << 12;
-        let pt: &mut PageTable = unsafe { &mut *(special as *mut PageTable) };
+        // The linear mapping stays in place, only the frame is given back
+        if self.entries[pde_index!(address)].0 & PDEF::PageSize.bits() != 0 {
+            let phys = self.virt_to_phys(address).ok_or(EFAULT)?;
+            pmm::free_page(Frame(phys / PAGE_SIZE));
+            return Ok(());
+        }
 
-        todo!();
+        let pt = self.page_table(pde_index!(address)).ok_or(EFAULT)?;
+        let pte_index = pte_index!(address);
+        let pte = pt.entries[pte_index];
+        if pte & PTEF::Present.bits() == 0 {
+            return Err(EFAULT);
+        }
+        pt.entries[pte_index] = 0;
+        // Harmless if this directory is not the active one
+        invlpg(address);
+        // Shared by all the directories, the other CPUs may have it cached too
+        if address >= KERNEL_LINEAR_START {
+            super::smp::flush_tlb_others(address);
+        }
 
         // Release the physical frame
-        flush_tlb();
+        pmm::free_page(Frame((pte >> 12) as usize));
+        Ok(())
+    }
+
+    /// Change the access rights of a mapped page
+    /// Copy on write pages stay read-only until they are written to, pages without any right
+    /// are kept for the kernel only
+    fn set_prot(&mut self, address: usize, prot: Prot) -> Result<()> {
+        let pt = self.page_table(pde_index!(address)).ok_or(EFAULT)?;
+        let pte_index = pte_index!(address);
+        let mut pte = pt.entries[pte_index];
+        if pte & PTEF::Present.bits() == 0 {
+            return Err(EFAULT);
+        }
+        pte &= !(PTEF::Write | PTEF::User).bits();
+        if prot.contains(Prot::WRITE) {
+            // A shared frame is copied on the first write, never written in place
+            let shared = pmm::page_refcount(&Frame((pte >> 12) as usize)) > 1;
+            if shared || pte & PTEF::Cow.bits() != 0 {
+                pte |= PTEF::Cow.bits();
+            } else {
+                pte |= PTEF::Write.bits();
+            }
+        }
+        if prot.contains(Prot::USER) && prot.intersects(Prot::READ | Prot::WRITE | Prot::EXEC) {
+            pte |= PTEF::User.bits();
+        }
+        pt.entries[pte_index] = pte;
+        invlpg(address);
+        if address >= KERNEL_LINEAR_START {
+            super::smp::flush_tlb_others(address);
+        }
         Ok(())
     }
 
@@ -272,7 +496,7 @@ impl mapper::MapperInterface for PageDir {
     fn unmap_range(&mut self, address: usize, npages: usize) -> Result<()> {
         let mut ptr = address;
         for _ in 0..npages {
-            self.unmap_single(ptr).unwrap();
+            self.unmap_single(ptr)?;
             ptr += PAGE_SIZE;
         }
         Ok(())
@@ -317,54 +541,43 @@ impl mapper::MapperInterface for PageDir {
             return Some(addr);
         }
 
-        let pt = unsafe {
-            &*((self.entries[pde_index].0 as usize + super::KERNEL_LINEAR_START)
-                as *const PageTable)
-        };
-        let addr = pt.entries[pte_offset] as usize + offset;
+        let pt = self.page_table(pde_index)?;
+        let pte = pt.entries[pte_offset];
+        if pte & PTEF::Present.bits() == 0 {
+            return None;
+        }
+        let addr = (pte & !0xfff) as usize + offset;
         dbg!("Virt_to_phys output {:x}", addr);
         Some(addr)
     }
 
     // TODO implement translation of IO Mapping ?
+    /// Devices above the linear mapping have to go through `io_remap`
     fn phys_to_virt(&self, address: usize) -> Option<usize> {
-        if address > super::KERNEL_TEMP_START {
-            todo!();
+        if address >= super::KERNEL_TEMP_START - KERNEL_LINEAR_START {
             return None;
         }
         Some(KERNEL_LINEAR_START + address)
     }
 
     //TODO this API feels clunky, might switch to a higher level
+    /// Map device memory in the IO window, uncached
     fn io_remap(&mut self, f: FrameRange) -> Option<usize> {
-        let mut start: usize = 0;
-        let mut size: usize = 0;
-        let mut i = 0;
-        while i < 1024 {
-            unsafe {
-                if IO_REMAP_PT.entries[i] == 0 {
-                    start = i;
-                    size = 0;
-                    while i < 1024 && IO_REMAP_PT.entries[i] == 0 {
-                        size += 1;
-                        i += 1;
-                        // found a slot that match our requirement
-                        if f.size == size {
-                            self.map_range(
-                                FrameRange {
-                                    start: Frame(start),
-                                    size,
-                                },
-                                super::KERNEL_IO_REMAP + start * PAGE_SIZE,
-                            )
-                            .unwrap();
-                        }
-                    }
-                    size = 0;
-                }
-            }
+        let pt = self.page_table(pde_index!(super::KERNEL_IO_REMAP))?;
+        // First run of free entries long enough
+        let mut run = 0;
+        let last = (0..1024).find(|&i| {
+            run = if pt.entries[i] == 0 { run + 1 } else { 0 };
+            run == f.size
+        })?;
+        let start = last + 1 - f.size;
+        for i in 0..f.size {
+            let phys = (f.start.0 + i) * PAGE_SIZE;
+            let flags = PTEF::Present | PTEF::Write | PTEF::CacheDisable | PTEF::Global;
+            pt.entries[start + i] = phys as u32 | flags.bits();
+            invlpg(super::KERNEL_IO_REMAP + (start + i) * PAGE_SIZE);
         }
-        panic!("impossile to IO remap!");
+        Some(super::KERNEL_IO_REMAP + start * PAGE_SIZE)
     }
 }
 
@@ -410,11 +623,7 @@ pub extern "C" fn setup_early_paging() {
         );
     }
 
-    // Last entry is remaping for IO
-    pd_ptr.entries[1023] = PDE::new(
-        core::ptr::addr_of_mut!(KERNEL_PD) as u32 - super::KERNEL_LINEAR_START as u32,
-        PDEF::Present | PDEF::PageSize | PDEF::Write | PDEF::Global,
-    );
+    // The last entry, for IO, gets a page table in `init`
     activate_paging();
     // because paranoia is sometimes the right attitude
     flush_tlb();
@@ -425,6 +634,8 @@ pub extern "C" fn setup_early_paging() {
 pub extern "C" fn activate_paging() {
     unsafe {
         // enable pse, move KERNEL_PD to cr3 and enable paging bit
+        // WP is set as well so that the kernel faults on read-only user pages, which copy
+        // on write relies on
         asm!(
             concat!(
                 "
@@ -436,7 +647,7 @@ pub extern "C" fn activate_paging() {
             mov cr3, eax
 
             mov eax, cr0
-            or eax, 0x80000001
+            or eax, 0x80010001
             mov cr0, eax"
             ),
             in("ecx") 1 << 4,
@@ -445,6 +656,19 @@ pub extern "C" fn activate_paging() {
     }
 }
 
+/// Allocate the kernel page tables above the linear mapping
+/// User page directories copy the kernel half when they are created, so those tables must
+/// never change afterwards for kernel mappings to be seen by every address space
+pub fn init() -> Result<()> {
+    let pd = kernel_mapper();
+    // The last one is the IO window
+    for i in pde_index!(super::KERNEL_TEMP_START)..1024 {
+        pd.page_table_alloc(i << 22)?;
+    }
+    flush_tlb();
+    Ok(())
+}
+
 /// Remove the early identity mapping since we're in higher half now
 pub fn cleanup_post_jump() {
     unsafe {
@@ -455,16 +679,99 @@ pub fn cleanup_post_jump() {
     }
 }
 
+/// Identity map the first 4MB again, or remove them, while the application processors turn
+/// paging on from the trampoline
+pub fn set_low_identity(mapped: bool) {
+    unsafe {
+        KERNEL_PD.entries[0] = if mapped {
+            PDE::new(0, PDEF::Present | PDEF::PageSize | PDEF::Write)
+        } else {
+            PDE::new(0, PDEF::empty())
+        };
+        flush_tlb();
+    }
+}
+
+extern "C" {
+    // Defined in routines.S
+    fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    static copy_user_insn: u8;
+    static copy_user_fixup: u8;
+}
+
+/// Copy from the current user space, fails with EFAULT where the page fault cannot be
+/// resolved instead of faulting in the kernel
+pub fn read_user(dst: &mut [u8], address: usize) -> Result<()> {
+    match unsafe { copy_user(dst.as_mut_ptr(), address as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
+/// Copy to the current user space, fails with EFAULT where the page fault cannot be
+/// resolved instead of faulting in the kernel
+pub fn write_user(address: usize, src: &[u8]) -> Result<()> {
+    match unsafe { copy_user(address as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
 // TODO is this code archiecture specific ?
-pub fn page_fault_handler(_instruction_pointer: u32, code: u32) {
+pub fn page_fault_handler(frame: &mut TrapFrame) {
     let address: u32;
-    klog!("PAGE FAULT EXCEPTION");
     unsafe {
         asm!("mov {0}, cr2", out(reg) address);
     }
+    let code = frame.error_code;
+    let flags = PF::from_bits(code).unwrap();
+
+    // User pages, either not mapped yet or shared copy on write
+    let res = if (address as usize) < KERNEL_LINEAR_START {
+        // Filling the page may sleep on the disk, the #PF gate disabled interrupts
+        // Not if the faulting code had them disabled, it may hold an irqsave lock
+        if frame.eflags & (1 << 9) != 0 {
+            super::enable_interrupts();
+        }
+        // The space must not be kept referenced here if the process is killed
+        schedule::current_space()
+            .map(|space| space.write().handle_fault(address as usize, flags.contains(PF::W)))
+    } else {
+        // Kernel pages are never filled on demand
+        None
+    };
+    match res {
+        Some(Ok(())) => return,
+        // A syscall copying from or to the program, the copy fails and the syscall with it
+        // The task must not be killed there, it may hold locks
+        Some(Err(_))
+            if !frame.from_user()
+                && frame.eip as usize == core::ptr::addr_of!(copy_user_insn) as usize =>
+        {
+            frame.eip = core::ptr::addr_of!(copy_user_fixup) as u32;
+            return;
+        }
+        _ => {}
+    }
+
+    // Whatever the address, a program does not get to stop the kernel
+    if frame.from_user() {
+        // No signals yet, the process is killed
+        klog!(
+            "Process {:?} segmentation fault at {:x}, ip {:x}, error code {:x}",
+            schedule::current_pid(),
+            address,
+            frame.eip,
+            code
+        );
+        super::enable_interrupts();
+        process::exit_current(process::signaled(process::SIGSEGV));
+    }
+
+    super::disable_interrupts();
+    klog!("PAGE FAULT EXCEPTION");
     klog!("Virtual address : {:p}", (address as *const u32));
     kprint!("Error code: "); // TODO reformat in the future
-    let flags = PF::from_bits(code).unwrap();
     kprint!(
         "{} ",
         if flags.contains(PF::P) {
@@ -66,6 +66,25 @@ pub fn setup() {
     pic_remap(0x20 as i8, 0x28 as i8); // the first 32 interrupts are reserved for the CPU exceptions
 }
 
+/// Set when the PIC delivers the interrupts instead of the APIC
+static mut ACTIVE: bool = false;
+
+#[inline]
+pub fn active() -> bool {
+    unsafe { ACTIVE }
+}
+
+/// Use the PIC for the timer, the keyboard and the disks, for machines booted with noapic
+pub fn enable_legacy() {
+    setup();
+    // timer, keyboard and the cascade to the slave
+    io::outb(port::PICMASTERDATA, !0b111);
+    // both IDE channels
+    io::outb(port::PICSLAVEDATA, !0b1100_0000);
+    io::wait();
+    unsafe { ACTIVE = true };
+}
+
 pub fn disable() {
     setup();
     io::outb(port::PICMASTERDATA, 0xFF);
@@ -0,0 +1,203 @@
+//! Application processors, the CPUs other than the one the bootloader started
+//! They are found in the MADT, and started once the scheduler is ready through a real mode
+//! trampoline copied in low memory, then they run tasks like the bootstrap processor
+//! CPUs are numbered from 0 for the bootstrap one, in the order they are started
+
+use super::context::STACK_SIZE;
+use super::{apic, gdt, idt, irq, paging, timer};
+use crate::memory::vmm::mapper;
+use crate::{dbg, klog};
+use alloc::vec;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub const MAX_CPUS: usize = 8;
+
+/// Where the trampoline is copied, the startup IPI gives it as a page number
+const TRAMPOLINE_ADDR: usize = 0x8000;
+/// How long an application processor has to start, in milliseconds
+const BOOT_TIMEOUT_MS: u32 = 100;
+
+extern "C" {
+    // Defined in trampoline.S
+    static trampoline_start: u8;
+    static trampoline_end: u8;
+    static trampoline_cr3: u32;
+    static trampoline_stack: u32;
+}
+
+/// LAPIC ids of the CPUs found in the MADT, by CPU number once they are started
+static mut APIC_IDS: [u8; MAX_CPUS] = [0; MAX_CPUS];
+static mut NFOUND: usize = 0;
+/// CPUs running, the bootstrap one included
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+/// Number of the CPU being started, it reads it in `ap_start`
+static BOOTING: AtomicUsize = AtomicUsize::new(0);
+/// Set by the CPU being started once it is done with the trampoline
+static BOOTED: AtomicBool = AtomicBool::new(false);
+/// Taken by the CPU doing a TLB shootdown, one at a time
+static SHOOTDOWN_BUSY: AtomicBool = AtomicBool::new(false);
+/// Page to drop from the TLB, and the CPUs that did not do it yet, one bit each
+static SHOOTDOWN_ADDR: AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Record a processor found in the MADT, at boot
+pub fn add_cpu(apic_id: u8) {
+    unsafe {
+        if NFOUND == MAX_CPUS {
+            klog!("Too many processors, APIC id {} ignored", apic_id);
+            return;
+        }
+        APIC_IDS[NFOUND] = apic_id;
+        NFOUND += 1;
+    }
+}
+
+/// Number of the CPU running this
+/// The task may move to another CPU right after, unless interrupts or preemption are disabled
+/// Called on every lock, it does not look at the LAPIC but at the number `gdt::load` stored
+pub fn cpu_id() -> usize {
+    // Before the GDT is loaded
+    if ONLINE.load(Ordering::Acquire) == 1 {
+        return 0;
+    }
+    gdt::cpu_number()
+}
+
+/// Number of CPUs running
+pub fn online() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Make an idle CPU look for tasks, the interrupt gets it out of hlt
+pub fn wake_cpu(cpu: usize) {
+    if cpu < online() {
+        apic::send_fixed_ipi(unsafe { APIC_IDS[cpu] }, irq::WAKEUP);
+    }
+}
+
+/// Nothing to do, the scheduler runs after the interrupt
+fn wakeup_handler() -> Result<(), ()> {
+    Ok(())
+}
+
+/// Drop the page of the current shootdown from the TLB, if this CPU has not done it yet
+fn shootdown_pending(cpu: usize) {
+    if SHOOTDOWN_PENDING.load(Ordering::Acquire) & 1 << cpu != 0 {
+        paging::invlpg(SHOOTDOWN_ADDR.load(Ordering::Acquire));
+        SHOOTDOWN_PENDING.fetch_and(!(1 << cpu), Ordering::AcqRel);
+    }
+}
+
+fn shootdown_handler() -> Result<(), ()> {
+    shootdown_pending(cpu_id());
+    Ok(())
+}
+
+/// Make the other CPUs drop a kernel page from their TLB, returns once they all did
+/// Their interrupts must be able to come: never with a spinning lock they may take held
+pub fn flush_tlb_others(address: usize) {
+    let online = online();
+    if online == 1 {
+        return;
+    }
+    super::without_interrupts(|| {
+        let cpu = cpu_id();
+        // Another CPU may be waiting for this one meanwhile
+        while SHOOTDOWN_BUSY
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            shootdown_pending(cpu);
+            core::hint::spin_loop();
+        }
+        let others = ((1 << online) - 1) & !(1 << cpu);
+        SHOOTDOWN_ADDR.store(address, Ordering::Release);
+        SHOOTDOWN_PENDING.store(others, Ordering::Release);
+        for other in (0..online).filter(|&c| c != cpu) {
+            apic::send_fixed_ipi(unsafe { APIC_IDS[other] }, irq::TLB_SHOOTDOWN);
+        }
+        while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+        SHOOTDOWN_BUSY.store(false, Ordering::Release);
+    });
+}
+
+/// Address of a trampoline symbol once it is copied
+fn trampoline_addr(symbol: *const u8) -> usize {
+    let start = addr_of!(trampoline_start) as usize;
+    mapper::phys_to_virt(TRAMPOLINE_ADDR).unwrap() + symbol as usize - start
+}
+
+/// Start the application processors one after the other, they join the scheduler right away
+/// Called once the scheduler is ready, with interrupts disabled
+pub fn start_secondary_cpus() {
+    let nfound = unsafe { NFOUND };
+    if nfound <= 1 {
+        return;
+    }
+    if apic::NOSMP.get() {
+        klog!("{} processors, SMP disabled", nfound);
+        return;
+    }
+    // The bootstrap processor is CPU 0, whatever its place in the MADT
+    let bsp = apic::id();
+    unsafe {
+        if let Some(i) = APIC_IDS[..nfound].iter().position(|&a| a == bsp) {
+            APIC_IDS.swap(0, i);
+        }
+    }
+    // TODO error handling
+    let _ = irq::request_irq_top(irq::WAKEUP, wakeup_handler);
+    let _ = irq::request_irq_top(irq::TLB_SHOOTDOWN, shootdown_handler);
+
+    // Below 1MB, already reserved as the pmm blocks everything before the kernel end
+    unsafe {
+        let start = addr_of!(trampoline_start);
+        let len = addr_of!(trampoline_end) as usize - start as usize;
+        core::ptr::copy_nonoverlapping(start, trampoline_addr(start) as *mut u8, len);
+        *(trampoline_addr(addr_of!(trampoline_cr3) as *const u8) as *mut u32) =
+            paging::kernel_pd_phys() as u32;
+    }
+    // The trampoline turns paging on while running from its physical address
+    paging::set_low_identity(true);
+    for cpu in 1..nfound {
+        let apic_id = unsafe { APIC_IDS[cpu] };
+        // Never freed, the CPU keeps running on it as its idle task
+        let stack = vec![0u8; STACK_SIZE].leak();
+        unsafe {
+            *(trampoline_addr(addr_of!(trampoline_stack) as *const u8) as *mut u32) =
+                stack.as_ptr() as u32 + STACK_SIZE as u32;
+        }
+        BOOTING.store(cpu, Ordering::Release);
+        BOOTED.store(false, Ordering::Release);
+        apic::start_ap(apic_id, (TRAMPOLINE_ADDR >> 12) as u8);
+        let mut waited = 0;
+        while !BOOTED.load(Ordering::Acquire) && waited < BOOT_TIMEOUT_MS {
+            timer::pit_wait(1000);
+            waited += 1;
+        }
+        if !BOOTED.load(Ordering::Acquire) {
+            // TODO it may still start later and take the number of the next one
+            klog!("CPU {} (APIC id {}) did not start", cpu, apic_id);
+            break;
+        }
+    }
+    paging::set_low_identity(false);
+    klog!("{} processors online", online());
+}
+
+/// Where the trampoline jumps, on the stack given by the bootstrap processor
+#[no_mangle]
+extern "C" fn ap_start() -> ! {
+    let cpu = BOOTING.load(Ordering::Acquire);
+    // From now on cpu_id reads the number from FS, gdt::load sets it before anything else
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    gdt::load(cpu);
+    idt::load();
+    apic::init_ap();
+    dbg!("CPU {} started, APIC id {}", cpu, apic::id());
+    BOOTED.store(true, Ordering::Release);
+    crate::proc::schedule::start_cpu(cpu)
+}
@@ -1,4 +1,105 @@
+use super::apic;
+use super::cpu;
+use super::io::{self, port};
+use crate::klib::time::{self, ClockSource};
 
+// pub fn sleep_seconds() {}
 
+/// Input frequency of the PIT, in Hz
+const PIT_FREQUENCY: u32 = 1193182;
 
-// pub fn sleep_seconds() {}
+/// Periodic interrupts from PIT channel 0 on IRQ 0, used when the APIC is not
+pub fn init_pit(hz: u32) {
+    let divisor = PIT_FREQUENCY / hz;
+    // channel 0, lobyte/hibyte, mode 2 rate generator
+    io::outb(port::PITCONTROL, 0b00110100);
+    io::wait();
+    io::outb(port::PITCHAN0, (divisor & 0xff) as u8);
+    io::wait();
+    io::outb(port::PITCHAN0, (divisor >> 8) as u8);
+    io::wait();
+}
+
+/// Stop the periodic tick and interrupt once after `jiffies` instead
+/// Returns false if the timer cannot, the PIT is always periodic
+pub fn stop_tick(jiffies: u64) -> bool {
+    apic::timer::oneshot(jiffies)
+}
+
+/// Back to the periodic tick, returns the jiffies that went by since `stop_tick`
+pub fn restart_tick() -> u64 {
+    apic::timer::periodic()
+}
+
+/// Busy wait for `us` microseconds on PIT channel 2, up to about 54ms
+/// Only for calibrating the other timers at boot, when there is nothing better to wait on
+pub fn pit_wait(us: u32) {
+    let count = (PIT_FREQUENCY as u64 * us as u64 / 1_000_000).clamp(1, 0xffff);
+
+    // set PIT chan 2 to mode 1 for one-shot
+    io::outb(port::PITCONTROL, 0b10110010);
+    io::wait();
+    // Sending 2 bytes starting with LSB
+    io::outb(port::PITCHAN2, (count & 0xff) as u8);
+    io::wait();
+    io::outb(port::PITCHAN2, (count >> 8) as u8);
+    io::wait();
+
+    // The count starts on the rising edge of the gate
+    let mut input_gate = io::inb(port::PITGATE) & 0xfe;
+    io::outb(port::PITGATE, input_gate);
+    input_gate |= 1;
+    io::outb(port::PITGATE, input_gate);
+
+    // 5th bit to 1 indicate that the counter reached 0
+    while io::inb(port::PITGATE) & 0b100000 == 0 {}
+}
+
+/// Time stamp counter, calibrated against the PIT
+struct Tsc;
+
+// Only written at boot, before the source is registered
+static mut TSC_FREQUENCY: u64 = 0;
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn read(&self) -> u64 {
+        // The counters of the CPUs may not be synchronized, the clock never goes back though
+        cpu::rdtsc()
+    }
+
+    fn frequency(&self) -> u64 {
+        unsafe { TSC_FREQUENCY }
+    }
+
+    fn rating(&self) -> u32 {
+        // Older ones change speed with the power states
+        if cpu::invariant_tsc() {
+            300
+        } else {
+            100
+        }
+    }
+}
+
+static TSC: Tsc = Tsc;
+
+/// Calibrate the TSC and use it as a clock source if there is one
+pub fn init_tsc() {
+    if !cpu::has_feature(cpu::Flags::TSC2) {
+        return;
+    }
+    let start = cpu::rdtsc();
+    pit_wait(10_000);
+    let frequency = (cpu::rdtsc() - start) * 100;
+    if frequency == 0 {
+        return;
+    }
+    unsafe {
+        TSC_FREQUENCY = frequency;
+    }
+    time::register(&TSC);
+}
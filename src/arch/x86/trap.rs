@@ -0,0 +1,76 @@
+use crate::syscall;
+
+/// Registers saved by `syscall_wrapper` and the exception wrappers in int.S
+/// The field order follows the push order, the last pushed comes first
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TrapFrame {
+    // pushad
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    // esp before pushad, ignored by popad
+    pub kernel_esp: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+    // Segment registers
+    pub gs: u32,
+    pub fs: u32,
+    pub es: u32,
+    pub ds: u32,
+    pub vector: u32,
+    // Pushed by the CPU for some exceptions, 0 otherwise
+    pub error_code: u32,
+    // Pushed by the CPU
+    pub eip: u32,
+    pub cs: u32,
+    pub eflags: u32,
+    // Only valid when coming from ring 3
+    pub user_esp: u32,
+    pub user_ss: u32,
+}
+
+// Compile time check, int.S relies on this layout
+const _: [u8; 76] = [0; core::mem::size_of::<TrapFrame>()];
+
+impl TrapFrame {
+    /// Whether the trap interrupted ring 3 code
+    #[inline]
+    pub fn from_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+
+    /// Return to ring 3 at `entry` with the stack `stack` and cleared registers
+    pub fn reset_user(&mut self, entry: u32, stack: u32) {
+        self.edi = 0;
+        self.esi = 0;
+        self.ebp = 0;
+        self.ebx = 0;
+        self.edx = 0;
+        self.ecx = 0;
+        self.eax = 0;
+        self.eip = entry;
+        self.user_esp = stack;
+    }
+
+    /// Syscall number and arguments, in the i386 Linux order
+    pub fn args(&self) -> syscall::Args {
+        syscall::Args([
+            self.ebx as usize,
+            self.ecx as usize,
+            self.edx as usize,
+            self.esi as usize,
+            self.edi as usize,
+            self.ebp as usize,
+        ])
+    }
+}
+
+/// Called from `syscall_wrapper`
+#[no_mangle]
+pub extern "C" fn syscall_handler(frame: &mut TrapFrame) {
+    let ret = syscall::dispatch(frame.eax as usize, &frame.args());
+    frame.eax = ret as u32;
+}
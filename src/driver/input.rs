@@ -1,3 +1,5 @@
+use crate::klib::lock::SpinLock;
+use crate::proc::wait::WaitQueue;
 use alloc::collections::VecDeque;
 
 #[derive(Debug)]
@@ -5,22 +7,29 @@ pub enum InputEvent {
     Keyboard(u32)
 }
 
-// TODO thread safety
 // TODO remove need for new allocations as input queuing will tak place in interrupt context
-static mut QUEUE: VecDeque<InputEvent> = VecDeque::new();
+static QUEUE: SpinLock<VecDeque<InputEvent>> = SpinLock::new(VecDeque::new());
+/// Readers waiting for an event
+static READERS: WaitQueue = WaitQueue::new();
 
 pub fn push_event(ev: InputEvent) {
-    unsafe {
-        QUEUE.push_back(ev);
-    }
+    QUEUE.lock_irqsave().push_back(ev);
+    READERS.wake_all();
+}
+
+/// Take the oldest pending event
+pub fn pop_event() -> Option<InputEvent> {
+    // The queue is filled from the keyboard interrupt
+    QUEUE.lock_irqsave().pop_front()
+}
+
+/// Take the oldest event, sleeping until there is one
+pub fn wait_event() -> InputEvent {
+    READERS.wait_for(pop_event)
 }
 
 // TODO implement limit ?
 #[allow(dead_code)]
 pub fn process_input_events() {
-    unsafe {
-        while !QUEUE.is_empty() {
-            let _v = QUEUE.pop_front().unwrap();
-        }
-    }
+    QUEUE.lock_irqsave().clear();
 }
@@ -18,6 +18,35 @@ fn read_conf_byte() -> u8 {
     io::inb(port::PS2DATA)
 }
 
+// Scancode set 1, which is what the controller gives us with translation on
+// TODO keymaps
+const KEYMAP: [u8; 0x3a] = *b"\x00\x1b1234567890-=\x08\tqwertyuiop[]\n\x00asdfghjkl;'`\x00\\zxcvbnm,./\x00*\x00 ";
+const KEYMAP_SHIFT: [u8; 0x3a] = *b"\x00\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\x00ASDFGHJKL:\"~\x00|ZXCVBNM<>?\x00*\x00 ";
+
+const LSHIFT: u8 = 0x2a;
+const RSHIFT: u8 = 0x36;
+const RELEASED: u8 = 0x80;
+
+static mut SHIFT: bool = false;
+
+/// Turn a scancode into the character typed, if any
+/// Only meant to be called from a single consumer, it tracks the state of the shift keys
+pub fn decode(scancode: u8) -> Option<u8> {
+    let key = scancode & !RELEASED;
+    if key == LSHIFT || key == RSHIFT {
+        unsafe { SHIFT = scancode & RELEASED == 0 };
+        return None;
+    }
+    if scancode & RELEASED != 0 {
+        return None;
+    }
+    let map = if unsafe { SHIFT } { &KEYMAP_SHIFT } else { &KEYMAP };
+    match map.get(key as usize) {
+        Some(&c) if c != 0 => Some(c),
+        _ => None,
+    }
+}
+
 fn int_handler() -> Result<(), ()> {
     let event = read_data();
     input::push_event(input::InputEvent::Keyboard(event as u32));
@@ -30,8 +59,7 @@ pub fn init() -> Result<(), ()> {
     if conf & (1 << 6) != 0 {
         // panic!("PS2 translation enabled");
     }
-    // TODO remap to sensible number
-    if irq::request_irq_top(42, int_handler).is_err() {
+    if irq::request_irq_top(irq::KEYBOARD, int_handler).is_err() {
         panic!("Could not init keyboard driver!");
     }
     Ok(())
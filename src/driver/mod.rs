@@ -4,6 +4,7 @@ pub mod input;
 pub mod vga;
 pub mod kbd;
 pub mod timer;
+pub mod rtc;
 pub mod pci_ide;
 pub mod pci;
 
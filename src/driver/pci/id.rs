@@ -65,7 +65,7 @@ fn enumerate_functions(bus_num: u8, dev_num: u8) {
                 kind: identify_class(&conf),
             };
 
-            let mut pci_devs = super::PCI_DEVICES.write().unwrap();
+            let mut pci_devs = super::PCI_DEVICES.write();
             pci_devs.push(new_dev);
             drop(pci_devs);
             // Multi-function device
@@ -44,7 +44,7 @@ pub static PCI_DEVICES: RwLock<Vec<PCIDevice>> = RwLock::new(vec![]);
 pub fn init() {
     // TODO check ACPI tables for PCI support, it's assumed there
     id::enumerate();
-    let devices = PCI_DEVICES.read().unwrap();
+    let devices = PCI_DEVICES.read();
     klog!("{} PCI devices detected", devices.len());
     for dev in devices.iter() {
         klog!("{:?}", dev);
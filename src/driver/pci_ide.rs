@@ -1,9 +1,13 @@
+use crate::arch;
 use crate::driver::pci::{config::BarType, PCIDevice};
+use crate::driver::timer;
+use crate::error::{codes::{EINVAL, EIO}, Result, EUNKNOWN};
 use crate::fs::block;
 use crate::io::{Pio, PortIO};
-use crate::klib::lock::RwLock;
+use crate::irq;
+use crate::klib::lock::{Mutex, RwLock};
 use crate::klog;
-use crate::error::{Result, EUNKNOWN};
+use crate::proc::wait::WaitQueue;
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
@@ -82,9 +86,12 @@ struct AlignedArray {
 
 pub struct IDEController {
     // only two bus supported, sorry ATA/IDE/PATA afficionados
-    pub buses: [Arc<RwLock<Bus>>; 2],
+    pub buses: [Arc<Mutex<Bus>>; 2],
 }
 
+/// Time a drive has to complete a command
+const COMMAND_TIMEOUT_MS: u64 = 5000;
+
 // One ATA bus, used to interact with two drives
 #[allow(dead_code)]
 pub struct Bus {
@@ -109,7 +116,9 @@ pub struct Bus {
     pub dma_prdt: [Pio<u8>; 4],
     // Remember if master or slave is active
     pub active_drivesel: u8,
-    pub disks: RwLock<Vec<Arc<RwLock<IDEDisk>>>>,
+    // 0 for the primary channel, 1 for the secondary, to find its IRQ
+    channel: usize,
+    pub disks: RwLock<Vec<Arc<Mutex<IDEDisk>>>>,
 }
 
 pub struct IDEDiskInfo {
@@ -121,26 +130,38 @@ pub struct IDEDiskInfo {
 
 pub struct IDEDisk {
     info: IDEDiskInfo,
-    bus: Arc<RwLock<Bus>>,
+    bus: Arc<Mutex<Bus>>,
 }
 
 impl block::BlockDriver for IDEDisk {
     fn read(&self, lba: usize, buffer: &mut [u8]) -> Result<usize> {
-        let mut bus = self.bus.write().unwrap();
+        // Held while the disk works, other tasks sleep meanwhile
+        let mut bus = self.bus.lock();
 
         bus.select_slot(self.info.slot);
         // TODO optimize to DMA more than 512 bytes at a time
-        for chunk in buffer.chunks_mut(512) {
-            bus.read_dma(lba.try_into().unwrap(), chunk)?;
+        for (i, chunk) in buffer.chunks_mut(512).enumerate() {
+            bus.read_dma((lba + i).try_into().unwrap(), chunk)?;
+        }
+        Ok(buffer.len())
+    }
+
+    fn write(&self, lba: usize, buffer: &[u8]) -> Result<usize> {
+        let mut bus = self.bus.lock();
+
+        bus.select_slot(self.info.slot);
+        for (i, chunk) in buffer.chunks(512).enumerate() {
+            bus.write_pio((lba + i).try_into().map_err(|_| EINVAL)?, chunk)?;
         }
         Ok(buffer.len())
     }
 }
 
 impl Bus {
-    pub fn new(iobase: u16, controlbase: u16, dmabase: u16) -> Self {
+    pub fn new(channel: usize, iobase: u16, controlbase: u16, dmabase: u16) -> Self {
         Bus {
             active_drivesel: 0,
+            channel,
             // PIO regs
             data: Pio::new(iobase + ATA_REG_DATA),
             error: Pio::new(iobase + ATA_REG_ERROR),
@@ -218,7 +239,7 @@ impl Bus {
         com |= 1;
         bus.dma_command.write(com);
 
-        self.poll();
+        self.poll()?;
         // TODO check for error
         // need to read it after each operation
         self.dma_status.read();
@@ -247,15 +268,76 @@ impl Bus {
         Ok(())
     }
 
-    fn poll(&self) {
-        loop {
-            let status = self.status.read();
-            // Check if not busy anymore
-            // TODO more extensive error checking
+    /// Write a sector with PIO, then flush the drive cache so that it is on the disk
+    // TODO DMA writes, and flush only once for several sectors
+    fn write_pio(&mut self, lba: u32, buffer: &[u8]) -> Result<()> {
+        if (lba >> 28) > 0 || buffer.len() != 512 {
+            return Err(EINVAL);
+        }
+        self.lba0.write(lba as u8);
+        self.lba1.write((lba >> 8) as u8);
+        self.lba2.write((lba >> 16) as u8);
+        self.drive_select
+            .write(self.drive_select.read() | (lba >> 24) as u8);
+        self.seccount.write(1);
+        self.command.write(ATA_CMD_WRITE_PIO);
+
+        self.wait_drq()?;
+        for word in buffer.chunks(2) {
+            self.data.write(u16::from_le_bytes([word[0], word[1]]));
+        }
+        // Interrupts once the sector is written
+        self.poll()?;
+        self.command.write(ATA_CMD_CACHE_FLUSH);
+        self.poll()?;
+        if self.status.read() & 1 != 0 {
+            klog!("Error while PIO write, error {:b}", self.error.read());
+            return Err(EIO);
+        }
+        Ok(())
+    }
+
+    /// Spin until the drive asks for the data, there is no interrupt for that
+    /// Bounded by the number of reads, jiffies do not move if interrupts are disabled
+    fn wait_drq(&self) -> Result<()> {
+        // Each port read takes about a microsecond
+        for _ in 0..COMMAND_TIMEOUT_MS * 1000 {
+            let status = self.altstatus.read();
             if status & 0x80 == 0 {
-                break;
+                if status & 1 != 0 {
+                    klog!("Error while PIO write, error {:b}", self.error.read());
+                    return Err(EIO);
+                }
+                if status & 0x08 != 0 {
+                    return Ok(());
+                }
+            }
+        }
+        klog!("IDE command timed out");
+        Err(EIO)
+    }
+
+    /// Sleep until the drive is not busy anymore, the channel interrupts when it is done
+    /// Reading the status also acknowledges the interrupt
+    fn poll(&self) -> Result<()> {
+        // TODO more extensive error checking
+        if !arch::interrupts_enabled() {
+            // Neither the interrupt nor the deadline would come, bounded like `wait_drq`
+            for _ in 0..COMMAND_TIMEOUT_MS * 1000 {
+                if self.status.read() & 0x80 == 0 {
+                    return Ok(());
+                }
             }
+            klog!("IDE command timed out");
+            return Err(EIO);
         }
+        let deadline = timer::get_jiffies() + timer::ms_to_jiffies(COMMAND_TIMEOUT_MS);
+        IRQ_WAIT[self.channel]
+            .wait_for_deadline(deadline, || (self.status.read() & 0x80 == 0).then_some(()))
+            .ok_or_else(|| {
+                klog!("IDE command timed out");
+                EIO
+            })
     }
 
     pub fn select_slot(&mut self, id: u8) {
@@ -265,9 +347,11 @@ impl Bus {
     }
 
     // TODO should add some error checking probably
+    /// The drive needs 400ns to show its status after being selected, each read of the
+    /// alternate status takes about 100ns
     fn wait(&self) {
-        for _ in 0..2000 {
-            let _ = self.status.read();
+        for _ in 0..4 {
+            let _ = self.altstatus.read();
         }
     }
 
@@ -343,6 +427,19 @@ static mut PRDT: AlignedArray = AlignedArray {
 };
 static mut BUFFER: [u8; 512] = [0 as u8; 512];
 
+/// Tasks waiting for a command to complete, for each channel
+static IRQ_WAIT: [WaitQueue; 2] = [WaitQueue::new(), WaitQueue::new()];
+
+fn primary_handler() -> core::result::Result<(), ()> {
+    IRQ_WAIT[0].wake_all();
+    Ok(())
+}
+
+fn secondary_handler() -> core::result::Result<(), ()> {
+    IRQ_WAIT[1].wake_all();
+    Ok(())
+}
+
 use crate::memory::vmm::mapper;
 
 #[allow(dead_code)]
@@ -395,20 +492,25 @@ impl IDEController {
         // Amen
         let mut controller: Box<IDEController> = Box::new(IDEController {
             buses: [
-                Arc::new(RwLock::new(Bus::new(0x1f0, 0x3f6, dma_base))),
-                Arc::new(RwLock::new(Bus::new(0x170, 0x376, dma_base + 0x8))),
+                Arc::new(Mutex::new(Bus::new(0, 0x1f0, 0x3f6, dma_base))),
+                Arc::new(Mutex::new(Bus::new(1, 0x170, 0x376, dma_base + 0x8))),
             ],
         });
+        if irq::request_irq_top(irq::IDE_PRIMARY, primary_handler).is_err()
+            || irq::request_irq_top(irq::IDE_SECONDARY, secondary_handler).is_err()
+        {
+            panic!("Could not register the IDE interrupt handlers");
+        }
 
         // Check if there are drives connected
         for bus_lock in controller.buses.iter_mut() {
             // Master
-            let mut bus = bus_lock.write().unwrap();
+            let mut bus = bus_lock.lock();
             bus.select_slot(IDE_DISK_MASTER);
 
             if let Some(diskinfo) = bus.probe() {
-                let mut disks = bus.disks.write().unwrap();
-                disks.push(Arc::new(RwLock::new(IDEDisk {
+                let mut disks = bus.disks.write();
+                disks.push(Arc::new(Mutex::new(IDEDisk {
                     info: diskinfo,
                     bus: bus_lock.clone(),
                 })));
@@ -417,8 +519,8 @@ impl IDEController {
             // Slave
             bus.select_slot(IDE_DISK_SLAVE);
             if let Some(diskinfo) = bus.probe() {
-                let mut disks = bus.disks.write().unwrap();
-                disks.push(Arc::new(RwLock::new(IDEDisk {
+                let mut disks = bus.disks.write();
+                disks.push(Arc::new(Mutex::new(IDEDisk {
                     info: diskinfo,
                     bus: bus_lock.clone(),
                 })));
@@ -0,0 +1,113 @@
+//! CMOS real time clock, only read at boot to set the wall clock
+//! TODO write it back when the time is set
+
+use crate::arch::io::{self, port};
+use crate::klib::time::{self, NSEC_PER_SEC};
+use crate::klog;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+/// Set while the clock updates its registers, they may be inconsistent
+const STATUS_A_UPDATING: u8 = 1 << 7;
+const STATUS_B_24H: u8 = 1 << 1;
+/// Values are in BCD otherwise
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// In 12 hours mode
+const HOURS_PM: u8 = 1 << 7;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+fn read_reg(reg: u8) -> u8 {
+    io::outb(port::CMOSADDRESS, reg);
+    io::wait();
+    io::inb(port::CMOSDATA)
+}
+
+fn read_raw() -> [u8; 6] {
+    while read_reg(REG_STATUS_A) & STATUS_A_UPDATING != 0 {}
+    [
+        REG_SECONDS,
+        REG_MINUTES,
+        REG_HOURS,
+        REG_DAY,
+        REG_MONTH,
+        REG_YEAR,
+    ]
+    .map(read_reg)
+}
+
+/// Days from 1970-01-01 to a date of the proleptic Gregorian calendar, after 1970
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // Years start in March so that the leap day comes last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    // 719468 days from 0000-03-01 to 1970-01-01
+    era * 146097 + day_of_era - 719468
+}
+
+/// Seconds since the epoch, the RTC is expected to be in UTC
+pub fn read_time() -> u64 {
+    // An update may come between the reads, until two in a row agree
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status = read_reg(REG_STATUS_B);
+    let pm = raw[2] & HOURS_PM != 0;
+    raw[2] &= !HOURS_PM;
+    if status & STATUS_B_BINARY == 0 {
+        for value in raw.iter_mut() {
+            *value = (*value & 0x0f) + (*value >> 4) * 10;
+        }
+    }
+    let [second, minute, mut hour, day, month, year] = raw.map(|v| v as u64);
+    if status & STATUS_B_24H == 0 {
+        // 12 AM is midnight
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    // TODO the century register given by the FADT
+    let year = 2000 + year;
+
+    days_from_civil(year, month, day) * SECS_PER_DAY + hour * 3600 + minute * 60 + second
+}
+
+/// Set the wall clock from the RTC
+pub fn init() {
+    let secs = read_time();
+    time::set_realtime(secs * NSEC_PER_SEC);
+    klog!("RTC time: {} seconds since the epoch", secs);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{klog, kprint};
+
+    #[test_case]
+    fn rtc_days_from_civil() {
+        kprint!("rtc days from civil... ");
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19782);
+        assert_eq!(days_from_civil(2026, 10, 18), 20744);
+        klog!("[ok]");
+    }
+}
@@ -32,7 +32,7 @@ static mut COM1: RwLock<SerialDriver> = RwLock::new(SerialDriver { com1: Pio::ne
 
 pub fn _print(args: fmt::Arguments) {
     unsafe {
-        let mut driver = COM1.write().unwrap();
+        let mut driver = COM1.write();
         driver.write_fmt(args).unwrap();
     }
 }
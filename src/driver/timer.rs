@@ -1,26 +1,204 @@
+//! Kernel timers, run from the timer interrupt once their deadline in jiffies is reached
+use crate::arch;
+use crate::arch::io;
+use crate::arch::smp::{self, MAX_CPUS};
 use crate::irq;
-use crate::proc;
+use crate::klib::lock::SpinLock;
+use crate::klib::time::{self, ClockSource, NSEC_PER_SEC};
+use crate::proc::wait::WaitQueue;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
-static mut JIFFIES: u64 = 0;
+/// Timer interrupts per second, for both the PIT and the LAPIC timer
+pub const HZ: u64 = 100;
 
-#[allow(dead_code)]
-pub fn do_timer() -> Result<(), ()>{
-    // TODO remove unsafe
-    unsafe {
-        JIFFIES += 1;
+/// Only counted by CPU 0, which also runs the timers
+static JIFFIES: AtomicU64 = AtomicU64::new(0);
+const FALSE: AtomicBool = AtomicBool::new(false);
+/// Set while the idle task stopped the periodic tick, by CPU
+static TICK_STOPPED: [AtomicBool; MAX_CPUS] = [FALSE; MAX_CPUS];
+
+pub type TimerId = usize;
+
+struct Timer {
+    /// Fired again every `period` jiffies, once if None
+    period: Option<u64>,
+    /// Runs in the interrupt handler, it must not sleep
+    /// Added on any CPU, it runs on CPU 0
+    callback: Box<dyn FnMut() + Send>,
+}
+
+/// Pending timers, by deadline then id so that the first one is the next to fire
+static TIMERS: SpinLock<BTreeMap<(u64, TimerId), Timer>> = SpinLock::new(BTreeMap::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub fn do_timer() -> Result<(), ()> {
+    // Interrupts are disabled in the handler
+    let cpu = smp::cpu_id();
+    if TICK_STOPPED[cpu].load(Ordering::Relaxed) {
+        // The one-shot timer of the idle task, several jiffies went by
+        restart_tick();
+    } else if cpu == 0 {
+        JIFFIES.fetch_add(1, Ordering::Release);
+    }
+    // The other CPUs only get the tick for the scheduler
+    if cpu == 0 {
+        run_timers(get_jiffies());
     }
-    // TODO error handling
-    let _ = proc::schedule::schedule();
     Ok(())
 }
 
-#[allow(dead_code)]
+/// Run the callbacks of the expired timers, periodic ones are armed again
+fn run_timers(now: u64) {
+    loop {
+        // Not locked while the callback runs, it may add timers
+        let expired = {
+            let mut timers = TIMERS.lock_irqsave();
+            match timers.first_key_value() {
+                Some((&(deadline, _), _)) if deadline <= now => timers.pop_first(),
+                _ => None,
+            }
+        };
+        let ((deadline, id), mut timer) = match expired {
+            Some(t) => t,
+            None => break,
+        };
+        (timer.callback)();
+        if let Some(period) = timer.period {
+            TIMERS.lock_irqsave().insert((deadline + period, id), timer);
+        }
+    }
+}
+
 pub fn get_jiffies() -> u64 {
-    unsafe { JIFFIES }
+    JIFFIES.load(Ordering::Acquire)
+}
+
+/// Jiffies needed to wait at least `ms` milliseconds
+pub fn ms_to_jiffies(ms: u64) -> u64 {
+    (ms * HZ).div_ceil(1000)
+}
+
+/// Call `callback` from the timer interrupt once `deadline` is reached, then every `period`
+/// jiffies if there is one
+pub fn add_timer(
+    deadline: u64,
+    period: Option<u64>,
+    callback: impl FnMut() + Send + 'static,
+) -> TimerId {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let timer = Timer {
+        period: period.map(|p| p.max(1)),
+        callback: Box::new(callback),
+    };
+    // CPU 0 keeps its tick while another one may add timers, see `stop_tick`
+    TIMERS.lock_irqsave().insert((deadline, id), timer);
+    id
+}
+
+/// Remove a pending timer, returns false if it already fired, or never existed
+pub fn cancel_timer(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock_irqsave();
+    let len = timers.len();
+    timers.retain(|&(_, t), _| t != id);
+    timers.len() != len
+}
+
+/// Sleep until jiffies reach `deadline`
+pub fn sleep_until(deadline: u64) {
+    if !arch::interrupts_enabled() {
+        // Jiffies do not move, each port write takes about a microsecond
+        let us = deadline.saturating_sub(get_jiffies()) * (1_000_000 / HZ);
+        for _ in 0..us {
+            io::wait();
+        }
+        return;
+    }
+    // Nobody else knows about the queue, only the deadline wakes us up
+    let _ = WaitQueue::new().wait_for_deadline(deadline, || None::<()>);
+}
+
+pub fn sleep_ms(ms: u64) {
+    sleep_until(get_jiffies() + ms_to_jiffies(ms));
 }
 
-#[allow(dead_code)]
+/// Sleep for at least `ns` nanoseconds of the monotonic clock, which may be finer than jiffies
+pub fn sleep_ns(ns: u64) {
+    let end = time::monotonic_ns().saturating_add(ns);
+    loop {
+        let now = time::monotonic_ns();
+        if now >= end {
+            break;
+        }
+        let jiffies = (end - now).div_ceil(NSEC_PER_SEC / HZ);
+        sleep_until(get_jiffies() + jiffies);
+    }
+}
+
+/// Let the timer interrupt only for the next timer, called by the idle task with interrupts
+/// disabled, before it halts
+/// The other CPUs do not run the timers, they only wait for an interrupt
+/// CPU 0 keeps the tick as soon as they are started: they read the jiffies it counts, which
+/// would be late by the whole idle period, deadlines computed from them would come too early
+pub fn stop_tick() {
+    let cpu = smp::cpu_id();
+    if cpu == 0 && smp::online() > 1 {
+        return;
+    }
+    let next = match cpu {
+        0 => TIMERS
+            .lock_irqsave()
+            .first_key_value()
+            .map(|(&(deadline, _), _)| deadline),
+        _ => None,
+    };
+    let jiffies = next.map_or(u64::MAX, |deadline| deadline.saturating_sub(get_jiffies()));
+    // Not worth it for the next tick
+    if jiffies > 1 && arch::timer::stop_tick(jiffies) {
+        TICK_STOPPED[cpu].store(true, Ordering::Relaxed);
+    }
+}
+
+/// Back to the periodic tick once the idle task wakes up, counting the jiffies that went by
+pub fn restart_tick() {
+    arch::without_interrupts(|| {
+        let cpu = smp::cpu_id();
+        if TICK_STOPPED[cpu].swap(false, Ordering::Relaxed) {
+            let elapsed = arch::timer::restart_tick();
+            if cpu == 0 {
+                JIFFIES.fetch_add(elapsed, Ordering::Release);
+            }
+        }
+    })
+}
+
+/// The timer interrupt count, the clock source of last resort
+struct Jiffies;
+
+impl ClockSource for Jiffies {
+    fn name(&self) -> &'static str {
+        "jiffies"
+    }
+
+    fn read(&self) -> u64 {
+        get_jiffies()
+    }
+
+    fn frequency(&self) -> u64 {
+        HZ
+    }
+
+    fn rating(&self) -> u32 {
+        1
+    }
+}
+
+static JIFFIES_SOURCE: Jiffies = Jiffies;
+
+/// Count the jiffies and run the timers, before the scheduler tick
 pub fn init() {
     // TODO error handling
-    let _ = irq::request_irq_top(50, do_timer);
+    let _ = irq::request_irq_top(irq::TIMER, do_timer);
+    time::register(&JIFFIES_SOURCE);
 }
@@ -33,7 +33,7 @@ pub struct VGA {
 pub fn io_init() {
     unsafe {
         VGA_INSTANCE = Some(RwLock::new(VGA::new()));
-        let mut buffer = VGA_INSTANCE.as_mut().unwrap().write().unwrap();
+        let mut buffer = VGA_INSTANCE.as_mut().unwrap().write();
         buffer.deref_mut().clear();
         // r.deref_mut().clear();
     }
@@ -42,7 +42,7 @@ pub fn io_init() {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     unsafe {
-        let mut buffer = VGA_INSTANCE.as_mut().unwrap().write().unwrap();
+        let mut buffer = VGA_INSTANCE.as_mut().unwrap().write();
         buffer.write_fmt(args).unwrap();
     }
 }
@@ -97,6 +97,10 @@ impl VGA {
                 b'\n' => {
                     self.new_line();
                 }
+                // Backspace only moves the cursor, like a terminal
+                0x08 => {
+                    self.x = self.x.saturating_sub(1);
+                }
                 _ => {}
             }
             i += 1;
@@ -1,6 +1,6 @@
 use crate::error::Result;
 use crate::fs::{ext2, vfs, vfs::FileSystemSetup};
-use crate::klib::lock::RwLock;
+use crate::klib::lock::{Mutex, RwLock};
 use crate::klog;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
@@ -8,16 +8,19 @@ use alloc::vec::Vec;
 /// Logical Block Address
 pub type Lba = u64;
 
-pub trait BlockDriver {
+/// Shared by the tasks of every CPU
+pub trait BlockDriver: Send {
     fn read(&self, lba: usize, buffer: &mut [u8]) -> Result<usize>;
-    // fn write_block(&self, lba: usize, buffer: &[u8]);
+    /// The data is on the disk when it returns
+    fn write(&self, lba: usize, buffer: &[u8]) -> Result<usize>;
 }
 
 /// Block devices are registered here
-static BLOCKS_DRIVERS: RwLock<Vec<Arc<RwLock<dyn BlockDriver>>>> = RwLock::new(Vec::new());
+/// Drivers sleep while the disk works, their lock does the same
+static BLOCKS_DRIVERS: RwLock<Vec<Arc<Mutex<dyn BlockDriver>>>> = RwLock::new(Vec::new());
 
-pub fn register_device(dev: Arc<RwLock<dyn BlockDriver>>) {
-    let mut v = BLOCKS_DRIVERS.write().unwrap();
+pub fn register_device(dev: Arc<Mutex<dyn BlockDriver>>) {
+    let mut v = BLOCKS_DRIVERS.write();
     v.push(dev);
 }
 
@@ -55,7 +58,7 @@ struct MBR {
 pub struct BlockDev {
     block_start: Lba,
     seccount: u64,
-    pub driver: Arc<RwLock<dyn BlockDriver>>,
+    pub driver: Arc<Mutex<dyn BlockDriver>>,
 }
 
 static BLOCK_DEVS: RwLock<Vec<Arc<BlockDev>>> = RwLock::new(Vec::new());
@@ -63,31 +66,39 @@ static BLOCK_DEVS: RwLock<Vec<Arc<BlockDev>>> = RwLock::new(Vec::new());
 impl BlockDev {
     #[inline]
     pub fn read(&self, lba: Lba, buffer: &mut [u8]) -> Result<usize> {
-        let driver = self.driver.write().unwrap();
+        let driver = self.driver.lock();
         // TODO fix this mapping 
         let block_index = self.block_start + lba * 2;
         driver.read(block_index as usize, buffer)
     }
+
+    #[inline]
+    pub fn write(&self, lba: Lba, buffer: &[u8]) -> Result<usize> {
+        let driver = self.driver.lock();
+        let block_index = self.block_start + lba * 2;
+        driver.write(block_index as usize, buffer)
+    }
 }
 
 // Loop through all the disks and extract filesystems volumes
 pub fn init_fs_from_devices() {
-    let drivers = BLOCKS_DRIVERS.read().unwrap();
+    let drivers = BLOCKS_DRIVERS.read();
     if drivers.len() == 0 {
-        panic!("No block devices detected, are you sure a drive is connected ?");
+        klog!("No block devices detected");
+        return;
     }
 
     let mut buffer = [0 as u8; 512];
-    for drv_lock in drivers.iter() {
+    for (disk, drv_lock) in drivers.iter().enumerate() {
         // Reading the first sector, and release the lock
-        let drv = drv_lock.write().unwrap();
+        let drv = drv_lock.lock();
         drv.read(0, &mut buffer).unwrap();
         drop(drv);
 
         // Read first block of device using lba
         // MBR partition
         if buffer[510] == 0x55 && buffer[511] == 0xAA {
-            let mut block_devs = BLOCK_DEVS.write().unwrap();
+            let mut block_devs = BLOCK_DEVS.write();
             let mbr: &MBR = unsafe { &*(buffer.as_ptr() as *const MBR) };
 
             for i in 0..4 {
@@ -105,8 +116,12 @@ pub fn init_fs_from_devices() {
                     Ok(val) => {
                         if val.is_some() {
                             // TODO ugggh remove the arc new, change the architecture or something
-                            vfs::get_filesystems().push(Arc::new(val.unwrap()));
-                            klog!("  Registered an ext2 filesystem");
+                            let name = format!("hd{}{}", (b'a' + disk as u8) as char, i + 1);
+                            klog!("  Registered an ext2 filesystem on {}", name);
+                            vfs::get_filesystems().push(vfs::Volume {
+                                name,
+                                fs: Arc::new(val.unwrap()),
+                            });
                         }
                     }
                     Err(errcode) => {
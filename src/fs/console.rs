@@ -0,0 +1,77 @@
+use super::vfs::{File, FileOps, OpenFlags};
+use crate::driver::{input, kbd};
+use crate::error::Result;
+use crate::klib::lock::RwLock;
+use crate::kprint;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Typed lines not read yet
+static INPUT: RwLock<VecDeque<u8>> = RwLock::new(VecDeque::new());
+
+/// The kernel console, what programs get as standard input and outputs
+pub struct Console;
+
+impl FileOps for Console {
+    fn open(&mut self, _flags: OpenFlags) -> Result<()> {
+        Ok(())
+    }
+
+    /// Lines are edited and echoed until enter is pressed
+    fn read(&mut self, _pos: u64, buf: &mut [u8]) -> Result<usize> {
+        if INPUT.read().is_empty() {
+            let line = read_line();
+            INPUT.write().extend(line);
+        }
+        let mut input = INPUT.write();
+        let n = buf.len().min(input.len());
+        for (dst, src) in buf.iter_mut().zip(input.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+
+    fn write(&mut self, _pos: u64, buf: &[u8]) -> Result<usize> {
+        kprint!("{}", String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+}
+
+/// Open the console, it is not part of the file tree
+pub fn open() -> File {
+    File {
+        dentry: None,
+        pos: 0,
+        flags: OpenFlags::RDWR,
+        ops: Box::new(Console),
+    }
+}
+
+/// Wait for a full line from the keyboard, with its newline
+fn read_line() -> Vec<u8> {
+    let mut line = Vec::new();
+    loop {
+        let scancode = match input::wait_event() {
+            input::InputEvent::Keyboard(code) => code as u8,
+        };
+        match kbd::decode(scancode) {
+            Some(b'\x08') => {
+                if line.pop().is_some() {
+                    kprint!("\x08 \x08");
+                }
+            }
+            Some(b'\n') => {
+                kprint!("\n");
+                line.push(b'\n');
+                return line;
+            }
+            Some(c) => {
+                kprint!("{}", c as char);
+                line.push(c);
+            }
+            None => {}
+        }
+    }
+}
@@ -1,9 +1,11 @@
-use crate::dbg;
 use crate::error::{codes::*, Result};
+use crate::klib::time;
+use crate::klog;
 use crate::fs::block::{BlockDev, Lba};
+use crate::fs::pipe;
 use crate::fs::vfs::{
-    Dentry, Dirent, File, FileOps, FileSystemSetup, Filesystem, Info, Inonum, NodeOps, Vnode,
-    VnodeType, NAME_MAX,
+    self, Dentry, Dirent, File, FileOps, FileSystemSetup, Filesystem, Info, Inonum, NodeOps,
+    OpenFlags, Vnode, VnodeType, Whence, NAME_MAX,
 };
 use alloc::sync::Arc;
 use alloc::boxed::Box;
@@ -40,6 +42,9 @@ pub struct SuperBlock {
     major_version: u32,
     uid_reserved: u16,
     guid_reserved: u16,
+    // Extended fields, major version >= 1
+    first_inode: u32,
+    inode_size: u16,
 }
 
 #[repr(C, packed)]
@@ -49,9 +54,9 @@ pub struct Inode {
     uid: u16,
     /// low 32 bit of size
     size_low: u32,
-    /// Last of each POSIX timestamp
+    /// Last access, inode change, content modification and deletion, as POSIX timestamps
     access: u32,
-    creation: u32,
+    change: u32,
     modification: u32,
     delete: u32,
     guid: u16,
@@ -71,6 +76,25 @@ pub struct Inode {
     os_frag: [u8; 12],
 }
 
+impl Inode {
+    /// Size in bytes, the upper half is only valid for regular files
+    #[inline]
+    fn size(&self) -> u64 {
+        let low = self.size_low as u64;
+        let high = self.size_upper as u64;
+        low | high << 32
+    }
+
+    /// Whether reading should update the access time, like the relatime mount option:
+    /// only once a day, unless the file changed since the last access
+    fn atime_stale(&self, now: u32) -> bool {
+        let access = self.access;
+        access <= self.modification
+            || access <= self.change
+            || now.saturating_sub(access) >= RELATIME_SECS
+    }
+}
+
 #[derive(Copy, Clone)]
 struct InoBlockBuff {
     // index of current cached buffer
@@ -239,7 +263,7 @@ pub struct Ext2Dentry {
 }
 
 // Compile time checks to ensure correct size of structures
-const _: [u8; 84] = [0 as u8; core::mem::size_of::<SuperBlock>()];
+const _: [u8; 90] = [0 as u8; core::mem::size_of::<SuperBlock>()];
 const _: [u8; 32] = [0 as u8; core::mem::size_of::<BGDescriptor>()];
 const _: [u8; 128] = [0 as u8; core::mem::size_of::<Inode>()];
 const _: [u8; 12] = [0 as u8; core::mem::size_of::<Ext2Dentry>()];
@@ -278,32 +302,72 @@ pub struct Ext2 {
     block_dev: Arc<BlockDev>,
 }
 
+/// Root directory inode number
+const ROOT_INODE: Inonum = 2;
+/// How old the access time gets before a read updates it
+const RELATIME_SECS: u32 = 24 * 60 * 60;
+// TODO support other block sizes, buffers are sized for 1024 bytes blocks
+const BLOCK_SIZE: usize = 1024;
+
 impl Ext2 {
-    fn get_inode(&self, inode_num: Inonum) -> Result<Inode> {
+    /// Size of an inode structure on disk, it is fixed before version 1
+    fn inode_size(&self) -> usize {
+        if { self.sb.major_version } < 1 {
+            return size_of::<Inode>();
+        }
+        let size = self.sb.inode_size;
+        size as usize
+    }
+
+    /// Block of the inode table holding the inode, and the offset of the inode in it
+    fn inode_location(&self, inode_num: Inonum) -> Result<(Lba, usize)> {
         // Figure out in which block group the inode is
         let block_group = ((inode_num as u32 - 1) / self.sb.inodes_per_group) as usize;
         let bgd = self.get_bg_descriptor(block_group)?;
 
         // TODO clear up type and casts, remove fs/drive constants and place them in structs
-        // TODO clean up the casts, less of it please
         // getting the block that contains the right portion of the inode table
-        let mut buffer = [0 as u8; 1024];
-        let mut inode_table_i = (inode_num as usize - 1) % self.sb.inodes_per_group as usize;
-        let inode_per_block = 1024 / size_of::<Inode>();
-        let table_block_offset = (inode_table_i as usize as usize * size_of::<Inode>()) / 1024;
-        inode_table_i -= inode_per_block * table_block_offset;
-
-        self.block_dev.read(
+        let inode_table_i = (inode_num as usize - 1) % self.sb.inodes_per_group as usize;
+        let table_offset = inode_table_i * self.inode_size();
+        let table_block_offset = table_offset / BLOCK_SIZE;
+        Ok((
             (bgd.inode_table as usize + table_block_offset) as Lba,
-            &mut buffer,
-        )?;
-        let inode = unsafe { *(buffer.as_ptr() as *const Inode).offset(inode_table_i as isize) };
+            table_offset % BLOCK_SIZE,
+        ))
+    }
 
-        dbg!("========= Start entries");
-        todo!();
+    fn get_inode(&self, inode_num: Inonum) -> Result<Inode> {
+        let (lba, offset) = self.inode_location(inode_num)?;
+        let mut buffer = [0 as u8; BLOCK_SIZE];
+        self.block_dev.read(lba, &mut buffer)?;
+        // Inodes larger than 128 bytes are not aligned on the structure size
+        let inode = unsafe { (buffer.as_ptr().add(offset) as *const Inode).read_unaligned() };
         Ok(inode)
     }
 
+    /// Write an inode back to the disk, the extra bytes of larger inodes are kept
+    fn put_inode(&self, inode_num: Inonum, inode: &Inode) -> Result<()> {
+        let (lba, offset) = self.inode_location(inode_num)?;
+        let mut buffer = [0 as u8; BLOCK_SIZE];
+        self.block_dev.read(lba, &mut buffer)?;
+        unsafe { (buffer.as_mut_ptr().add(offset) as *mut Inode).write_unaligned(*inode) };
+        self.block_dev.write(lba, &buffer)?;
+        Ok(())
+    }
+
+    /// Update the access time after a read, if it is stale
+    fn touch_atime(&self, inode_num: Inonum, inode: &mut Inode) {
+        let now = time::realtime_secs();
+        if !inode.atime_stale(now) {
+            return;
+        }
+        inode.access = now;
+        // The read itself went fine
+        if let Err(code) = self.put_inode(inode_num, inode) {
+            klog!("ext2: could not update the access time of inode {}: {}", inode_num, code);
+        }
+    }
+
     fn get_bg_descriptor(&self, index: usize) -> Result<BGDescriptor> {
         // TODO Figure out the buffer situation
         let mut buffer = [0 as u8; 1024];
@@ -350,23 +414,13 @@ impl FileSystemSetup for Ext2 {
 impl Filesystem for Arc<Ext2> {
     // TODO handle other ext2 versions
     fn get_root_inode(&self) -> Result<Inonum> {
-        // TODO this will be offsetted by -1, maybe not clear enough
-        let rootindex: Inonum = 3;
-        Ok(rootindex)
+        Ok(ROOT_INODE)
     }
 
     fn read_inode(&self, inode: Inonum) -> Result<Vnode> {
         // self.driver.read
         let raw_inode = self.get_inode(inode)?;
-        let kind = {
-            if raw_inode.mode & 0x4000 != 0 {
-                VnodeType::Dir
-            } else if raw_inode.mode & 0x8000 != 0 {
-                VnodeType::File
-            } else {
-                panic!("Unimplemented inode mode {:x}", { raw_inode.mode })
-            }
-        };
+        let kind = VnodeType::from_mode(raw_inode.mode).ok_or(EUCLEAN)?;
 
         Ok(Vnode {
             inode,
@@ -374,6 +428,9 @@ impl Filesystem for Arc<Ext2> {
             gid: raw_inode.guid,
             mode: raw_inode.mode,
             kind,
+            atime: raw_inode.access,
+            mtime: raw_inode.modification,
+            ctime: raw_inode.change,
             ops: Arc::new(Ext2NodeOps { fs: self.clone() }),
         })
     }
@@ -382,13 +439,21 @@ impl Filesystem for Arc<Ext2> {
 pub struct Ext2NodeOps {
     fs: Arc<Ext2>,
 }
-pub struct Ext2File {}
+/// Interface implementing FileOps for regular files
+pub struct Ext2File {
+    inum: Inonum,
+    inode: Inode,
+    fs: Arc<Ext2>,
+    blocks: InoBlocks,
+    // Last block read, offset is unused
+    buff: DirBuff,
+}
 
 impl NodeOps for Ext2NodeOps {
     fn open(&self, node: &Vnode, dent: &Arc<Dentry>) -> Result<File> {
         let inode = self.fs.get_inode(node.inode)?;
-        let ops = match node.kind {
-            VnodeType::FIFO => todo!(),
+        let ops: Box<dyn FileOps> = match node.kind {
+            VnodeType::FIFO => pipe::fifo_ops(Arc::as_ptr(&self.fs) as usize, node.inode),
             VnodeType::Char => todo!(),
             VnodeType::Dir => Box::new(Ext2Dir {
                 inum: node.inode,
@@ -399,22 +464,72 @@ impl NodeOps for Ext2NodeOps {
                 buff: DirBuff { offset: 0, curr: 0, data: [0; 1024]}
             }),
             VnodeType::Block => todo!(),
-            VnodeType::File => todo!(),
+            VnodeType::File => Box::new(Ext2File {
+                inum: node.inode,
+                inode,
+                fs: self.fs.clone(),
+                blocks: inode.blocks(self.fs.block_dev.clone()),
+                buff: DirBuff { offset: 0, curr: 0, data: [0; 1024]}
+            }),
             VnodeType::Symlink => todo!(),
             VnodeType::Socket => todo!(),
         };
 
 
         Ok(File {
-            dentry: dent.clone(),
+            dentry: Some(dent.clone()),
             pos: 0,
+            flags: OpenFlags::RDONLY,
             ops,
         })
     }
 }
 
 
-impl FileOps for Ext2File {}
+impl FileOps for Ext2File {
+    fn open(&mut self, _flags: OpenFlags) -> Result<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize> {
+        let size = self.inode.size();
+        if pos >= size {
+            return Ok(0);
+        }
+        let len = core::cmp::min(buf.len() as u64, size - pos) as usize;
+
+        let mut done = 0;
+        while done < len {
+            let current = pos as usize + done;
+            let offset = current % BLOCK_SIZE;
+            let n = core::cmp::min(BLOCK_SIZE - offset, len - done);
+            let dest = &mut buf[done..done + n];
+            match self.blocks.get_lba(&self.inode, current / BLOCK_SIZE)? {
+                Some(lba) if lba != 0 => {
+                    if lba != self.buff.curr {
+                        self.fs.block_dev.read(lba, &mut self.buff.data)?;
+                        self.buff.curr = lba;
+                    }
+                    dest.copy_from_slice(&self.buff.data[offset..offset + n]);
+                }
+                // Sparse file, holes read as zeros
+                _ => dest.fill(0),
+            }
+            done += n;
+        }
+        self.fs.touch_atime(self.inum, &mut self.inode);
+        Ok(len)
+    }
+
+    // TODO write support
+    fn write(&mut self, _pos: u64, _buf: &[u8]) -> Result<usize> {
+        Err(EROFS)
+    }
+
+    fn lseek(&mut self, pos: u64, offset: i64, whence: Whence) -> Result<u64> {
+        vfs::seek_position(pos, self.inode.size(), offset, whence)
+    }
+}
 
 pub struct DirBuff {
     offset: usize,
@@ -434,39 +549,57 @@ pub struct Ext2Dir {
 
 impl FileOps for Ext2Dir {
 
-    fn open(&mut self) -> Result<()> {
+    fn open(&mut self, _flags: OpenFlags) -> Result<()> {
         Ok(())
     }
 
     fn readdir(&mut self) -> Result<Option<Dirent>> {
-        // get lba from linear block index
-        let r = self.blocks.get_lba(&self.inode, self.block_index)?;
-        if r.is_none() {return Ok(None)}
-        let lba = r.unwrap();
-        // If the lba is not the one cached
-        if lba != self.buff.curr {
-            // fetch the data
-            self.fs.block_dev.read(lba, &mut self.buff.data)?;
-            self.buff.curr = lba;
-            self.buff.offset = 0;
-        }
+        loop {
+            // End of the directory
+            if (self.block_index * BLOCK_SIZE) as u64 >= self.inode.size() {
+                return Ok(None);
+            }
+            // get lba from linear block index
+            let lba = match self.blocks.get_lba(&self.inode, self.block_index)? {
+                Some(lba) if lba != 0 => lba,
+                _ => return Ok(None),
+            };
+            // If the lba is not the one cached
+            if lba != self.buff.curr {
+                // fetch the data
+                self.fs.block_dev.read(lba, &mut self.buff.data)?;
+                self.buff.curr = lba;
+                self.buff.offset = 0;
+            }
 
-        let dentry: &Ext2Dentry = unsafe {
-            &*(&self.buff.data[self.buff.offset] as *const u8 as *const Ext2Dentry)
-        };
+            let dentry: &Ext2Dentry = unsafe {
+                &*(&self.buff.data[self.buff.offset] as *const u8 as *const Ext2Dentry)
+            };
+            // A zero sized entry would loop forever
+            if dentry.size == 0 {
+                return Err(EUCLEAN);
+            }
 
-        let mut dirent = Dirent {
-            inode: dentry.inode as Inonum,
-            name: [0 as u8; NAME_MAX],
-            size: dentry.size as usize
-        };
-        dirent.name.clone_from_slice(dentry.name().unwrap().as_bytes());
-        // offset by the entry size
-        self.buff.offset += dirent.size;
-        // If end of dirent for current block, go to next block
-        if self.buff.offset == 1024 {
-            self.block_index += 1;
+            // offset by the entry size
+            self.buff.offset += dentry.size as usize;
+            // If end of dirent for current block, go to next block
+            if self.buff.offset >= BLOCK_SIZE {
+                self.block_index += 1;
+            }
+            // Unused entry
+            if dentry.inode == 0 {
+                continue;
+            }
+
+            let name = dentry.name().ok_or(EUCLEAN)?.as_bytes();
+            let mut dirent = Dirent {
+                inode: dentry.inode as Inonum,
+                name: [0 as u8; NAME_MAX],
+                name_len: name.len(),
+                size: dentry.size as usize
+            };
+            dirent.name[..name.len()].copy_from_slice(name);
+            return Ok(Some(dirent));
         }
-        return Ok(Some(dirent));
     }
 }
@@ -0,0 +1,100 @@
+use super::console;
+use super::vfs::File;
+use crate::error::{codes::*, Result};
+use crate::klib::lock::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Maximum number of descriptors per task
+pub const MAX_FDS: usize = 256;
+
+/// An open file, shared by the descriptors duplicated from the same open
+/// Reads and writes may sleep with it locked, so the other users sleep too instead of spinning
+pub type FileRef = Arc<Mutex<File>>;
+
+/// A descriptor in use
+#[derive(Clone)]
+struct Slot {
+    file: FileRef,
+    /// Closed when the task executes another program
+    cloexec: bool,
+}
+
+/// The file descriptor table of a task
+/// Cloned on fork, the open files themselves are shared with the parent
+#[derive(Clone, Default)]
+pub struct FdTable {
+    files: Vec<Option<Slot>>,
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        FdTable { files: Vec::new() }
+    }
+
+    /// Table with the console open on the standard input and outputs
+    /// Opened once for each of them, so that writes do not wait for a read blocked on the
+    /// keyboard with the file locked
+    pub fn with_console() -> Self {
+        let mut table = FdTable::new();
+        for _ in 0..3 {
+            table.files.push(Some(Slot {
+                file: Arc::new(Mutex::new(console::open())),
+                cloexec: false,
+            }));
+        }
+        table
+    }
+
+    /// The file open on `fd`
+    pub fn get(&self, fd: usize) -> Result<FileRef> {
+        match self.files.get(fd) {
+            Some(Some(slot)) => Ok(slot.file.clone()),
+            _ => Err(EBADF),
+        }
+    }
+
+    /// Install the file on the lowest free descriptor
+    pub fn insert(&mut self, file: FileRef, cloexec: bool) -> Result<usize> {
+        let fd = match self.files.iter().position(|f| f.is_none()) {
+            Some(fd) => fd,
+            None if self.files.len() < MAX_FDS => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(EMFILE),
+        };
+        self.files[fd] = Some(Slot { file, cloexec });
+        Ok(fd)
+    }
+
+    /// Install the file on `fd`, returns the file that was open there
+    pub fn insert_at(&mut self, fd: usize, file: FileRef, cloexec: bool) -> Result<Option<FileRef>> {
+        if fd >= MAX_FDS {
+            return Err(EBADF);
+        }
+        if fd >= self.files.len() {
+            self.files.resize(fd + 1, None);
+        }
+        Ok(self.files[fd]
+            .replace(Slot { file, cloexec })
+            .map(|slot| slot.file))
+    }
+
+    /// Free the descriptor, the file is closed with its last reference
+    pub fn remove(&mut self, fd: usize) -> Result<FileRef> {
+        match self.files.get_mut(fd).and_then(|f| f.take()) {
+            Some(slot) => Ok(slot.file),
+            None => Err(EBADF),
+        }
+    }
+
+    /// Close the descriptors marked close on exec
+    pub fn close_on_exec(&mut self) {
+        for f in self.files.iter_mut() {
+            if f.as_ref().is_some_and(|slot| slot.cloexec) {
+                *f = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Initial ramdisk, a cpio archive in the newc format loaded by the bootloader as the first
+//! module. It is unpacked in a ramfs, that serves as root until a disk is mounted
+
+use super::ramfs::{Content, RamFs, RamNode};
+use super::vfs::{Inonum, VnodeType, S_IFDIR};
+use crate::error::{codes::*, Result};
+use crate::klog;
+use crate::memory::pmm::{self, Frame, FrameRange};
+use crate::memory::vmm::mapper;
+use crate::memory::PAGE_SIZE;
+use alloc::sync::Arc;
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &[u8] = b"TRAILER!!!";
+
+/// Physical location of the archive, [start, end[
+static mut IMAGE: Option<(usize, usize)> = None;
+
+/// Called while parsing the multiboot information
+pub fn set_image(start: usize, end: usize) {
+    unsafe { IMAGE = Some((start, end)) };
+}
+
+/// Frames holding the archive, they must not be allocated before it is unpacked
+pub fn frames() -> Option<FrameRange> {
+    let (start, end) = unsafe { IMAGE }?;
+    let first = start / PAGE_SIZE;
+    Some(FrameRange {
+        start: Frame(first),
+        size: (end + PAGE_SIZE - 1) / PAGE_SIZE - first,
+    })
+}
+
+/// The fields of a newc header, each one is 8 hex digits
+struct Header {
+    mode: u16,
+    uid: u16,
+    gid: u16,
+    filesize: usize,
+    namesize: usize,
+}
+
+fn hex(field: &[u8]) -> Result<usize> {
+    let s = core::str::from_utf8(field).map_err(|_| EINVAL)?;
+    usize::from_str_radix(s, 16).map_err(|_| EINVAL)
+}
+
+impl Header {
+    fn parse(raw: &[u8]) -> Result<Header> {
+        if raw.len() < HEADER_SIZE || &raw[..6] != MAGIC {
+            return Err(EINVAL);
+        }
+        let field = |i: usize| hex(&raw[6 + i * 8..6 + (i + 1) * 8]);
+        // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor,
+        // rdevminor, namesize, check
+        Ok(Header {
+            mode: field(1)? as u16,
+            uid: field(2)? as u16,
+            gid: field(3)? as u16,
+            filesize: field(6)?,
+            namesize: field(11)?,
+        })
+    }
+}
+
+/// Sizes come from the archive, a malformed one must not overflow
+#[inline]
+fn align4(n: usize) -> Result<usize> {
+    Ok(n.checked_add(3).ok_or(EINVAL)? & !3)
+}
+
+/// Directory holding `path`, created if needed, and the last component
+fn parent<'a>(fs: &RamFs, path: &'a [u8]) -> Result<(Inonum, &'a [u8])> {
+    let mut dir = fs.root();
+    let mut components = path.split(|&c| c == b'/').filter(|c| !c.is_empty() && *c != b".");
+    let mut name = components.next().ok_or(EINVAL)?;
+    for next in components {
+        dir = match fs.lookup(dir, name)? {
+            Some(inode) => inode,
+            // Archives do not always list the directories
+            None => fs.insert(dir, name, RamNode::new(S_IFDIR | 0o755, 0, 0))?,
+        };
+        name = next;
+    }
+    Ok((dir, name))
+}
+
+/// Create the files of the archive in `fs`, returns the number of entries
+fn unpack(fs: &RamFs, mut data: &[u8]) -> Result<usize> {
+    let mut count = 0;
+    loop {
+        let header = Header::parse(data)?;
+        let name_end = HEADER_SIZE.checked_add(header.namesize).ok_or(EINVAL)?;
+        let data_start = align4(name_end)?;
+        let data_end = data_start.checked_add(header.filesize).ok_or(EINVAL)?;
+        if header.namesize == 0 || data.len() < data_end {
+            return Err(EINVAL);
+        }
+        // The name is NUL terminated
+        let name = &data[HEADER_SIZE..name_end - 1];
+        if name == TRAILER {
+            return Ok(count);
+        }
+        let content = &data[data_start..data_end];
+        data = &data[align4(data_end)?.min(data.len())..];
+
+        // The root itself
+        if name.split(|&c| c == b'/').all(|c| c.is_empty() || c == b".") {
+            continue;
+        }
+        let (dir, name) = parent(fs, name)?;
+        let mut node = RamNode::new(header.mode, header.uid, header.gid);
+        match &mut node.content {
+            Content::File(pages) => {
+                pages.write(0, content)?;
+            }
+            Content::Symlink(target) => target.extend_from_slice(content),
+            _ => {}
+        }
+        // TODO hard links, they share an inode number in the archive
+        match fs.insert(dir, name, node) {
+            Ok(_) => {}
+            // Directories created for an earlier entry
+            Err(EEXIST) if matches!(VnodeType::from_mode(header.mode), Some(VnodeType::Dir)) => {}
+            Err(e) => return Err(e),
+        }
+        count += 1;
+    }
+}
+
+/// Unpack the initrd given by the bootloader, its memory is released afterwards
+pub fn load() -> Option<Arc<RamFs>> {
+    let range = frames()?;
+    let (start, end) = unsafe { IMAGE.take() }?;
+    let address = mapper::phys_to_virt(start)?;
+    let data = unsafe { core::slice::from_raw_parts(address as *const u8, end - start) };
+
+    let fs = RamFs::new(0o755);
+    let result = unpack(&fs, data);
+    // The boot header asks for page aligned modules, nothing else lives in those frames
+    pmm::free_contiguous_pages(range);
+    match result {
+        Ok(count) => {
+            klog!("Unpacked the initrd, {} entries, {}KB freed", count, (end - start) / 1024);
+            Some(fs)
+        }
+        Err(code) => {
+            klog!("Invalid initrd, error {}", code);
+            None
+        }
+    }
+}
@@ -1,4 +1,61 @@
 pub mod vfs;
 pub mod ext2;
 pub mod block;
+pub mod console;
+pub mod fd;
+pub mod pipe;
+pub mod ramfs;
+pub mod initrd;
 
+use crate::error::{codes::*, Result};
+use crate::klib::cmdline::Param;
+use crate::klog;
+use alloc::sync::Arc;
+
+/// Volume mounted on /, eg. hda1
+static ROOT: Param<&str> = Param::new("root", "");
+
+/// Unpack the initrd and mount it on /, returns whether there was one
+pub fn mount_initrd() -> bool {
+    match initrd::load() {
+        Some(fs) => {
+            klog!("Mounting the initrd on /");
+            // TODO remove the arc new, like for the block filesystems
+            vfs::register_mount("/", Arc::new(fs)).is_ok()
+        }
+        None => false,
+    }
+}
+
+/// Mount the root filesystem, chosen with root= when there are several volumes
+/// Without root=, an already mounted initrd stays the root
+pub fn mount_root(have_initrd: bool) -> Result<()> {
+    ROOT.register();
+    let volumes = vfs::get_filesystems();
+    let volume = match ROOT.get() {
+        "" if have_initrd => return Ok(()),
+        "" => {
+            if volumes.len() > 1 {
+                klog!("Several filesystems found, use root= to choose one");
+            }
+            volumes.first().ok_or(ENODEV)?
+        }
+        name => {
+            let name = name.strip_prefix("/dev/").unwrap_or(name);
+            volumes.iter().find(|v| v.name == name).ok_or(ENODEV)?
+        }
+    };
+    klog!("Mounting {} on /", volume.name);
+    vfs::register_mount("/", volume.fs.clone())
+}
+
+/// Mount an empty tmpfs on /tmp and /run
+pub fn mount_tmpfs() {
+    for path in ["/tmp", "/run"] {
+        // Only so that it is listed, the root may well be read-only
+        let _ = vfs::vfs_mkdir(path, 0o1777);
+        if let Err(code) = vfs::register_mount(path, Arc::new(ramfs::RamFs::new(0o1777))) {
+            klog!("Could not mount tmpfs on {}, error {}", path, code);
+        }
+    }
+}
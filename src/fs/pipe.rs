@@ -0,0 +1,212 @@
+use super::vfs::{File, FileOps, Inonum, OpenFlags};
+use crate::error::{codes::*, Result};
+use crate::klib::lock::SpinLock;
+use crate::proc::wait::WaitQueue;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+
+/// Capacity of a pipe, writers block once it is full
+pub const PIPE_SIZE: usize = 4096;
+
+/// The buffer shared by both ends of a pipe
+struct Pipe {
+    buf: VecDeque<u8>,
+    /// Number of open files on each end
+    readers: usize,
+    writers: usize,
+}
+
+impl Pipe {
+    fn new() -> Self {
+        Pipe {
+            buf: VecDeque::with_capacity(PIPE_SIZE),
+            readers: 0,
+            writers: 0,
+        }
+    }
+}
+
+/// A pipe and the tasks waiting for it to change
+struct Shared {
+    pipe: SpinLock<Pipe>,
+    /// Woken up each time data comes in or out, or an end is opened or closed
+    wait: WaitQueue,
+}
+
+impl Shared {
+    fn new() -> Arc<Self> {
+        Arc::new(Shared {
+            pipe: SpinLock::new(Pipe::new()),
+            wait: WaitQueue::new(),
+        })
+    }
+}
+
+/// An open end of a pipe, or both for a FIFO opened read-write
+pub struct PipeFile {
+    shared: Arc<Shared>,
+    read: bool,
+    write: bool,
+    nonblock: bool,
+}
+
+impl PipeFile {
+    fn new(shared: Arc<Shared>, flags: OpenFlags) -> Self {
+        PipeFile {
+            shared,
+            read: flags.readable(),
+            write: flags.writable(),
+            nonblock: flags.contains(OpenFlags::NONBLOCK),
+        }
+    }
+
+    /// Register this end on the pipe
+    fn attach(&self) {
+        {
+            let mut pipe = self.shared.pipe.lock();
+            if self.read {
+                pipe.readers += 1;
+            }
+            if self.write {
+                pipe.writers += 1;
+            }
+        }
+        self.shared.wait.wake_all();
+    }
+}
+
+impl FileOps for PipeFile {
+    /// Opening a FIFO waits for the other end, unless it is opened for both
+    fn open(&mut self, flags: OpenFlags) -> Result<()> {
+        let shared = self.shared.clone();
+        *self = PipeFile::new(shared, flags);
+        // Without readers, a non blocking writer fails right away
+        if self.write && !self.read && self.nonblock && self.shared.pipe.lock().readers == 0 {
+            self.write = false;
+            return Err(ENXIO);
+        }
+        self.attach();
+        if (self.read && self.write) || self.nonblock {
+            return Ok(());
+        }
+        self.shared.wait.wait_until(|| {
+            let pipe = self.shared.pipe.lock();
+            (self.read && pipe.writers > 0) || (self.write && pipe.readers > 0)
+        });
+        Ok(())
+    }
+
+    fn read(&mut self, _pos: u64, buf: &mut [u8]) -> Result<usize> {
+        let shared = &self.shared;
+        let nonblock = self.nonblock;
+        let ret = shared.wait.wait_for(|| {
+            let mut pipe = shared.pipe.lock();
+            if !pipe.buf.is_empty() {
+                let n = core::cmp::min(buf.len(), pipe.buf.len());
+                for (dest, byte) in buf.iter_mut().zip(pipe.buf.drain(..n)) {
+                    *dest = byte;
+                }
+                return Some(Ok(n));
+            }
+            // End of file, nobody can write anymore
+            if pipe.writers == 0 {
+                return Some(Ok(0));
+            }
+            nonblock.then_some(Err(EAGAIN))
+        });
+        // Writers may have room now
+        if let Ok(n) = ret {
+            if n > 0 {
+                shared.wait.wake_all();
+            }
+        }
+        ret
+    }
+
+    /// Blocks until everything is written, or until there are no readers left
+    fn write(&mut self, _pos: u64, buf: &[u8]) -> Result<usize> {
+        let shared = &self.shared;
+        let nonblock = self.nonblock;
+        let mut done = 0;
+        shared.wait.wait_for(|| {
+            let mut pipe = shared.pipe.lock();
+            // TODO SIGPIPE
+            if pipe.readers == 0 {
+                return Some(if done > 0 { Ok(done) } else { Err(EPIPE) });
+            }
+            let n = core::cmp::min(buf.len() - done, PIPE_SIZE - pipe.buf.len());
+            pipe.buf.extend(&buf[done..done + n]);
+            drop(pipe);
+            done += n;
+            // Readers are woken up as soon as there is something, the rest may not fit
+            if n > 0 {
+                shared.wait.wake_all();
+            }
+            if done == buf.len() {
+                Some(Ok(done))
+            } else if nonblock {
+                Some(if done > 0 { Ok(done) } else { Err(EAGAIN) })
+            } else {
+                None
+            }
+        })
+    }
+
+    fn close(&mut self) -> Result<()> {
+        {
+            let mut pipe = self.shared.pipe.lock();
+            if self.read {
+                pipe.readers -= 1;
+            }
+            if self.write {
+                pipe.writers -= 1;
+            }
+        }
+        self.shared.wait.wake_all();
+        Ok(())
+    }
+}
+
+/// Create an anonymous pipe, returns the read end and the write end
+pub fn new_pair(flags: OpenFlags) -> (File, File) {
+    let shared = Shared::new();
+    let open_end = |flags: OpenFlags| {
+        let end = PipeFile::new(shared.clone(), flags);
+        end.attach();
+        File {
+            dentry: None,
+            pos: 0,
+            flags,
+            ops: Box::new(end),
+        }
+    };
+    let extra = flags & OpenFlags::NONBLOCK;
+    (
+        open_end(OpenFlags::RDONLY | extra),
+        open_end(OpenFlags::WRONLY | extra),
+    )
+}
+
+/// Pipes of the named FIFOs currently open, by filesystem and inode
+/// The pipe goes away, with its content, when the last end is closed
+static FIFOS: SpinLock<BTreeMap<(usize, Inonum), Weak<Shared>>> = SpinLock::new(BTreeMap::new());
+
+/// Operations for a FIFO inode, `fs` identifies the filesystem it belongs to
+/// Every open of the same inode shares the same pipe
+pub fn fifo_ops(fs: usize, inode: Inonum) -> Box<dyn FileOps> {
+    let mut fifos = FIFOS.lock();
+    let key = (fs, inode);
+    let shared = match fifos.get(&key).and_then(|p| p.upgrade()) {
+        Some(shared) => shared,
+        None => {
+            let shared = Shared::new();
+            fifos.insert(key, Arc::downgrade(&shared));
+            shared
+        }
+    };
+    // Dead entries are dropped as other FIFOs are opened
+    fifos.retain(|_, p| p.strong_count() > 0);
+    // The access mode is only known once the file is opened
+    Box::new(PipeFile::new(shared, OpenFlags::RDONLY))
+}
@@ -0,0 +1,536 @@
+//! Filesystem living in memory, used for the initrd and mounted as tmpfs on /tmp and /run
+//! File contents are kept in physical pages, reached through the linear mapping
+
+use super::pipe;
+use super::vfs::{
+    self, Dentry, Dirent, File, FileOps, Filesystem, Inonum, NodeOps, OpenFlags, Vnode,
+    VnodeType, Whence, NAME_MAX, S_IFDIR,
+};
+use crate::error::{codes::*, Result};
+use crate::klib::lock::RwLock;
+use crate::klib::time;
+use crate::klib::mem::{memcpy, memset};
+use crate::memory::pmm::{self, Frame, Zone};
+use crate::memory::vmm::mapper;
+use crate::memory::PAGE_SIZE;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const ROOT_INODE: Inonum = 1;
+
+/// Content of a regular file, pages are allocated when first written
+#[derive(Default)]
+pub struct Pages {
+    frames: Vec<Option<Frame>>,
+    size: usize,
+}
+
+impl Pages {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Kernel address of the page at `index`, allocated if needed
+    fn page(&mut self, index: usize) -> Result<usize> {
+        if index >= self.frames.len() {
+            self.frames.resize_with(index + 1, || None);
+        }
+        if let Some(frame) = &self.frames[index] {
+            return mapper::phys_to_virt(frame.0 * PAGE_SIZE).ok_or(ENOMEM);
+        }
+        let frame = pmm::alloc_page(Zone::Normal)?;
+        let address = mapper::phys_to_virt(frame.0 * PAGE_SIZE).ok_or(ENOMEM)?;
+        memset(address as *mut c_void, 0, PAGE_SIZE);
+        self.frames[index] = Some(frame);
+        Ok(address)
+    }
+
+    pub fn read(&self, pos: usize, buf: &mut [u8]) -> usize {
+        if pos >= self.size {
+            return 0;
+        }
+        let len = buf.len().min(self.size - pos);
+        let mut done = 0;
+        while done < len {
+            let current = pos + done;
+            let offset = current % PAGE_SIZE;
+            let n = (PAGE_SIZE - offset).min(len - done);
+            let dest = &mut buf[done..done + n];
+            match self.frames.get(current / PAGE_SIZE) {
+                Some(Some(frame)) => {
+                    let src = mapper::phys_to_virt(frame.0 * PAGE_SIZE).unwrap() + offset;
+                    memcpy(dest.as_mut_ptr() as *mut c_void, src as *const c_void, n);
+                }
+                // Hole
+                _ => dest.fill(0),
+            }
+            done += n;
+        }
+        len
+    }
+
+    pub fn write(&mut self, pos: usize, buf: &[u8]) -> Result<usize> {
+        let end = pos.checked_add(buf.len()).ok_or(EFBIG)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let current = pos + done;
+            let offset = current % PAGE_SIZE;
+            let n = (PAGE_SIZE - offset).min(buf.len() - done);
+            let dest = self.page(current / PAGE_SIZE)? + offset;
+            memcpy(dest as *mut c_void, buf[done..].as_ptr() as *const c_void, n);
+            done += n;
+        }
+        self.size = self.size.max(end);
+        Ok(buf.len())
+    }
+
+    /// Shrink or extend the file, extended parts read as zeroes
+    pub fn truncate(&mut self, size: usize) {
+        let npages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        if npages < self.frames.len() {
+            for frame in self.frames.drain(npages..).flatten() {
+                pmm::free_page(frame);
+            }
+        }
+        // The end of the last page must read as zeroes if the file grows again
+        if size < self.size && size % PAGE_SIZE != 0 {
+            if let Some(Some(frame)) = self.frames.get(size / PAGE_SIZE) {
+                let offset = size % PAGE_SIZE;
+                let address = mapper::phys_to_virt(frame.0 * PAGE_SIZE).unwrap() + offset;
+                memset(address as *mut c_void, 0, PAGE_SIZE - offset);
+            }
+        }
+        self.size = size;
+    }
+}
+
+impl Drop for Pages {
+    fn drop(&mut self) {
+        self.truncate(0);
+    }
+}
+
+pub enum Content {
+    /// Entries by name, including "." and ".."
+    Dir(BTreeMap<Vec<u8>, Inonum>),
+    File(Pages),
+    /// Target of the link
+    Symlink(Vec<u8>),
+    /// FIFOs and device files, only the inode exists
+    Special,
+}
+
+pub struct RamNode {
+    pub mode: u16,
+    pub uid: u16,
+    pub gid: u16,
+    /// Seconds since the epoch, like on disk
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+    pub content: Content,
+}
+
+impl RamNode {
+    /// Empty node of the type given by the mode bits
+    pub fn new(mode: u16, uid: u16, gid: u16) -> Self {
+        let content = match VnodeType::from_mode(mode) {
+            Some(VnodeType::Dir) => Content::Dir(BTreeMap::new()),
+            Some(VnodeType::File) => Content::File(Pages::default()),
+            Some(VnodeType::Symlink) => Content::Symlink(Vec::new()),
+            _ => Content::Special,
+        };
+        let now = time::realtime_secs();
+        RamNode {
+            mode,
+            uid,
+            gid,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            content,
+        }
+    }
+
+    /// The content changed
+    fn touch(&mut self) {
+        self.mtime = time::realtime_secs();
+        self.ctime = self.mtime;
+    }
+
+    /// Directory with only "." and ".."
+    fn is_empty_dir(&self) -> bool {
+        match &self.content {
+            Content::Dir(entries) => entries.keys().all(|n| n == b"." || n == b".."),
+            _ => false,
+        }
+    }
+}
+
+/// Validate a name for a new entry
+fn check_name(name: &[u8]) -> Result<()> {
+    if name.is_empty() || name.contains(&b'/') {
+        return Err(EINVAL);
+    }
+    if name.len() > NAME_MAX {
+        return Err(ENAMETOOLONG);
+    }
+    Ok(())
+}
+
+pub struct RamFs {
+    nodes: RwLock<BTreeMap<Inonum, Arc<RwLock<RamNode>>>>,
+    next_inode: AtomicU64,
+    /// Taken for writing by every change of the tree, so that they do not interleave
+    namespace: RwLock<()>,
+}
+
+impl RamFs {
+    /// An empty filesystem, with only its root directory
+    pub fn new(root_mode: u16) -> Arc<RamFs> {
+        let fs = RamFs {
+            nodes: RwLock::new(BTreeMap::new()),
+            next_inode: AtomicU64::new(ROOT_INODE + 1),
+            namespace: RwLock::new(()),
+        };
+        let mut root = RamNode::new(S_IFDIR | root_mode, 0, 0);
+        if let Content::Dir(entries) = &mut root.content {
+            entries.insert(b".".to_vec(), ROOT_INODE);
+            entries.insert(b"..".to_vec(), ROOT_INODE);
+        }
+        fs.nodes.write().insert(ROOT_INODE, Arc::new(RwLock::new(root)));
+        Arc::new(fs)
+    }
+
+    pub fn root(&self) -> Inonum {
+        ROOT_INODE
+    }
+
+    fn node(&self, inode: Inonum) -> Result<Arc<RwLock<RamNode>>> {
+        self.nodes.read().get(&inode).cloned().ok_or(ENOENT)
+    }
+
+    /// Inode of the entry `name` in the directory `dir`
+    pub fn lookup(&self, dir: Inonum, name: &[u8]) -> Result<Option<Inonum>> {
+        let node = self.node(dir)?;
+        let node = node.read();
+        match &node.content {
+            Content::Dir(entries) => Ok(entries.get(name).copied()),
+            _ => Err(ENOTDIR),
+        }
+    }
+
+    /// Add `node` to the directory `dir`, fails with EEXIST if the name is taken
+    pub fn insert(&self, dir: Inonum, name: &[u8], mut node: RamNode) -> Result<Inonum> {
+        check_name(name)?;
+        let _namespace = self.namespace.write();
+        let parent = self.node(dir)?;
+        let mut parent = parent.write();
+        let entries = match &mut parent.content {
+            Content::Dir(entries) => entries,
+            _ => return Err(ENOTDIR),
+        };
+        if entries.contains_key(name) {
+            return Err(EEXIST);
+        }
+
+        let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
+        if let Content::Dir(children) = &mut node.content {
+            children.insert(b".".to_vec(), inode);
+            children.insert(b"..".to_vec(), dir);
+        }
+        entries.insert(name.to_vec(), inode);
+        parent.touch();
+        self.nodes.write().insert(inode, Arc::new(RwLock::new(node)));
+        Ok(inode)
+    }
+
+    /// Remove the entry `name` of `dir`, a directory only if `dir_wanted` and it is empty
+    /// Open files keep their node until they are closed
+    pub fn remove(&self, dir: Inonum, name: &[u8], dir_wanted: bool) -> Result<()> {
+        match name {
+            b"." => return Err(EINVAL),
+            b".." => return Err(ENOTEMPTY),
+            _ => {}
+        }
+        let _namespace = self.namespace.write();
+        let parent = self.node(dir)?;
+        let mut parent = parent.write();
+        let entries = match &mut parent.content {
+            Content::Dir(entries) => entries,
+            _ => return Err(ENOTDIR),
+        };
+        let inode = *entries.get(name).ok_or(ENOENT)?;
+        let node = self.node(inode)?;
+        {
+            let node = node.read();
+            let is_dir = matches!(node.content, Content::Dir(_));
+            if dir_wanted && !is_dir {
+                return Err(ENOTDIR);
+            }
+            if !dir_wanted && is_dir {
+                return Err(EISDIR);
+            }
+            if is_dir && !node.is_empty_dir() {
+                return Err(ENOTEMPTY);
+            }
+        }
+        entries.remove(name);
+        parent.touch();
+        // TODO hard links, every node has a single name for now
+        self.nodes.write().remove(&inode);
+        Ok(())
+    }
+
+    /// Whether `inode` is `ancestor` or one of its descendants
+    fn is_within(&self, mut inode: Inonum, ancestor: Inonum) -> Result<bool> {
+        loop {
+            if inode == ancestor {
+                return Ok(true);
+            }
+            if inode == ROOT_INODE {
+                return Ok(false);
+            }
+            inode = self.lookup(inode, b"..")?.ok_or(EUCLEAN)?;
+        }
+    }
+
+    /// Move the entry `name` of `dir` to `new_name` in `new_dir`, replacing the entry that
+    /// was there if it is compatible
+    pub fn rename(&self, dir: Inonum, name: &[u8], new_dir: Inonum, new_name: &[u8]) -> Result<()> {
+        check_name(new_name)?;
+        if matches!(name, b"." | b"..") || matches!(new_name, b"." | b"..") {
+            return Err(EINVAL);
+        }
+        let _namespace = self.namespace.write();
+        let inode = self.lookup(dir, name)?.ok_or(ENOENT)?;
+        let is_dir = matches!(self.node(inode)?.read().content, Content::Dir(_));
+        // A directory cannot be moved inside itself
+        if is_dir && self.is_within(new_dir, inode)? {
+            return Err(EINVAL);
+        }
+
+        let replaced = self.lookup(new_dir, new_name)?;
+        if replaced == Some(inode) {
+            return Ok(());
+        }
+        if let Some(target) = replaced {
+            let target = self.node(target)?;
+            let target = target.read();
+            match (&target.content, is_dir) {
+                (Content::Dir(_), false) => return Err(EISDIR),
+                (Content::Dir(_), true) if !target.is_empty_dir() => return Err(ENOTEMPTY),
+                (Content::Dir(_), true) => {}
+                (_, true) => return Err(ENOTDIR),
+                (_, false) => {}
+            }
+        }
+
+        // Directory locks are taken one at a time, the namespace lock keeps this atomic
+        if let Content::Dir(entries) = &mut self.node(dir)?.write().content {
+            entries.remove(name);
+        }
+        if let Content::Dir(entries) = &mut self.node(new_dir)?.write().content {
+            entries.insert(new_name.to_vec(), inode);
+        }
+        if is_dir {
+            if let Content::Dir(entries) = &mut self.node(inode)?.write().content {
+                entries.insert(b"..".to_vec(), new_dir);
+            }
+        }
+        if let Some(target) = replaced {
+            self.nodes.write().remove(&target);
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for Arc<RamFs> {
+    fn get_root_inode(&self) -> Result<Inonum> {
+        Ok(ROOT_INODE)
+    }
+
+    fn read_inode(&self, inode: Inonum) -> Result<Vnode> {
+        let node = self.node(inode)?;
+        let node = node.read();
+        Ok(Vnode {
+            inode,
+            uid: node.uid,
+            gid: node.gid,
+            mode: node.mode,
+            kind: VnodeType::from_mode(node.mode).ok_or(EUCLEAN)?,
+            atime: node.atime,
+            mtime: node.mtime,
+            ctime: node.ctime,
+            ops: Arc::new(RamNodeOps { fs: self.clone() }),
+        })
+    }
+}
+
+pub struct RamNodeOps {
+    fs: Arc<RamFs>,
+}
+
+impl NodeOps for RamNodeOps {
+    fn open(&self, node: &Vnode, dent: &Arc<Dentry>) -> Result<File> {
+        let ram = self.fs.node(node.inode)?;
+        let ops: Box<dyn FileOps> = match node.kind {
+            VnodeType::FIFO => pipe::fifo_ops(Arc::as_ptr(&self.fs) as usize, node.inode),
+            VnodeType::Dir => {
+                // Entries created after opening are not listed
+                let entries = match &ram.read().content {
+                    Content::Dir(entries) => entries
+                        .iter()
+                        .map(|(name, &inode)| (name.clone(), inode))
+                        .collect(),
+                    _ => return Err(EUCLEAN),
+                };
+                Box::new(RamDir { entries, index: 0 })
+            }
+            VnodeType::File => Box::new(RamFile { node: ram }),
+            // TODO follow symlinks during the path walk
+            VnodeType::Symlink => return Err(ELOOP),
+            // TODO device files
+            _ => return Err(ENODEV),
+        };
+        Ok(File {
+            dentry: Some(dent.clone()),
+            pos: 0,
+            flags: OpenFlags::RDONLY,
+            ops,
+        })
+    }
+
+    fn create(&self, dir: &Vnode, name: &[u8], mode: u16) -> Result<Inonum> {
+        // TODO owner of the calling process
+        self.fs.insert(dir.inode, name, RamNode::new(mode, 0, 0))
+    }
+
+    fn unlink(&self, dir: &Vnode, name: &[u8]) -> Result<()> {
+        self.fs.remove(dir.inode, name, false)
+    }
+
+    fn rmdir(&self, dir: &Vnode, name: &[u8]) -> Result<()> {
+        self.fs.remove(dir.inode, name, true)
+    }
+
+    fn rename(&self, dir: &Vnode, name: &[u8], new_dir: &Vnode, new_name: &[u8]) -> Result<()> {
+        self.fs.rename(dir.inode, name, new_dir.inode, new_name)
+    }
+}
+
+pub struct RamFile {
+    node: Arc<RwLock<RamNode>>,
+}
+
+impl RamFile {
+    fn size(&self) -> u64 {
+        match &self.node.read().content {
+            Content::File(pages) => pages.size() as u64,
+            _ => 0,
+        }
+    }
+}
+
+impl FileOps for RamFile {
+    fn open(&mut self, flags: OpenFlags) -> Result<()> {
+        if flags.contains(OpenFlags::TRUNC) && flags.writable() {
+            let mut node = self.node.write();
+            if let Content::File(pages) = &mut node.content {
+                pages.truncate(0);
+                node.touch();
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize> {
+        let mut node = self.node.write();
+        node.atime = time::realtime_secs();
+        match &node.content {
+            Content::File(pages) => Ok(pages.read(usize::try_from(pos).unwrap_or(usize::MAX), buf)),
+            _ => Err(EUCLEAN),
+        }
+    }
+
+    fn write(&mut self, pos: u64, buf: &[u8]) -> Result<usize> {
+        let pos = usize::try_from(pos).map_err(|_| EFBIG)?;
+        let mut node = self.node.write();
+        let written = match &mut node.content {
+            Content::File(pages) => pages.write(pos, buf)?,
+            _ => return Err(EUCLEAN),
+        };
+        node.touch();
+        Ok(written)
+    }
+
+    fn lseek(&mut self, pos: u64, offset: i64, whence: Whence) -> Result<u64> {
+        vfs::seek_position(pos, self.size(), offset, whence)
+    }
+}
+
+pub struct RamDir {
+    entries: Vec<(Vec<u8>, Inonum)>,
+    index: usize,
+}
+
+impl FileOps for RamDir {
+    fn open(&mut self, _flags: OpenFlags) -> Result<()> {
+        Ok(())
+    }
+
+    fn readdir(&mut self) -> Result<Option<Dirent>> {
+        let (name, inode) = match self.entries.get(self.index) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        self.index += 1;
+        let mut dirent = Dirent {
+            inode: *inode,
+            name: [0; NAME_MAX],
+            name_len: name.len(),
+            size: 0,
+        };
+        dirent.name[..name.len()].copy_from_slice(name);
+        Ok(Some(dirent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::vfs::S_IFREG;
+    use crate::{klog, kprint};
+
+    #[test_case]
+    fn ramfs_files() {
+        kprint!("ramfs files... ");
+        let fs = RamFs::new(0o755);
+        let dir = fs.insert(fs.root(), b"dir", RamNode::new(S_IFDIR | 0o755, 0, 0)).unwrap();
+        let file = fs.insert(dir, b"file", RamNode::new(S_IFREG | 0o644, 0, 0)).unwrap();
+        assert_eq!(fs.insert(dir, b"file", RamNode::new(S_IFREG, 0, 0)), Err(EEXIST));
+
+        let node = fs.node(file).unwrap();
+        let mut f = RamFile { node };
+        // Across a page boundary, with a hole before
+        let data = [0x42u8; 100];
+        assert_eq!(f.write(PAGE_SIZE as u64 - 50, &data), Ok(100));
+        let mut buf = [1u8; 200];
+        assert_eq!(f.read(PAGE_SIZE as u64 - 100, &mut buf), Ok(150));
+        assert!(buf[..50].iter().all(|&b| b == 0));
+        assert!(buf[50..150].iter().all(|&b| b == 0x42));
+
+        assert_eq!(fs.remove(fs.root(), b"dir", true), Err(ENOTEMPTY));
+        fs.rename(dir, b"file", fs.root(), b"moved").unwrap();
+        assert_eq!(fs.lookup(fs.root(), b"moved"), Ok(Some(file)));
+        assert_eq!(fs.rename(fs.root(), b"dir", dir, b"inside"), Err(EINVAL));
+        fs.remove(fs.root(), b"dir", true).unwrap();
+        fs.remove(fs.root(), b"moved", false).unwrap();
+        assert_eq!(fs.lookup(fs.root(), b"moved"), Ok(None));
+        klog!("[ok]");
+    }
+}
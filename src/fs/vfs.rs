@@ -1,11 +1,81 @@
 use super::block::BlockDev;
-use crate::dbg;
+use crate::{dbg, klog};
 use crate::error::{codes::*, Result};
 use alloc::sync::Arc;
 use alloc::boxed::Box;
+use alloc::string::String;
+use bitflags::bitflags;
 use core::fmt::Debug;
 
 pub const NAME_MAX: usize = 255;
+pub const PATH_MAX: usize = 4096;
+
+// File type bits of the mode
+pub const S_IFDIR: u16 = 0o040000;
+pub const S_IFREG: u16 = 0o100000;
+
+bitflags! {
+    /// Flags given to open, the values match Linux
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    pub struct OpenFlags : u32 {
+        const WRONLY = 0o1;
+        const RDWR = 0o2;
+        const CREAT = 0o100;
+        const EXCL = 0o200;
+        const TRUNC = 0o1000;
+        const APPEND = 0o2000;
+        const NONBLOCK = 0o4000;
+        const DIRECTORY = 0o200000;
+        const CLOEXEC = 0o2000000;
+        const _ = !0;
+    }
+}
+
+impl OpenFlags {
+    /// No flag means read only
+    pub const RDONLY: OpenFlags = OpenFlags::empty();
+
+    #[inline]
+    pub fn readable(&self) -> bool {
+        !self.contains(OpenFlags::WRONLY)
+    }
+
+    #[inline]
+    pub fn writable(&self) -> bool {
+        self.intersects(OpenFlags::WRONLY | OpenFlags::RDWR)
+    }
+}
+
+/// Reference point of lseek
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Whence {
+    Set,
+    Cur,
+    End,
+}
+
+impl TryFrom<usize> for Whence {
+    type Error = i32;
+
+    fn try_from(value: usize) -> Result<Self> {
+        match value {
+            0 => Ok(Whence::Set),
+            1 => Ok(Whence::Cur),
+            2 => Ok(Whence::End),
+            _ => Err(EINVAL),
+        }
+    }
+}
+
+/// New position of a seek in a file of `size` bytes
+pub fn seek_position(pos: u64, size: u64, offset: i64, whence: Whence) -> Result<u64> {
+    let base = match whence {
+        Whence::Set => 0,
+        Whence::Cur => pos,
+        Whence::End => size,
+    };
+    base.checked_add_signed(offset).ok_or(EINVAL)
+}
 
 // use to compose other filesystem
 pub struct Info {
@@ -27,6 +97,22 @@ pub enum VnodeType {
     Socket,
 }
 
+impl VnodeType {
+    /// The file type is in the 4 upper bits of the mode
+    pub fn from_mode(mode: u16) -> Option<VnodeType> {
+        match mode & 0xf000 {
+            0x1000 => Some(VnodeType::FIFO),
+            0x2000 => Some(VnodeType::Char),
+            0x4000 => Some(VnodeType::Dir),
+            0x6000 => Some(VnodeType::Block),
+            0x8000 => Some(VnodeType::File),
+            0xa000 => Some(VnodeType::Symlink),
+            0xc000 => Some(VnodeType::Socket),
+            _ => None,
+        }
+    }
+}
+
 pub type Inonum = u64;
 
 pub struct Mountpoint {
@@ -48,7 +134,10 @@ pub struct Vnode {
     pub gid: u16,
     pub mode: u16,
     pub kind: VnodeType,
-    // TODO add timestamps
+    /// Last access, content modification and inode change, in seconds since the epoch
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
     pub ops: Arc<dyn NodeOps>,
 }
 
@@ -73,15 +162,24 @@ impl Debug for Dentry {
 pub struct Dirent {
     pub inode: Inonum,
     pub name: [u8; NAME_MAX],
+    pub name_len: usize,
     pub size: usize,
 }
 
+impl Dirent {
+    /// The valid part of the name buffer
+    #[inline]
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
 impl Debug for Dirent {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "dirent:  name{}",
-            core::str::from_utf8(self.name.as_slice()).unwrap()
+            core::str::from_utf8(self.name()).unwrap_or("?")
         )
     }
 }
@@ -90,30 +188,116 @@ impl Debug for Dirent {
 // File descriptor structure
 #[allow(dead_code)]
 pub struct File {
-    pub dentry: Arc<Dentry>,
+    /// None for files that are not in the tree, eg. the console
+    pub dentry: Option<Arc<Dentry>>,
     pub pos: u64,
+    pub flags: OpenFlags,
     pub ops: Box<dyn FileOps>,
 }
 
+impl File {
+    /// Read from the current position, and advance it by the number of bytes read
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.ops.read(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    /// Write at the current position, and advance it by the number of bytes written
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.flags.contains(OpenFlags::APPEND) {
+            self.pos = self.ops.lseek(self.pos, 0, Whence::End)?;
+        }
+        let n = self.ops.write(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    /// Move the current position, returns the new one
+    pub fn lseek(&mut self, offset: i64, whence: Whence) -> Result<u64> {
+        self.pos = self.ops.lseek(self.pos, offset, whence)?;
+        Ok(self.pos)
+    }
+}
+
+impl Drop for File {
+    /// The last reference to an open file is gone
+    fn drop(&mut self) {
+        if let Err(e) = self.ops.close() {
+            klog!("Error {} while closing file", e);
+        }
+    }
+}
+
 // Operations traits
 // Those trait will be implemnted by each filesysystem
 // They will provide the callbacks to filesystem-specific operations
 
 /// Interface for Vnode operations
-pub trait NodeOps {
+/// The namespace operations are called on the directory, read-only filesystems keep the
+/// defaults
+/// Shared by the tasks of every CPU
+pub trait NodeOps: Send + Sync {
     fn open(&self, node: &Vnode, dentry: &Arc<Dentry>) -> Result<File>;
+
+    /// Create the entry `name` in `dir`, its type is given by the mode bits
+    fn create(&self, _dir: &Vnode, _name: &[u8], _mode: u16) -> Result<Inonum> {
+        Err(EROFS)
+    }
+
+    /// Remove an entry that is not a directory
+    fn unlink(&self, _dir: &Vnode, _name: &[u8]) -> Result<()> {
+        Err(EROFS)
+    }
+
+    /// Remove an empty directory
+    fn rmdir(&self, _dir: &Vnode, _name: &[u8]) -> Result<()> {
+        Err(EROFS)
+    }
+
+    /// Move the entry `name` of `dir` to `new_name` in `new_dir`, both on this filesystem
+    fn rename(&self, _dir: &Vnode, _name: &[u8], _new_dir: &Vnode, _new_name: &[u8]) -> Result<()> {
+        Err(EROFS)
+    }
 }
 
 /// Interface for file descriptor operations
-pub trait FileOps {
+/// Open files are shared with the children, which may run on other CPUs
+pub trait FileOps: Send {
 
-    fn open(&mut self) -> Result<()> {
+    /// Called when the file is opened, with the flags given to open
+    fn open(&mut self, _flags: OpenFlags) -> Result<()> {
         Err(ENOSYS)
     }
 
     fn readdir(&mut self) -> Result<Option<Dirent>> {
         Err(ENOSYS)
     }
+
+    /// Read at the position `pos` in the file, returns the number of bytes read, 0 at the end
+    fn read(&mut self, _pos: u64, _buf: &mut [u8]) -> Result<usize> {
+        Err(ENOSYS)
+    }
+
+    /// Write at the position `pos` in the file, returns the number of bytes written
+    fn write(&mut self, _pos: u64, _buf: &[u8]) -> Result<usize> {
+        Err(ENOSYS)
+    }
+
+    /// Compute the position after a seek from `pos`, files that cannot seek keep the default
+    fn lseek(&mut self, _pos: u64, _offset: i64, _whence: Whence) -> Result<u64> {
+        Err(ESPIPE)
+    }
+
+    /// Device specific request
+    fn ioctl(&mut self, _cmd: usize, _arg: usize) -> Result<usize> {
+        Err(ENOTTY)
+    }
+
+    /// Called when the file is not referenced anymore
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
 // Every filestystem will expose this API
@@ -130,9 +314,15 @@ pub trait FileSystemSetup {
     fn try_init(driver: Arc<BlockDev>) -> Result<Option<Arc<Self>>>;
 }
 
+/// A filesystem found on a block device, named after its partition, eg. hda1
+pub struct Volume {
+    pub name: String,
+    pub fs: Arc<dyn Filesystem>,
+}
+
 // TODO refactor to be more efficient
 // optimize heap access, like a pointer ?
-type FsVec = Vec<Arc<dyn Filesystem>>;
+type FsVec = Vec<Volume>;
 use alloc::vec::Vec;
 static mut FILESYSTEMS: FsVec = vec![];
 pub fn get_filesystems() -> &'static mut FsVec {
@@ -163,9 +353,19 @@ impl Path {
         split.skip(0)
     }
 
-    // TODO will not work on apths containing dots
     pub fn absolute(&self) -> bool {
-        self.buff[0] == b'/'
+        self.buff.first() == Some(&b'/')
+    }
+    /// The non empty components
+    pub fn components(&self) -> impl Iterator<Item = &str> {
+        self.component_iter().filter(|c| !c.is_empty())
+    }
+}
+
+impl Mountpoint {
+    /// Number of components of the mountpoint path, 0 for the root
+    fn depth(&self) -> usize {
+        self.path.components().count()
     }
 }
 
@@ -173,12 +373,16 @@ impl Path {
 static mut MOUNTPOINTS: Vec<Arc<Mountpoint>> = vec![];
 
 // Takes the root filesystem
+/// A filesystem already mounted on the same path is replaced, the root stays the first
 pub fn register_mount(path: &str, rootfs: Arc<dyn Filesystem>) -> Result<()> {
-    unsafe {
-        MOUNTPOINTS.push(Arc::new(Mountpoint {
-            path: Path::new(path),
-            fs: rootfs,
-        }))
+    let mount = Arc::new(Mountpoint {
+        path: Path::new(path),
+        fs: rootfs,
+    });
+    let mounts = unsafe { &mut *core::ptr::addr_of_mut!(MOUNTPOINTS) };
+    match mounts.iter_mut().find(|m| m.path.buff == mount.path.buff) {
+        Some(existing) => *existing = mount,
+        None => mounts.push(mount),
     }
     Ok(())
 }
@@ -186,48 +390,46 @@ pub fn register_mount(path: &str, rootfs: Arc<dyn Filesystem>) -> Result<()> {
 /// Will go through the list of mountpoints and find the longest match
 /// If it doesn't find any match, the root mountpoint is returned
 pub fn match_mountpoint(path: &Path) -> Arc<Mountpoint> {
-    unsafe {
-        let mut longest_match: usize = 0;
-        let mut longest_index: usize = 0;
-        for (i, mount) in MOUNTPOINTS.iter().enumerate() {
-            if mount.path.len() > path.len() {
-                continue;
-            }
-            let count = mount
-                .path
-                .component_iter()
-                .zip(path.component_iter())
-                .take_while(|(a, b)| a == b)
-                .count();
-            if count > longest_match {
-                longest_match = count;
-                longest_index = i;
-            }
+    let mounts = unsafe { &*core::ptr::addr_of!(MOUNTPOINTS) };
+    let mut longest_match: usize = 0;
+    let mut longest_index: usize = 0;
+    for (i, mount) in mounts.iter().enumerate() {
+        let depth = mount.depth();
+        if depth <= longest_match {
+            continue;
+        }
+        // All the components of the mountpoint must match
+        let matching = mount
+            .path
+            .components()
+            .zip(path.components())
+            .take_while(|(a, b)| a == b)
+            .count();
+        if matching == depth {
+            longest_match = depth;
+            longest_index = i;
         }
-        // TODO if the mountpoint at index 0 is not root, this will do something funky
-        MOUNTPOINTS[usize::try_from(longest_index).unwrap()].clone()
     }
+    // TODO if the mountpoint at index 0 is not root, this will do something funky
+    mounts[longest_index].clone()
 }
 
 /// Takes a path and returns the corresponding node if any
 pub fn walk_path_node(path: &Path) -> Result<Arc<Dentry>> {
-    // TODO handle properly
-    assert!(
-        path.absolute() && !path.buff.contains(&b'.'),
-        "Path is not absolute"
-    );
+    // TODO relative paths, once tasks have a working directory
+    // "." and ".." are resolved by the directory entries themselves
+    if !path.absolute() {
+        return Err(EINVAL);
+    }
+    if path.len() > PATH_MAX {
+        return Err(ENAMETOOLONG);
+    }
 
     let mount = match_mountpoint(path);
 
     // TODO refactor
     // iterator starting at end of mountpoints path
-    // TODO the -1 here is not clear enough, it's supposed to be for the root component that's
-    // empty
-    let mut components = path
-        .component_iter()
-        .skip(mount.path.component_iter().count() - 1)
-        .peekable()
-        .into_iter();
+    let mut components = path.components().skip(mount.depth()).peekable().into_iter();
 
     let mut inode = Some(mount.fs.get_root_inode()?);
     let mut node = Arc::new(mount.fs.read_inode(inode.unwrap())?);
@@ -248,17 +450,23 @@ pub fn walk_path_node(path: &Path) -> Result<Arc<Dentry>> {
         }
 
         dbg!("path component: {}", comp);
+        if comp.len() > NAME_MAX {
+            return Err(ENAMETOOLONG);
+        }
+        if !matches!(dentry.vnode.kind, VnodeType::Dir) {
+            return Err(ENOTDIR);
+        }
         //TODO locking concurrency ?
         let mut file = dentry.vnode.ops.open(&dentry.vnode, &dentry)?;
         // TODO check if really useful
-        file.ops.open()?;
+        file.ops.open(OpenFlags::DIRECTORY)?;
         dbg!("Listing dir from {}", comp);
 
         inode = None;
         while let Some(dirent) = file.ops.readdir()? {
             dbg!("dentry {:?}", dirent);
             // Match
-            if comp.as_bytes() == dirent.name.as_slice() {
+            if comp.as_bytes() == dirent.name() {
                 inode = Some(dirent.inode);
                 break;
             }
@@ -278,9 +486,108 @@ pub fn walk_path_node(path: &Path) -> Result<Arc<Dentry>> {
     Ok(dentry)
 }
 
-pub fn vfs_open(path: &str) -> Result<File> {
+/// Open the file at `path`
+pub fn vfs_open(path: &str, flags: OpenFlags) -> Result<File> {
     let p = Path::new(path);
-    let _node = walk_path_node(&p)?;
+    let dentry = walk_path_node(&p)?;
+
+    let is_dir = matches!(dentry.vnode.kind, VnodeType::Dir);
+    if flags.contains(OpenFlags::DIRECTORY) && !is_dir {
+        return Err(ENOTDIR);
+    }
+    if is_dir && flags.writable() {
+        return Err(EISDIR);
+    }
 
-    Err(EINVAL)
+    let mut file = dentry.vnode.ops.open(&dentry.vnode, &dentry)?;
+    file.flags = flags;
+    file.ops.open(flags)?;
+    Ok(file)
+}
+
+/// Whether a filesystem is mounted on `path`
+fn is_mountpoint(path: &Path) -> bool {
+    let mounts = unsafe { &*core::ptr::addr_of!(MOUNTPOINTS) };
+    mounts.iter().any(|m| m.path.components().eq(path.components()))
+}
+
+/// The directory containing `path`, and the last component of `path`
+/// The root has no parent, EINVAL is returned for it
+fn walk_parent(path: &str) -> Result<(Arc<Dentry>, &str)> {
+    let (parent, name) = path.trim_end_matches('/').rsplit_once('/').ok_or(EINVAL)?;
+    if name.is_empty() {
+        return Err(EINVAL);
+    }
+    if name.len() > NAME_MAX {
+        return Err(ENAMETOOLONG);
+    }
+    let parent = if parent.is_empty() { "/" } else { parent };
+    let dentry = walk_path_node(&Path::new(parent))?;
+    if !matches!(dentry.vnode.kind, VnodeType::Dir) {
+        return Err(ENOTDIR);
+    }
+    Ok((dentry, name))
+}
+
+/// Open the file at `path`, it is created with `mode` if it does not exist
+pub fn vfs_create(path: &str, flags: OpenFlags, mode: u16) -> Result<File> {
+    match walk_path_node(&Path::new(path)) {
+        Ok(_) if flags.contains(OpenFlags::EXCL) => return Err(EEXIST),
+        Ok(_) => {}
+        Err(ENOENT) => {
+            let (dir, name) = walk_parent(path)?;
+            match dir.vnode.ops.create(&dir.vnode, name.as_bytes(), S_IFREG | (mode & 0o7777)) {
+                Ok(_) => {}
+                // Someone else was faster
+                Err(EEXIST) if !flags.contains(OpenFlags::EXCL) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(e) => return Err(e),
+    }
+    vfs_open(path, flags)
+}
+
+/// Create a directory
+pub fn vfs_mkdir(path: &str, mode: u16) -> Result<()> {
+    let (dir, name) = walk_parent(path)?;
+    dir.vnode.ops.create(&dir.vnode, name.as_bytes(), S_IFDIR | (mode & 0o7777))?;
+    Ok(())
+}
+
+/// Remove a file, or anything that is not a directory
+pub fn vfs_unlink(path: &str) -> Result<()> {
+    if is_mountpoint(&Path::new(path)) {
+        return Err(EBUSY);
+    }
+    let (dir, name) = walk_parent(path)?;
+    dir.vnode.ops.unlink(&dir.vnode, name.as_bytes())
+}
+
+/// Remove an empty directory
+pub fn vfs_rmdir(path: &str) -> Result<()> {
+    if is_mountpoint(&Path::new(path)) {
+        return Err(EBUSY);
+    }
+    let (dir, name) = walk_parent(path)?;
+    dir.vnode.ops.rmdir(&dir.vnode, name.as_bytes())
+}
+
+/// Move `old` to `new`, replacing it if it exists, both must be on the same filesystem
+pub fn vfs_rename(old: &str, new: &str) -> Result<()> {
+    let (old_path, new_path) = (Path::new(old), Path::new(new));
+    if is_mountpoint(&old_path) || is_mountpoint(&new_path) {
+        return Err(EBUSY);
+    }
+    if !Arc::ptr_eq(&match_mountpoint(&old_path), &match_mountpoint(&new_path)) {
+        return Err(EXDEV);
+    }
+    let (old_dir, old_name) = walk_parent(old)?;
+    let (new_dir, new_name) = walk_parent(new)?;
+    old_dir.vnode.ops.rename(
+        &old_dir.vnode,
+        old_name.as_bytes(),
+        &new_dir.vnode,
+        new_name.as_bytes(),
+    )
 }
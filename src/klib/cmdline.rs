@@ -0,0 +1,128 @@
+//! Kernel command line, as given by the bootloader
+//! Options are either flags like `nosmp`, or `name=value` pairs like `root=hda1`
+//! Subsystems declare the parameters they use as `Param` statics, and register them so that
+//! the remaining options can be handed to init
+
+use crate::klib::lock::RwLock;
+use crate::klog;
+
+/// Longer command lines are truncated
+const CMDLINE_MAX: usize = 512;
+const MAX_PARAMS: usize = 32;
+
+static mut RAW: [u8; CMDLINE_MAX] = [0; CMDLINE_MAX];
+static mut RAW_LEN: usize = 0;
+
+/// Names of the registered parameters
+static KNOWN: RwLock<([&str; MAX_PARAMS], usize)> = RwLock::new(([""; MAX_PARAMS], 0));
+
+/// Keep a copy of the command line, called before the heap is available
+pub fn save(cmdline: &[u8]) {
+    let len = cmdline.len().min(CMDLINE_MAX);
+    unsafe {
+        RAW[..len].copy_from_slice(&cmdline[..len]);
+        RAW_LEN = len;
+    }
+}
+
+/// The whole command line, without the kernel image path some bootloaders put first
+pub fn raw() -> &'static str {
+    let raw = unsafe { core::str::from_utf8(&RAW[..RAW_LEN]).unwrap_or("") }.trim_start();
+    if raw.starts_with('/') {
+        raw.split_once(char::is_whitespace).map_or("", |(_, rest)| rest)
+    } else {
+        raw
+    }
+}
+
+/// Split an option into its name and value
+fn split(opt: &'static str) -> (&'static str, Option<&'static str>) {
+    match opt.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (opt, None),
+    }
+}
+
+/// Iterate through the options as (name, value) pairs
+// TODO quoted values
+pub fn options() -> impl Iterator<Item = (&'static str, Option<&'static str>)> {
+    raw().split_whitespace().map(split)
+}
+
+/// Value of the last occurence of an option, Some(None) for a flag
+fn lookup(name: &str) -> Option<Option<&'static str>> {
+    options().filter(|(n, _)| *n == name).map(|(_, v)| v).last()
+}
+
+/// Options that no subsystem registered, as written on the command line, for init to deal with
+pub fn unknown() -> impl Iterator<Item = &'static str> {
+    raw().split_whitespace().filter(|opt| {
+        let known = KNOWN.read();
+        !known.0[..known.1].contains(&split(opt).0)
+    })
+}
+
+/// Types a parameter can be parsed to
+pub trait ParamValue: Sized + Copy {
+    /// `None` is passed when the option is given without a value
+    fn parse(value: Option<&'static str>) -> Option<Self>;
+}
+
+impl ParamValue for bool {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        match value {
+            None | Some("1") | Some("y") | Some("on") => Some(true),
+            Some("0") | Some("n") | Some("off") => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl ParamValue for usize {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        value?.parse().ok()
+    }
+}
+
+impl ParamValue for &'static str {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        value.filter(|v| !v.is_empty())
+    }
+}
+
+/// A typed boot parameter
+pub struct Param<T: ParamValue> {
+    pub name: &'static str,
+    default: T,
+}
+
+impl<T: ParamValue> Param<T> {
+    pub const fn new(name: &'static str, default: T) -> Self {
+        Param { name, default }
+    }
+
+    /// Mark the parameter as used by the kernel
+    pub fn register(&'static self) {
+        let mut known = KNOWN.write();
+        let count = known.1;
+        if known.0[..count].contains(&self.name) {
+            return;
+        }
+        if count == MAX_PARAMS {
+            panic!("Too many boot parameters registered");
+        }
+        known.0[count] = self.name;
+        known.1 += 1;
+    }
+
+    /// The value given on the command line, or the default one
+    pub fn get(&self) -> T {
+        match lookup(self.name) {
+            None => self.default,
+            Some(value) => T::parse(value).unwrap_or_else(|| {
+                klog!("Invalid value for boot parameter {}, ignored", self.name);
+                self.default
+            }),
+        }
+    }
+}
@@ -1,12 +1,18 @@
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
+use crate::arch;
 use crate::arch::lock::RawSpinLock;
+use crate::proc::preempt;
+use crate::proc::wait::WaitQueue;
 // TODO poisoning and exceptiohns everywhere
 // TODO manage writer starvation
 
 /// Multiple reader, single writer lock
 /// Spin lock for now TODO other lock types ?
+/// Unlike `SpinLock`, the holder may be switched out and may sleep: another task of the same
+/// CPU would spin until it runs again, the data of the scheduler does not use it
 pub struct RwLock<T: ?Sized> {
     /// Read lock
     read_lock: RawSpinLock,
@@ -16,9 +22,8 @@ pub struct RwLock<T: ?Sized> {
     data: UnsafeCell<T>,
 }
 
-// TODO not clear how that works
-unsafe impl<T> Sync for RwLock<T> {}
-// impl<T: ?Sized> for RwLock<T> {};
+// Readers share the data from several CPUs, writers move it from one to another
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
 
 impl<T> RwLock<T> {
     /// Initialize a new RwLock wich data
@@ -36,7 +41,7 @@ impl<T: ?Sized> RwLock<T> {
     /// Locks for read access
     /// It ensures that read access is possible before returning
     /// Otherwise it locks until the write access has been released
-    pub fn read(&self) -> Result<RwLockReadGuard<T>, ()> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
         self.read_lock.lock();
         let readers = unsafe { &mut *self.nreaders.get() };
         *readers = *readers + 1;
@@ -47,16 +52,14 @@ impl<T: ?Sized> RwLock<T> {
             self.write_lock.lock();
         }
         self.read_lock.release();
-        // check if read lock is taken
-        Ok(RwLockReadGuard { lock: self })
+        RwLockReadGuard { lock: self }
     }
 
     /// Locks for write access
     /// This function will lock until no one is reading anymore
-    pub fn write(&self) -> Result<RwLockWriteGuard<T>, ()> {
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
         self.write_lock.lock();
-        // check if write lock is taken
-        Ok(RwLockWriteGuard { lock: self })
+        RwLockWriteGuard { lock: self }
     }
 }
 
@@ -110,3 +113,262 @@ impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
         self.lock.write_lock.release();
     }
 }
+
+/// Spin lock for short critical sections, the task is not switched out while holding it
+/// Data shared with interrupt handlers must be locked with `lock_irqsave`, a handler spinning
+/// on a lock held by the code it interrupted would never return
+pub struct SpinLock<T: ?Sized> {
+    lock: RawSpinLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        SpinLock {
+            lock: RawSpinLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        preempt::disable();
+        self.lock.lock();
+        SpinLockGuard {
+            lock: self,
+            irqs: false,
+        }
+    }
+
+    /// Lock with interrupts disabled, they are restored as they were when the guard is dropped
+    pub fn lock_irqsave(&self) -> SpinLockGuard<'_, T> {
+        let irqs = arch::interrupts_enabled();
+        arch::disable_interrupts();
+        preempt::disable();
+        self.lock.lock();
+        SpinLockGuard { lock: self, irqs }
+    }
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    /// Interrupts have to be enabled again on release
+    irqs: bool,
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.lock.release();
+        if self.irqs {
+            arch::enable_interrupts();
+        }
+        preempt::enable();
+    }
+}
+
+/// Mutual exclusion lock that puts the waiting tasks to sleep instead of spinning
+/// Tasks get the lock in the order they asked for it
+pub struct Mutex<T: ?Sized> {
+    /// Ticket given to the next task asking for the lock
+    next: AtomicUsize,
+    /// Ticket of the task owning the lock
+    serving: AtomicUsize,
+    wait: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            next: AtomicUsize::new(0),
+            serving: AtomicUsize::new(0),
+            wait: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Sleeps until the lock is free
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let ticket = self.next.fetch_add(1, Ordering::Acquire);
+        if self.serving.load(Ordering::Acquire) != ticket {
+            self.wait
+                .wait_key_until(ticket, || self.serving.load(Ordering::Acquire) == ticket);
+        }
+        MutexGuard { lock: self }
+    }
+
+    /// Take the lock only if nobody has it or waits for it
+    #[allow(dead_code)]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let serving = self.serving.load(Ordering::Acquire);
+        self.next
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()
+            .map(|_| MutexGuard { lock: self })
+    }
+
+    /// Hand the lock over to the next task in line
+    fn unlock(&self) {
+        let next = self.serving.fetch_add(1, Ordering::Release).wrapping_add(1);
+        // Only the one holding the next ticket can go on, the others keep sleeping
+        self.wait.wake_key(next);
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    lock: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+
+/// Counting semaphore, `down` sleeps while the count is 0
+/// Like the mutex, it is served in order
+#[allow(dead_code)]
+pub struct Semaphore {
+    /// Ticket given to the next `down`
+    next: AtomicUsize,
+    /// Tickets below this one may go on, each `up` lets one more through
+    limit: AtomicUsize,
+    wait: WaitQueue,
+}
+
+#[allow(dead_code)]
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            next: AtomicUsize::new(0),
+            limit: AtomicUsize::new(count),
+            wait: WaitQueue::new(),
+        }
+    }
+
+    /// Whether the ticket may go on, the counters wrap around
+    fn passes(&self, ticket: usize) -> bool {
+        (self.limit.load(Ordering::Acquire).wrapping_sub(ticket) as isize) > 0
+    }
+
+    /// Take one unit, sleeping until there is one available
+    pub fn down(&self) {
+        let ticket = self.next.fetch_add(1, Ordering::Acquire);
+        if !self.passes(ticket) {
+            self.wait.wait_key_until(ticket, || self.passes(ticket));
+        }
+    }
+
+    /// Take one unit if there is one available and nobody waits for it
+    pub fn try_down(&self) -> bool {
+        let ticket = self.next.load(Ordering::Acquire);
+        self.passes(ticket)
+            && self
+                .next
+                .compare_exchange(
+                    ticket,
+                    ticket.wrapping_add(1),
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+    }
+
+    /// Give back one unit, the first task waiting gets it
+    pub fn up(&self) {
+        // The ticket that passes now
+        let ticket = self.limit.fetch_add(1, Ordering::Release);
+        self.wait.wake_key(ticket);
+    }
+}
+
+/// Condition variable, to sleep with a mutex released until another task signals a change
+pub struct Condvar {
+    /// Bumped on each notification, waiters go on once it changed
+    seq: AtomicUsize,
+    wait: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            seq: AtomicUsize::new(0),
+            wait: WaitQueue::new(),
+        }
+    }
+
+    /// Release the mutex and sleep until notified, the mutex is taken again before returning
+    /// Wake ups can be spurious, the condition has to be checked again
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let seq = self.seq.load(Ordering::Acquire);
+        let mutex = guard.lock;
+        drop(guard);
+        // A notification between the unlock and the sleep changes seq, it is not lost
+        self.wait
+            .wait_until(|| self.seq.load(Ordering::Acquire) != seq);
+        mutex.lock()
+    }
+
+    /// Sleep as long as `cond` holds on the protected data
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut cond: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while cond(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    #[allow(dead_code)]
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        self.wait.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        self.wait.wake_all();
+    }
+}
@@ -1,12 +1,74 @@
+use crate::driver::{serial, vga};
+use crate::klib::cmdline::Param;
+use crate::klib::time::{self, NSEC_PER_SEC};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 #[macro_export]
 macro_rules! kprint { // TODO maybe rename to klog
-    ($($arg:tt)*) => ($crate::driver::vga::_print(format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::klib::log::_print(format_args!($($arg)*)));
 }
 
 #[macro_export]
 macro_rules! klog { // TODO maybe rename to klogn
     () => ($crate::print!("\n"));
-    ($($arg:tt)*) => ($crate::kprint!("{}\n", format_args!($($arg)*)));
+    ($($arg:tt)*) => {
+        if $crate::klib::log::enabled() {
+            $crate::klib::log::_log(format_args!($($arg)*))
+        }
+    };
 }
 
+/// Level of the klog messages, like KERN_INFO
+const LOG_INFO: usize = 6;
+
+/// Messages below this level are printed, `loglevel=4` keeps the console quiet
+static LOGLEVEL: Param<usize> = Param::new("loglevel", 7);
+/// Where the kernel output goes, tty0 for the screen or ttyS0 for the first serial port
+static CONSOLE: Param<&str> = Param::new("console", "tty0");
+/// Prefix the messages with the seconds since boot
+static LOGTIME: Param<bool> = Param::new("logtime", false);
+
+static LEVEL: AtomicUsize = AtomicUsize::new(7);
+static SERIAL: AtomicBool = AtomicBool::new(false);
+static TIME: AtomicBool = AtomicBool::new(false);
+
+/// Apply the logging boot parameters
+pub fn init() {
+    LOGLEVEL.register();
+    CONSOLE.register();
+    LOGTIME.register();
+    LEVEL.store(LOGLEVEL.get(), Ordering::Relaxed);
+    TIME.store(LOGTIME.get(), Ordering::Relaxed);
+    match CONSOLE.get() {
+        "tty0" => SERIAL.store(false, Ordering::Relaxed),
+        "ttyS0" => SERIAL.store(true, Ordering::Relaxed),
+        other => crate::klog!("Unknown console {}, using tty0", other),
+    }
+}
+
+/// Whether klog messages are shown
+#[inline]
+pub fn enabled() -> bool {
+    LOG_INFO < LEVEL.load(Ordering::Relaxed)
+}
+
+#[doc(hidden)]
+pub fn _log(args: fmt::Arguments) {
+    if TIME.load(Ordering::Relaxed) {
+        let ns = time::monotonic_ns();
+        let (secs, us) = (ns / NSEC_PER_SEC, ns % NSEC_PER_SEC / 1000);
+        crate::kprint!("[{:5}.{:06}] {}\n", secs, us, args);
+    } else {
+        crate::kprint!("{}\n", args);
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    if SERIAL.load(Ordering::Relaxed) {
+        serial::_print(args);
+    } else {
+        vga::_print(args);
+    }
+}
@@ -1,4 +1,5 @@
+pub mod cmdline;
 pub mod mem;
 pub mod lock;
-mod log;
-mod time;
+pub mod log;
+pub mod time;
@@ -0,0 +1,86 @@
+//! Monotonic time, read from the best clock source the machine has
+//! Sources register as they are found, the kernel switches to a better one without the clock
+//! going back
+//! The wall clock is the monotonic one plus the time of boot, as given by the RTC
+
+use crate::klib::lock::SpinLock;
+use crate::klog;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// A free running counter
+pub trait ClockSource {
+    fn name(&self) -> &'static str;
+    fn read(&self) -> u64;
+    /// Increments per second
+    fn frequency(&self) -> u64;
+    /// The one with the highest rating is used
+    fn rating(&self) -> u32;
+}
+
+type Source = &'static (dyn ClockSource + Sync);
+
+struct Clock {
+    source: Source,
+    /// Counter value and time when the source was picked
+    base_count: u64,
+    base_ns: u64,
+    /// Last time returned, the counters of the CPUs may not be synchronized
+    last_ns: u64,
+}
+
+impl Clock {
+    fn now_ns(&mut self) -> u64 {
+        // Behind the base when read on a CPU whose counter is late
+        let delta = self.source.read().saturating_sub(self.base_count);
+        // Would overflow in a few seconds with a GHz counter in 64 bits
+        let ns = delta as u128 * NSEC_PER_SEC as u128 / self.source.frequency() as u128;
+        self.last_ns = self.last_ns.max(self.base_ns + ns as u64);
+        self.last_ns
+    }
+}
+
+static CLOCK: SpinLock<Option<Clock>> = SpinLock::new(None);
+
+/// Use the source from now on if it is better than the current one
+pub fn register(source: Source) {
+    let mut clock = CLOCK.lock_irqsave();
+    let base_ns = match clock.as_mut() {
+        Some(current) if current.source.rating() >= source.rating() => return,
+        Some(current) => current.now_ns(),
+        None => 0,
+    };
+    *clock = Some(Clock {
+        source,
+        base_count: source.read(),
+        base_ns,
+        last_ns: base_ns,
+    });
+    // The log reads the clock
+    drop(clock);
+    klog!("Clock source: {}, {} Hz", source.name(), source.frequency());
+}
+
+/// Nanoseconds since the first clock source was registered, at boot
+pub fn monotonic_ns() -> u64 {
+    CLOCK.lock_irqsave().as_mut().map_or(0, |c| c.now_ns())
+}
+
+/// Wall clock time of boot, in nanoseconds since the epoch
+static BOOT_REALTIME_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Set the wall clock, in nanoseconds since the epoch
+pub fn set_realtime(ns: u64) {
+    BOOT_REALTIME_NS.store(ns.saturating_sub(monotonic_ns()), Ordering::Relaxed);
+}
+
+/// Nanoseconds since the epoch, the epoch itself at boot if the time was never set
+pub fn realtime_ns() -> u64 {
+    BOOT_REALTIME_NS.load(Ordering::Relaxed) + monotonic_ns()
+}
+
+/// Seconds since the epoch, as stored in inodes
+pub fn realtime_secs() -> u32 {
+    (realtime_ns() / NSEC_PER_SEC) as u32
+}
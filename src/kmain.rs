@@ -18,6 +18,7 @@ mod fs;
 mod klib;
 mod memory;
 mod proc;
+mod syscall;
 mod utils;
 
 // include architecure specific code
@@ -62,6 +63,12 @@ use crate::fs::block;
 
 /// The main loop of the kernel
 pub fn kmain() -> ! {
+    // Wall clock, before any file gets a timestamp
+    driver::rtc::init();
+
+    // Usable as root before any disk is probed
+    let initrd = fs::mount_initrd();
+
     // PS/2 keyboard driver with ISA interrupts
     driver::kbd::init().unwrap();
 
@@ -69,7 +76,7 @@ pub fn kmain() -> ! {
     driver::pci::init();
     klog!("Enumerating PCI devices");
 
-    let mut pci_devices = driver::pci::PCI_DEVICES.write().unwrap();
+    let mut pci_devices = driver::pci::PCI_DEVICES.write();
     for dev in pci_devices.iter_mut() {
         match dev.kind {
             // ATA/IDE
@@ -78,8 +85,8 @@ pub fn kmain() -> ! {
                 if let Some(drv) = driver::pci_ide::IDEController::probe_controller(dev) {
                     // Register block devices from detected ATA disks if any
                     for bus_lock in drv.buses {
-                        let bus = bus_lock.read().unwrap();
-                        let disks = bus.disks.read().unwrap();
+                        let bus = bus_lock.lock();
+                        let disks = bus.disks.read();
                         for d in disks.iter() {
                             klog!("  IDE drive");
                             block::register_device(d.clone());
@@ -92,31 +99,28 @@ pub fn kmain() -> ! {
     }
     drop(pci_devices);
 
-    // Will panic if no block have been registered
     klog!("Initializing filesystems");
     block::init_fs_from_devices();
 
-    // TODO id system for partition, kernel boot arg, something like ata1hd0part1
-    // let part = 0x0100;
-
-    let fss = fs::vfs::get_filesystems();
-    if fss.len() > 1 {
-        panic!("Don't know how to choose vfs root!");
+    if let Err(code) = fs::mount_root(initrd) {
+        panic!("Could not mount the root filesystem, error {}", code);
     }
-    // TODO ugly
-    let _ = fs::vfs::register_mount("/", fss[0].clone());
-
-    dbg!("testing the vfs");
-    let _file = fs::vfs::vfs_open("/home/bob/hello-world");
-    // klog!("VFS setup");
+    fs::mount_tmpfs();
 
+    // Timers run before the scheduler tick, it sees the tasks they woke up
+    driver::timer::init();
     // After the first scheduler tick, the execution context will not come back to this loop
     let _ = schedule::init();
-    // schedule::new_kernel_thread(spawn_proc_0);
-    // schedule::new_kernel_thread(spawn_proc_1);
+    // They join the scheduler as soon as they are up
+    arch::smp::start_secondary_cpus();
+
+    proc::init::start();
+    // proc::kthread::spawn("proc0", spawn_proc_0);
+    // proc::kthread::spawn("proc1", spawn_proc_1);
     klog!("Starting the scheduler");
     arch::enable_interrupts();
-    loop {}
+    // Becomes the idle task at the first switch
+    schedule::idle();
 }
 
 #[panic_handler]
@@ -125,8 +129,8 @@ fn panic(_info: &PanicInfo) -> ! {
     arch::disable_interrupts();
     // TODO could it dead lock ?
     dbg!("Kernel panic: {}\n", _info.message());
-    klog!("Kernel panic: {}\n", _info.message());
+    kprint!("Kernel panic: {}\n\n", _info.message());
     dbg!("{}\n", _info); // TODO log macro
-    klog!("{}\n", _info); // TODO log macro
+    kprint!("{}\n\n", _info); // TODO log macro
     loop {}
 }
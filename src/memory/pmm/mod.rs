@@ -1,8 +1,9 @@
 mod bitmap;
 
 use crate::error::Result;
-use crate::klib::lock::RwLock;
+use crate::klib::lock::SpinLock;
 use crate::memory::{PhysicalMemory, RegionType, PAGE_SIZE};
+use alloc::vec::Vec;
 use bitmap::BitMap;
 use core::fmt;
 use crate::dbg;
@@ -30,7 +31,13 @@ pub struct FrameRange {
 }
 
 /// The PMM instance
-static PMM: RwLock<BitMap> = RwLock::new(BitMap::default_const());
+static PMM: SpinLock<BitMap> = SpinLock::new(BitMap::default_const());
+
+/// Extra references on every frame, indexed by frame number
+/// A frame with no extra reference has a single owner, which is also what frames
+/// outside of the table are considered to have
+/// Frames shared between address spaces, eg. copy on write pages, are freed with their last reference
+static REFCOUNTS: SpinLock<Vec<u16>> = SpinLock::new(Vec::new());
 
 /// Zone of the allocation
 pub enum Zone {
@@ -52,7 +59,7 @@ pub trait PageManager {
 
 /// Initialize the physical memory manager
 pub fn init(memmap: &PhysicalMemory) {
-    let mut pmm = PMM.write().unwrap();
+    let mut pmm = PMM.lock();
     pmm.setup();
     drop(pmm);
     for entry in memmap.regions {
@@ -68,28 +75,62 @@ pub fn init(memmap: &PhysicalMemory) {
 #[inline(always)]
 /// Allocate a single physical page in a physical zone
 pub fn alloc_page(zone: Zone) -> Result<Frame> {
-    let mut pmm = PMM.write().unwrap();
+    let mut pmm = PMM.lock();
     pmm.alloc_page(zone)
 }
 
 #[inline(always)]
 /// Allocate n contiguous pages in a physical zone
 pub fn alloc_contiguous_pages(n: usize, zone: Zone) -> Result<FrameRange> {
-    let mut pmm = PMM.write().unwrap();
+    let mut pmm = PMM.lock();
     pmm.alloc_contiguous_pages(n, zone)
 }
 
-#[inline(always)]
-/// Free a single page
+/// Allocate the reference count table, needs the kernel heap
+pub fn init_refcounts(memmap: &PhysicalMemory) {
+    let nframes = memmap.regions[..memmap.size]
+        .iter()
+        .filter(|r| r.rtype == RegionType::Available)
+        .map(|r| (r.start + r.size) / PAGE_SIZE)
+        .max()
+        .unwrap_or(0);
+    // Allocate before taking the lock, growing the heap allocates pages
+    let table = vec![0 as u16; nframes];
+    *REFCOUNTS.lock() = table;
+}
+
+/// Take an extra reference on an allocated page
+pub fn share_page(f: &Frame) {
+    let mut refcounts = REFCOUNTS.lock();
+    let count = refcounts.get_mut(f.0).expect("Sharing a frame without reference count");
+    *count = count.checked_add(1).expect("Frame reference count overflow");
+}
+
+/// Number of owners of an allocated page
+pub fn page_refcount(f: &Frame) -> usize {
+    let refcounts = REFCOUNTS.lock();
+    refcounts.get(f.0).map_or(1, |&count| count as usize + 1)
+}
+
+/// Drop a reference to a single page, the page is freed with the last one
 pub fn free_page(f: Frame) {
-    let mut pmm = PMM.write().unwrap();
+    {
+        let mut refcounts = REFCOUNTS.lock();
+        if let Some(count) = refcounts.get_mut(f.0) {
+            if *count > 0 {
+                *count -= 1;
+                return;
+            }
+        }
+    }
+    let mut pmm = PMM.lock();
     pmm.free_page(f);
 }
 
 #[inline(always)]
 /// Free a range of contiguous pages
 pub fn free_contiguous_pages(f: FrameRange) {
-    let mut pmm = PMM.write().unwrap();
+    let mut pmm = PMM.lock();
     pmm.free_contiguous_pages(f)
 }
 
@@ -98,12 +139,12 @@ pub fn free_contiguous_pages(f: FrameRange) {
 /// among other things, used to block out reserved page ranges
 pub fn fill_range(f: FrameRange) -> () {
     dbg!("Filling range : {}", f.size);
-    let mut pmm = PMM.write().unwrap();
+    let mut pmm = PMM.lock();
     pmm.fill_range(f)
 }
 
 #[inline(always)]
 pub fn get_phys_frames(phys_addres: usize, n: usize) -> FrameRange {
-    let mut pmm = PMM.write().unwrap();
+    let mut pmm = PMM.lock();
     pmm.get_phys_frames(phys_addres, n)
 }
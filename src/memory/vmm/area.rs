@@ -0,0 +1,184 @@
+use super::mapper::Prot;
+use crate::error::{codes::*, Result};
+use crate::fs::fd::FileRef;
+use crate::memory::PAGE_SIZE;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// What the pages of an area are filled with on first touch
+#[derive(Clone)]
+pub enum Backing {
+    /// Zero filled pages
+    Anonymous,
+    /// Private copy of a file, `offset` is the file position of the start of the area
+    File {
+        file: FileRef,
+        offset: u64,
+    },
+}
+
+/// A region of a user address space, page aligned
+/// Pages are only mapped when they are first accessed
+#[derive(Clone)]
+pub struct Area {
+    pub start: usize,
+    pub end: usize,
+    pub prot: Prot,
+    pub backing: Backing,
+}
+
+impl Area {
+    pub fn new(start: usize, end: usize, prot: Prot, backing: Backing) -> Self {
+        debug_assert!(start % PAGE_SIZE == 0 && end % PAGE_SIZE == 0 && start < end);
+        Area {
+            start,
+            end,
+            prot,
+            backing,
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, address: usize) -> bool {
+        self.start <= address && address < self.end
+    }
+
+    /// Whether the range [start, end[ intersects the area
+    #[inline]
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+
+    /// Cut the area at `address`, self keeps the lower part and the upper part is returned
+    fn split_off(&mut self, address: usize) -> Area {
+        debug_assert!(self.start < address && address < self.end);
+        let backing = match &self.backing {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { file, offset } => Backing::File {
+                file: file.clone(),
+                offset: offset + (address - self.start) as u64,
+            },
+        };
+        let upper = Area::new(address, self.end, self.prot, backing);
+        self.end = address;
+        upper
+    }
+
+    /// Fill a freshly allocated page at `page` with its initial content
+    pub fn fill_page(&self, page: usize, buf: &mut [u8]) -> Result<()> {
+        match &self.backing {
+            Backing::Anonymous => Ok(()),
+            Backing::File { file, offset } => {
+                let mut file = file.lock();
+                let mut pos = offset + (page - self.start) as u64;
+                let mut done = 0;
+                // Past the end of file, the page stays zero filled
+                while done < buf.len() {
+                    let n = file.ops.read(pos, &mut buf[done..])?;
+                    if n == 0 {
+                        break;
+                    }
+                    done += n;
+                    pos += n as u64;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The areas of an address space, they never overlap
+#[derive(Clone, Default)]
+pub struct Areas {
+    /// Indexed by start address
+    map: BTreeMap<usize, Area>,
+}
+
+impl Areas {
+    pub const fn new() -> Self {
+        Areas {
+            map: BTreeMap::new(),
+        }
+    }
+
+    /// Add an area, fails with EEXIST if it overlaps an existing one
+    pub fn insert(&mut self, area: Area) -> Result<()> {
+        if self.iter().any(|a| a.overlaps(area.start, area.end)) {
+            return Err(EEXIST);
+        }
+        self.map.insert(area.start, area);
+        Ok(())
+    }
+
+    /// The area containing the address
+    pub fn find(&self, address: usize) -> Option<&Area> {
+        self.map
+            .range(..=address)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.contains(address))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Area> {
+        self.map.values()
+    }
+
+    /// Make sure no area crosses `address`, splitting the one containing it
+    pub fn split_at(&mut self, address: usize) {
+        let upper = match self.map.range_mut(..address).next_back() {
+            Some((_, area)) if area.contains(address) => area.split_off(address),
+            _ => return,
+        };
+        self.map.insert(upper.start, upper);
+    }
+
+    /// Remove the areas in [start, end[, the ones crossing the bounds are split
+    pub fn remove_range(&mut self, start: usize, end: usize) -> Vec<Area> {
+        self.split_at(start);
+        self.split_at(end);
+        let starts: Vec<usize> = self.map.range(start..end).map(|(&s, _)| s).collect();
+        starts.iter().filter_map(|s| self.map.remove(s)).collect()
+    }
+
+    /// Mutable access to the areas covering [start, end[, split at the bounds
+    /// Fails with ENOMEM if part of the range is not covered by any area
+    pub fn range_mut(
+        &mut self,
+        start: usize,
+        end: usize,
+    ) -> Result<impl Iterator<Item = &mut Area>> {
+        self.split_at(start);
+        self.split_at(end);
+        let mut covered = start;
+        for area in self.map.range(start..end).map(|(_, a)| a) {
+            if area.start != covered {
+                return Err(ENOMEM);
+            }
+            covered = area.end;
+        }
+        if covered != end {
+            return Err(ENOMEM);
+        }
+        Ok(self.map.range_mut(start..end).map(|(_, a)| a))
+    }
+
+    /// Lowest address of a free range of `len` bytes in [from, limit[
+    pub fn find_free(&self, from: usize, limit: usize, len: usize) -> Option<usize> {
+        let mut candidate = from;
+        for area in self.iter() {
+            if area.end <= candidate {
+                continue;
+            }
+            if area.start >= candidate.checked_add(len)? {
+                break;
+            }
+            candidate = area.end;
+        }
+        let end = candidate.checked_add(len)?;
+        if end <= limit {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+}
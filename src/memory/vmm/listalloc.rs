@@ -100,7 +100,6 @@ impl ListAllocator
                     block.next = current.next.take();
                     // Goto first block
                     current.next = Some(block);
-                    current = current.next.as_mut().unwrap();
                     // TODO coalesce with next ?
                 }
                 else if ca + current.size == block.addr() {
@@ -116,13 +115,8 @@ impl ListAllocator
                     block.next = tmp;
                     current.next = Some(block);
                 }
-                // If the last free block is at the heap's end, unmap pages if we can
-                if current.next.is_none() && current.addr() + current.size == self.memstart + self.heapsize {
-                    let start = if is_aligned!(current.addr(), PAGE_SIZE) { current.addr() } else {ROUND_PAGE_UP!(current.addr())};
-                    let npages = current.size / PAGE_SIZE;
-                    let _ = mapper::unmap_range_kernel(start, npages);
-                    current.size -= npages * PAGE_SIZE;
-                }
+                // The heap never shrinks, the TLB shootdown of the other CPUs would wait with
+                // the allocator locked while they may be spinning on it
                 return;
             }
             current = current.next.as_mut().unwrap(); // TODO don't really understand this line
@@ -204,6 +198,7 @@ impl ListAllocator
     }
 
     /// Decrease current heap size by a number of pages
+    /// Only while the other CPUs are not started, see `free_block`
     fn decrease_heap(&mut self, npages: usize)
     {
         mapper::unmap_range_kernel(self.memstart + self.heapsize - npages * PAGE_SIZE, npages).expect("Failed to decrease kernel heap");
@@ -243,7 +238,7 @@ unsafe impl GlobalAlloc for RwLock<Option<ListAllocator>>
 {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8
     {
-        let mut alloc_guard = self.write().unwrap();
+        let mut alloc_guard = self.write();
         let alloc = alloc_guard.as_mut().unwrap();
         let (size, _align) = ListAllocator::adjust_layout(layout);
         dbg!("___________ ALLOC REQUEST for {}", size);
@@ -262,7 +257,7 @@ unsafe impl GlobalAlloc for RwLock<Option<ListAllocator>>
     }
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout)
     {
-        let mut alloc_guard = self.write().unwrap();
+        let mut alloc_guard = self.write();
         let alloc = alloc_guard.as_mut().unwrap();
         // TODO check aligntment and use layout
         // TODO Add a mechanism to check if the pointer is valid ?
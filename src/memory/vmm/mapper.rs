@@ -1,6 +1,20 @@
 use crate::memory::pmm::{Frame, FrameRange};
 use crate::arch::paging::kernel_mapper;
 use crate::error::Result;
+use crate::memory::PAGE_SIZE;
+use bitflags::bitflags;
+
+bitflags! {
+    /// Access rights of a mapping
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    pub struct Prot : u32 {
+        const READ = 1;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+        /// Accessible from user mode
+        const USER = 1 << 3;
+    }
+}
 
 // TODO error enum ?
 #[allow(dead_code)] // TODO
@@ -8,6 +22,10 @@ pub trait MapperInterface
 {
     /// Map a single frame
     fn map_single(&mut self, f: Frame, address: usize) -> Result<()>;
+    /// Map a single frame with the given access rights
+    fn map_single_prot(&mut self, f: Frame, address: usize, prot: Prot) -> Result<()>;
+    /// Change the access rights of a mapped page
+    fn set_prot(&mut self, address: usize, prot: Prot) -> Result<()>;
     /// Unmap a single frame
     fn unmap_single(&mut self, address: usize) -> Result<()>;
     /// Contiguous mapping of the FrameRange from the address and up
@@ -62,10 +80,15 @@ pub fn unmap_range_kernel(address: usize, n: usize) -> Result<()> {
     kernel_mapper().unmap_range(address, n)
 }
 
-/// Remap a physical IO address in virtual IO space
+/// Remap `n` bytes of physical IO memory at `address` in virtual IO space
 /// TODO the API is inconsistent with other functions, might want to pick only one
+/// TODO never unmapped
 #[inline(always)]
 pub fn io_remap(address: usize, n: usize) -> Option<usize> {
-    todo!()
-    // kernel_mapper().io_remap(f)
+    let offset = address % PAGE_SIZE;
+    let range = FrameRange {
+        start: Frame(address / PAGE_SIZE),
+        size: (offset + n.max(1)).div_ceil(PAGE_SIZE),
+    };
+    kernel_mapper().io_remap(range).map(|virt| virt + offset)
 }
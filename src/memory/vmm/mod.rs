@@ -1,6 +1,8 @@
 mod bump;
 mod listalloc;
+pub mod area;
 pub mod mapper;
+pub mod space;
 
 use crate::klib::lock::RwLock;
 use listalloc::ListAllocator;
@@ -20,7 +22,7 @@ pub fn init(memstart: usize, size: usize)
 {
     unsafe { 
         ALLOCATOR = RwLock::new(Some(ListAllocator::default()));
-        let mut guard = ALLOCATOR.write().unwrap();
+        let mut guard = ALLOCATOR.write();
         guard.as_mut().unwrap().init(memstart, size);
     };
 }
@@ -0,0 +1,225 @@
+use super::area::{Area, Areas, Backing};
+use super::mapper::{self, MapperInterface, Prot};
+use crate::arch::paging::{self, PageDir};
+use crate::dbg;
+use crate::error::{codes::*, Result};
+use crate::klib::mem::{memcpy, memset};
+use crate::memory::pmm::{self, Frame, Zone};
+use crate::memory::PAGE_SIZE;
+use core::ffi::c_void;
+
+/// Mappings without a fixed address are placed from here
+pub const MMAP_BASE: usize = 0x40000000;
+
+/// A virtual address space
+/// The kernel half is shared by every address space, the user half is private
+pub struct AddressSpace {
+    /// Page directory, accessed through the kernel linear mapping
+    pd: &'static mut PageDir,
+    /// Physical address of the page directory
+    phys: usize,
+    /// Regions where user pages can be mapped
+    pub areas: Areas,
+}
+
+impl AddressSpace {
+    /// Create an address space with an empty user half
+    pub fn new() -> Result<Self> {
+        let (pd, phys) = PageDir::new_user()?;
+        Ok(AddressSpace {
+            pd,
+            phys,
+            areas: Areas::new(),
+        })
+    }
+
+    /// Duplicate this address space, the user pages are shared copy on write
+    /// Must be called on the active address space
+    pub fn fork(&mut self) -> Result<Self> {
+        let mut child = AddressSpace::new()?;
+        // On failure, dropping the child releases what was already shared
+        self.pd.clone_cow(child.pd)?;
+        child.areas = self.areas.clone();
+        Ok(child)
+    }
+
+    /// Map the page containing `address` on first access
+    /// Fails with EFAULT if the address is outside of every area, or if the access is not
+    /// allowed by the area
+    pub fn handle_fault(&mut self, address: usize, write: bool) -> Result<()> {
+        let area = self.areas.find(address).ok_or(EFAULT)?;
+        if (write && !area.prot.contains(Prot::WRITE)) || !area.prot.contains(Prot::READ) {
+            return Err(EFAULT);
+        }
+        let page = address & !(PAGE_SIZE - 1);
+        if self.pd.virt_to_phys(page).is_some() {
+            // Already there, only a write to a shared page can be resolved
+            return match write && self.pd.resolve_cow(page)? {
+                true => Ok(()),
+                false => Err(EFAULT),
+            };
+        }
+        dbg!("Demand paging {:x}", page);
+
+        let frame = pmm::alloc_page(Zone::Normal)?;
+        let kaddr = mapper::phys_to_virt(frame.0 * PAGE_SIZE).ok_or(ENOMEM)?;
+        memset(kaddr as *mut c_void, 0, PAGE_SIZE);
+        let buf = unsafe { core::slice::from_raw_parts_mut(kaddr as *mut u8, PAGE_SIZE) };
+        let prot = area.prot | Prot::USER;
+        let filled = area.fill_page(page, buf);
+        if let Err(e) = filled.and_then(|_| self.pd.map_single_prot(Frame(frame.0), page, prot)) {
+            pmm::free_page(frame);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Copy bytes to user memory, the address space does not need to be the active one
+    /// Pages are mapped as needed, the areas must allow writing
+    pub fn copy_to(&mut self, address: usize, bytes: &[u8]) -> Result<()> {
+        let mut done = 0;
+        while done < bytes.len() {
+            let current = address + done;
+            let page = current & !(PAGE_SIZE - 1);
+            if self.pd.virt_to_phys(page).is_none() {
+                self.handle_fault(page, true)?;
+            }
+            let phys = self.pd.virt_to_phys(current).ok_or(EFAULT)?;
+            let n = core::cmp::min(PAGE_SIZE - (current - page), bytes.len() - done);
+            // Through the linear mapping
+            let dest = mapper::phys_to_virt(phys).ok_or(ENOMEM)?;
+            memcpy(dest as *mut c_void, bytes[done..].as_ptr() as *const c_void, n);
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Whether [address, address + len[ is covered by areas allowing the access
+    pub fn check_access(&self, address: usize, len: usize, write: bool) -> bool {
+        let end = match address.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        let mut current = address;
+        while current < end {
+            match self.areas.find(current) {
+                Some(area)
+                    if area.prot.contains(Prot::READ)
+                        && (!write || area.prot.contains(Prot::WRITE)) =>
+                {
+                    current = area.end
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Reserve an area of `len` bytes, pages are mapped on first access
+    /// With `fixed`, the area is placed at `address` and replaces the previous mappings,
+    /// otherwise `address` is only a hint
+    /// Returns the start of the area
+    pub fn map(
+        &mut self,
+        address: usize,
+        len: usize,
+        prot: Prot,
+        backing: Backing,
+        fixed: bool,
+    ) -> Result<usize> {
+        let len = len.checked_add(PAGE_SIZE - 1).ok_or(ENOMEM)? & !(PAGE_SIZE - 1);
+        if len == 0 || address % PAGE_SIZE != 0 {
+            return Err(EINVAL);
+        }
+        let limit = crate::arch::KERNEL_LINEAR_START;
+        let start = if fixed {
+            if address.checked_add(len).map_or(true, |end| end > limit) {
+                return Err(ENOMEM);
+            }
+            self.unmap(address, len)?;
+            address
+        } else {
+            let hint = if address == 0 { MMAP_BASE } else { address };
+            self.areas
+                .find_free(hint, limit, len)
+                .filter(|&start| start == hint)
+                .or_else(|| self.areas.find_free(MMAP_BASE, limit, len))
+                .ok_or(ENOMEM)?
+        };
+        self.areas
+            .insert(Area::new(start, start + len, prot, backing))?;
+        Ok(start)
+    }
+
+    /// Remove the mappings in [address, address + len[, unmapped pages are fine
+    pub fn unmap(&mut self, address: usize, len: usize) -> Result<()> {
+        let end = Self::range_end(address, len)?;
+        for area in self.areas.remove_range(address, end) {
+            for page in (area.start..area.end).step_by(PAGE_SIZE) {
+                if self.pd.virt_to_phys(page).is_some() {
+                    self.pd.unmap_single(page)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Change the access rights of [address, address + len[
+    /// Fails with ENOMEM if part of the range is not mapped
+    pub fn protect(&mut self, address: usize, len: usize, prot: Prot) -> Result<()> {
+        let end = Self::range_end(address, len)?;
+        for area in self.areas.range_mut(address, end)? {
+            area.prot = prot;
+            for page in (area.start..area.end).step_by(PAGE_SIZE) {
+                if self.pd.virt_to_phys(page).is_some() {
+                    self.pd.set_prot(page, prot | Prot::USER)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Page aligned end of a user range
+    fn range_end(address: usize, len: usize) -> Result<usize> {
+        let end = address
+            .checked_add(len)
+            .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+            .ok_or(EINVAL)?
+            & !(PAGE_SIZE - 1);
+        if address % PAGE_SIZE != 0 || len == 0 || end > crate::arch::KERNEL_LINEAR_START {
+            return Err(EINVAL);
+        }
+        Ok(end)
+    }
+
+    /// Mapper for this address space, it does not need to be the active one
+    #[inline]
+    pub fn mapper(&mut self) -> &mut PageDir {
+        self.pd
+    }
+
+    /// Physical address of the page directory, what the MMU is loaded with
+    #[inline]
+    pub fn root(&self) -> usize {
+        self.phys
+    }
+
+    /// Make this address space the active one
+    #[allow(dead_code)]
+    pub fn activate(&self) {
+        paging::load_page_dir(self.phys);
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Give back every user frame, the page tables and the directory
+    /// The kernel half is shared and left untouched
+    fn drop(&mut self) {
+        // Never pull the rug from under the MMU
+        if paging::current_page_dir() == self.phys {
+            paging::load_page_dir(paging::kernel_pd_phys());
+        }
+        self.pd.clear_user();
+        pmm::free_page(Frame(self.phys / PAGE_SIZE));
+    }
+}
@@ -0,0 +1,111 @@
+// ELF32 structures, see the System V ABI and its i386 supplement
+use crate::error::{codes::*, Result};
+
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+
+#[allow(dead_code)]
+pub mod consts {
+    // e_ident indexes
+    pub const EI_CLASS: usize = 4;
+    pub const EI_DATA: usize = 5;
+    pub const EI_VERSION: usize = 6;
+
+    pub const ELFCLASS32: u8 = 1;
+    pub const ELFDATA2LSB: u8 = 1;
+    pub const EV_CURRENT: u8 = 1;
+
+    // e_type
+    pub const ET_EXEC: u16 = 2;
+    pub const ET_DYN: u16 = 3;
+
+    // e_machine
+    pub const EM_386: u16 = 3;
+
+    // p_type
+    pub const PT_NULL: u32 = 0;
+    pub const PT_LOAD: u32 = 1;
+    pub const PT_DYNAMIC: u32 = 2;
+    pub const PT_INTERP: u32 = 3;
+    pub const PT_PHDR: u32 = 6;
+
+    // p_flags
+    pub const PF_X: u32 = 1 << 0;
+    pub const PF_W: u32 = 1 << 1;
+    pub const PF_R: u32 = 1 << 2;
+}
+use consts::*;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct ElfHeader {
+    pub ident: [u8; 16],
+    pub etype: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u32,
+    pub phoff: u32,
+    pub shoff: u32,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct ProgramHeader {
+    pub ptype: u32,
+    pub offset: u32,
+    pub vaddr: u32,
+    pub paddr: u32,
+    pub filesz: u32,
+    pub memsz: u32,
+    pub flags: u32,
+    pub align: u32,
+}
+
+// Compile time checks to ensure correct size of structures
+const _: [u8; 52] = [0 as u8; core::mem::size_of::<ElfHeader>()];
+const _: [u8; 32] = [0 as u8; core::mem::size_of::<ProgramHeader>()];
+
+impl ElfHeader {
+    pub fn from_bytes(buffer: &[u8; core::mem::size_of::<ElfHeader>()]) -> ElfHeader {
+        unsafe { *(buffer.as_ptr() as *const ElfHeader) }
+    }
+
+    /// Check that the file is an executable we know how to run
+    pub fn check(&self) -> Result<()> {
+        if self.ident[..4] != ELF_MAGIC
+            || self.ident[EI_CLASS] != ELFCLASS32
+            || self.ident[EI_DATA] != ELFDATA2LSB
+            || self.ident[EI_VERSION] != EV_CURRENT
+        {
+            return Err(ENOEXEC);
+        }
+        if { self.machine } != EM_386 || { self.etype } != ET_EXEC {
+            return Err(ENOEXEC);
+        }
+        if { self.phentsize } as usize != core::mem::size_of::<ProgramHeader>() {
+            return Err(ENOEXEC);
+        }
+        Ok(())
+    }
+}
+
+impl ProgramHeader {
+    pub fn from_bytes(buffer: &[u8]) -> ProgramHeader {
+        assert!(buffer.len() >= core::mem::size_of::<ProgramHeader>());
+        unsafe { *(buffer.as_ptr() as *const ProgramHeader) }
+    }
+
+    /// Whether the range [start, end[ intersects the segment in memory
+    #[inline]
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        let vaddr = self.vaddr as usize;
+        let vend = vaddr + self.memsz as usize;
+        vaddr < end && start < vend
+    }
+}
@@ -0,0 +1,309 @@
+use super::elf::{consts::*, ElfHeader, ProgramHeader};
+use crate::arch::cpu;
+use crate::arch::trap::TrapFrame;
+use super::process::Pid;
+use super::schedule;
+use crate::arch::KERNEL_LINEAR_START;
+use crate::dbg;
+use crate::error::{codes::*, Result};
+use crate::fs::vfs::{self, File, OpenFlags};
+use crate::klib::lock::RwLock;
+use crate::klib::mem::memset;
+use crate::memory::pmm::{self, Zone};
+use crate::memory::vmm::area::{Area, Backing};
+use crate::memory::vmm::mapper::{self, MapperInterface, Prot};
+use crate::memory::vmm::space::AddressSpace;
+use crate::memory::PAGE_SIZE;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+
+/// The user stack ends right below the kernel
+pub const USER_STACK_TOP: usize = KERNEL_LINEAR_START;
+
+/// Size of the stack area of a user task, pages are mapped as the stack grows
+pub const USER_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Program headers beyond this are not considered
+const MAX_PHDRS: usize = 32;
+
+/// Maximum size of the arguments and environment strings
+const ARG_MAX: usize = 128 * 1024;
+/// Maximum number of arguments plus environment variables
+pub const MAX_ARGS: usize = 4096;
+
+/// Auxiliary vector entry types, from the System V ABI
+mod auxv {
+    pub const AT_NULL: u32 = 0;
+    pub const AT_PHDR: u32 = 3;
+    pub const AT_PHENT: u32 = 4;
+    pub const AT_PHNUM: u32 = 5;
+    pub const AT_PAGESZ: u32 = 6;
+    pub const AT_ENTRY: u32 = 9;
+    pub const AT_RANDOM: u32 = 25;
+}
+
+/// A program loaded in a fresh address space, ready to be started
+pub struct Image {
+    pub space: AddressSpace,
+    pub entry: usize,
+    /// Address of the program headers in the program's memory, 0 if they are not loaded
+    pub phdr: usize,
+    pub phnum: usize,
+}
+
+/// Read exactly buf.len() bytes at offset in the file
+fn read_exact_at(file: &mut File, offset: u64, buf: &mut [u8]) -> Result<()> {
+    file.pos = offset;
+    let mut done = 0;
+    while done < buf.len() {
+        let n = file.read(&mut buf[done..])?;
+        // Truncated file
+        if n == 0 {
+            return Err(ENOEXEC);
+        }
+        done += n;
+    }
+    Ok(())
+}
+
+/// Access rights of a segment
+fn segment_prot(phdr: &ProgramHeader) -> Prot {
+    let mut prot = Prot::USER;
+    if phdr.flags & PF_R != 0 {
+        prot |= Prot::READ;
+    }
+    if phdr.flags & PF_W != 0 {
+        prot |= Prot::WRITE;
+    }
+    if phdr.flags & PF_X != 0 {
+        prot |= Prot::EXEC;
+    }
+    prot
+}
+
+/// Access rights of a page, those of all the segments it holds a part of
+fn page_prot(phdrs: &[ProgramHeader], page: usize) -> Prot {
+    phdrs
+        .iter()
+        .filter(|p| p.ptype == PT_LOAD && p.overlaps(page, page + PAGE_SIZE))
+        .fold(Prot::USER, |acc, p| acc | segment_prot(p))
+}
+
+/// Map the pages of a PT_LOAD segment, copy its file content and zero the rest
+fn load_segment(
+    file: &mut File,
+    space: &mut AddressSpace,
+    phdr: &ProgramHeader,
+    phdrs: &[ProgramHeader],
+) -> Result<()> {
+    let vaddr = phdr.vaddr as usize;
+    let memsz = phdr.memsz as usize;
+    let filesz = phdr.filesz as usize;
+    if filesz > memsz || vaddr.checked_add(memsz).ok_or(ENOEXEC)? > USER_STACK_TOP {
+        return Err(ENOEXEC);
+    }
+    dbg!("ELF segment at {:x}, {} bytes in file, {} in memory", vaddr, filesz, memsz);
+
+    let file_end = vaddr + filesz;
+    let mut page = vaddr & !(PAGE_SIZE - 1);
+    while page < vaddr + memsz {
+        // A page can be shared by the end of a segment and the start of the next one
+        let page_phys = match space.mapper().virt_to_phys(page) {
+            Some(phys) => phys,
+            None => {
+                let prot = page_prot(phdrs, page);
+                let frame = pmm::alloc_page(Zone::Normal)?;
+                let phys = frame.0 * PAGE_SIZE;
+                // The BSS and the page padding are zero filled
+                memset(mapper::phys_to_virt(phys).ok_or(ENOMEM)? as *mut c_void, 0, PAGE_SIZE);
+                space.mapper().map_single_prot(frame, page, prot)?;
+                phys
+            }
+        };
+
+        // The part of the page backed by the file, written through the linear mapping
+        // because the address space is not active
+        let start = core::cmp::max(page, vaddr);
+        let end = core::cmp::min(page + PAGE_SIZE, file_end);
+        if start < end {
+            let kaddr = mapper::phys_to_virt(page_phys).ok_or(ENOMEM)? + (start - page);
+            let dest = unsafe { core::slice::from_raw_parts_mut(kaddr as *mut u8, end - start) };
+            read_exact_at(file, phdr.offset as u64 + (start - vaddr) as u64, dest)?;
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Load an ELF32 executable from the VFS into a new address space
+pub fn load(path: &str) -> Result<Image> {
+    let mut file = vfs::vfs_open(path, OpenFlags::RDONLY)?;
+
+    let mut buffer = [0 as u8; core::mem::size_of::<ElfHeader>()];
+    read_exact_at(&mut file, 0, &mut buffer)?;
+    let header = ElfHeader::from_bytes(&buffer);
+    header.check()?;
+
+    let phnum = header.phnum as usize;
+    if phnum > MAX_PHDRS {
+        return Err(ENOEXEC);
+    }
+    let mut raw = vec![0 as u8; phnum * core::mem::size_of::<ProgramHeader>()];
+    read_exact_at(&mut file, header.phoff as u64, &mut raw)?;
+    let phdrs: Vec<ProgramHeader> = raw
+        .chunks(core::mem::size_of::<ProgramHeader>())
+        .map(ProgramHeader::from_bytes)
+        .collect();
+
+    let mut space = AddressSpace::new()?;
+    for phdr in phdrs.iter().filter(|p| p.ptype == PT_LOAD) {
+        load_segment(&mut file, &mut space, phdr, &phdrs)?;
+    }
+
+    // Already populated, the areas only record the rights, one per run of pages with the same
+    // A page shared by two segments gets the rights of both, as its mapping
+    let mut run: Option<(usize, usize, Prot)> = None;
+    for phdr in phdrs.iter().filter(|p| p.ptype == PT_LOAD) {
+        let start = phdr.vaddr as usize & !(PAGE_SIZE - 1);
+        let end = (phdr.vaddr as usize + phdr.memsz as usize + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        for page in (start..end).step_by(PAGE_SIZE) {
+            let prot = page_prot(&phdrs, page) - Prot::USER;
+            run = match run {
+                // Segments are sorted by address, this is the shared page, already in the run
+                Some((_, run_end, _)) if page < run_end => run,
+                Some((run_start, run_end, run_prot)) if page == run_end && prot == run_prot => {
+                    Some((run_start, page + PAGE_SIZE, prot))
+                }
+                Some((run_start, run_end, run_prot)) => {
+                    let area = Area::new(run_start, run_end, run_prot, Backing::Anonymous);
+                    space.areas.insert(area)?;
+                    Some((page, page + PAGE_SIZE, prot))
+                }
+                None => Some((page, page + PAGE_SIZE, prot)),
+            };
+        }
+    }
+    if let Some((run_start, run_end, run_prot)) = run {
+        let area = Area::new(run_start, run_end, run_prot, Backing::Anonymous);
+        space.areas.insert(area)?;
+    }
+
+    space.areas.insert(Area::new(
+        USER_STACK_TOP - USER_STACK_SIZE,
+        USER_STACK_TOP,
+        Prot::READ | Prot::WRITE,
+        Backing::Anonymous,
+    ))?;
+
+    // Where the program headers ended up, for AT_PHDR
+    let phoff = header.phoff as usize;
+    let phdr = match phdrs.iter().find(|p| p.ptype == PT_PHDR) {
+        Some(p) => p.vaddr as usize,
+        None => phdrs
+            .iter()
+            .find(|p| {
+                p.ptype == PT_LOAD
+                    && (p.offset as usize) <= phoff
+                    && phoff < (p.offset + p.filesz) as usize
+            })
+            .map_or(0, |p| p.vaddr as usize + phoff - p.offset as usize),
+    };
+
+    Ok(Image {
+        space,
+        entry: header.entry as usize,
+        phdr,
+        phnum,
+    })
+}
+
+/// Lay out the initial stack of the program, following the i386 System V ABI
+/// From the top: the strings, AT_RANDOM bytes, then 16 bytes aligned argc, argv pointers,
+/// NULL, envp pointers, NULL and the auxiliary vector
+/// Returns the stack pointer to start the program with
+fn setup_stack(image: &mut Image, argv: &[&[u8]], envp: &[&[u8]]) -> Result<usize> {
+    let strings: usize = argv.iter().chain(envp.iter()).map(|s| s.len() + 1).sum();
+    if strings > ARG_MAX || argv.len() + envp.len() > MAX_ARGS {
+        return Err(E2BIG);
+    }
+    let space = &mut image.space;
+    let mut sp = USER_STACK_TOP;
+
+    // Strings, the kernel builds the pointers as it goes
+    let mut pointers: Vec<u32> = Vec::with_capacity(argv.len() + envp.len() + 2);
+    for (i, s) in argv.iter().chain(envp.iter()).enumerate() {
+        if i == argv.len() {
+            pointers.push(0);
+        }
+        sp -= s.len() + 1;
+        space.copy_to(sp, s)?;
+        space.copy_to(sp + s.len(), &[0])?;
+        pointers.push(sp as u32);
+    }
+    if envp.is_empty() {
+        pointers.push(0);
+    }
+    pointers.push(0);
+
+    // Seed for the program's own randomness, eg. stack protector canaries
+    let mut seed = cpu::rdtsc();
+    let mut random = [0 as u8; 16];
+    for chunk in random.chunks_mut(8) {
+        // xorshift64
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        chunk.copy_from_slice(&seed.to_le_bytes());
+    }
+    sp -= random.len();
+    let random_address = sp;
+    space.copy_to(sp, &random)?;
+
+    let aux = [
+        (auxv::AT_PHDR, image.phdr as u32),
+        (auxv::AT_PHENT, core::mem::size_of::<ProgramHeader>() as u32),
+        (auxv::AT_PHNUM, image.phnum as u32),
+        (auxv::AT_PAGESZ, PAGE_SIZE as u32),
+        (auxv::AT_ENTRY, image.entry as u32),
+        (auxv::AT_RANDOM, random_address as u32),
+        (auxv::AT_NULL, 0),
+    ];
+
+    let mut words: Vec<u32> = Vec::with_capacity(1 + pointers.len() + 2 * aux.len());
+    words.push(argv.len() as u32);
+    words.extend_from_slice(&pointers);
+    for (key, value) in aux {
+        words.push(key);
+        words.push(value);
+    }
+    sp = (sp - words.len() * core::mem::size_of::<u32>()) & !0xf;
+    let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    space.copy_to(sp, &bytes)?;
+    Ok(sp)
+}
+
+/// Load the executable at `path` and start it in a new process, returns its pid
+pub fn spawn(path: &str, argv: &[&[u8]], envp: &[&[u8]]) -> Result<Pid> {
+    let mut image = load(path)?;
+    let sp = setup_stack(&mut image, argv, envp)?;
+    dbg!("Starting {} at {:x}", path, image.entry);
+    schedule::new_user_task(image.entry, sp, Arc::new(RwLock::new(image.space)))
+}
+
+/// Replace the image of the current process by the executable at `path`
+/// `frame` is the trap frame of the calling task, it returns to the new program
+/// On failure the current image is left untouched
+pub fn exec(path: &str, argv: &[&[u8]], envp: &[&[u8]], frame: &mut TrapFrame) -> Result<()> {
+    // The arguments may live in the current image, which stays in place until the end
+    let mut image = load(path)?;
+    let sp = setup_stack(&mut image, argv, envp)?;
+    dbg!("Executing {} at {:x}", path, image.entry);
+
+    schedule::replace_current_space(Arc::new(RwLock::new(image.space)));
+    if let Some(files) = schedule::current_files() {
+        files.write().close_on_exec();
+    }
+    frame.reset_user(image.entry as u32, sp as u32);
+    Ok(())
+}
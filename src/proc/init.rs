@@ -0,0 +1,54 @@
+//! Start of the first user process
+
+use super::exec;
+use super::process::Pid;
+use super::kthread;
+use super::shell;
+use crate::klib::cmdline::{self, Param};
+use crate::klog;
+use alloc::vec::Vec;
+
+/// Program started as PID 1
+static INIT: Param<&str> = Param::new("init", "");
+
+/// Tried in order when the requested init cannot be started, like Linux does
+const DEFAULT_INIT: [&str; 4] = ["/sbin/init", "/etc/init", "/bin/init", "/bin/sh"];
+
+fn try_init(path: &str) -> Option<Pid> {
+    // Options the kernel does not know about are for init, flags as arguments and the
+    // others as environment variables
+    let mut argv: Vec<&[u8]> = vec![path.as_bytes()];
+    let mut envp: Vec<&[u8]> = vec![b"HOME=/", b"TERM=linux"];
+    for opt in cmdline::unknown() {
+        if opt.contains('=') {
+            envp.push(opt.as_bytes());
+        } else {
+            argv.push(opt.as_bytes());
+        }
+    }
+    match exec::spawn(path, &argv, &envp) {
+        Ok(pid) => {
+            klog!("Started {} as pid {}", path, pid);
+            Some(pid)
+        }
+        Err(code) => {
+            klog!("Could not start {}, error {}", path, code);
+            None
+        }
+    }
+}
+
+/// Spawn PID 1 from init=, or from the default locations
+/// The emergency shell is started if none of them works
+pub fn start() {
+    INIT.register();
+    let requested = Some(INIT.get()).filter(|p| !p.is_empty());
+    let candidates = requested.into_iter().chain(DEFAULT_INIT.iter().copied());
+    for path in candidates {
+        if try_init(path).is_some() {
+            return;
+        }
+    }
+    klog!("No working init found, starting the emergency shell");
+    kthread::spawn("shell", shell::run);
+}
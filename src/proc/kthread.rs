@@ -0,0 +1,56 @@
+//! Kernel threads, for deferred driver work and background jobs
+
+use super::schedule::{self, TaskId};
+use crate::klib::lock::{Condvar, Mutex};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+/// Where a thread leaves its return value for `join`
+struct Packet<T> {
+    result: Mutex<Option<T>>,
+    done: Condvar,
+}
+
+/// Owned handle on a kernel thread, dropping it detaches the thread
+#[allow(dead_code)]
+pub struct JoinHandle<T> {
+    id: TaskId,
+    packet: Arc<Packet<T>>,
+}
+
+#[allow(dead_code)]
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Sleep until the thread returns, and get what it returned
+    pub fn join(self) -> T {
+        let result = self.packet.result.lock();
+        let mut result = self.packet.done.wait_while(result, |r| r.is_none());
+        result.take().unwrap()
+    }
+}
+
+/// Start a kernel thread named `name` running `f`
+/// It may run on any CPU, so what it owns and returns must be `Send`
+pub fn spawn<T, F>(name: &str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet {
+        result: Mutex::new(None),
+        done: Condvar::new(),
+    });
+    let theirs = packet.clone();
+    let id = schedule::new_kernel_thread(
+        name,
+        Box::new(move || {
+            let ret = f();
+            *theirs.result.lock() = Some(ret);
+            theirs.done.notify_all();
+        }),
+    );
+    JoinHandle { id, packet }
+}
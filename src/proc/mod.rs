@@ -1 +1,10 @@
+pub mod elf;
+pub mod exec;
+pub mod init;
+pub mod kthread;
+pub mod policy;
+pub mod preempt;
+pub mod process;
 pub mod schedule;
+mod shell;
+pub mod wait;
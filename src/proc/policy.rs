@@ -0,0 +1,198 @@
+//! Scheduling policies, each one picks among the runnable tasks of its classes
+//! Real time tasks always run before the fair ones, and the idle task only when nothing else can
+
+use super::schedule::{Task, TaskState};
+use crate::arch::smp;
+
+/// Ticks a task runs before the others of its class get a turn
+pub const TIME_SLICE: u32 = 5;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Class {
+    /// Shares the CPU with the other fair tasks, by nice value from -20 to 19
+    Fair { nice: i8 },
+    /// Runs until it blocks or a higher priority task is runnable, priority from 1 to 99
+    Fifo { priority: u8 },
+    /// Same as Fifo, but takes turns with the tasks of the same priority
+    RoundRobin { priority: u8 },
+    /// Only runs when nothing else can
+    Idle,
+}
+
+impl Default for Class {
+    fn default() -> Self {
+        Class::Fair { nice: 0 }
+    }
+}
+
+impl Class {
+    /// Tasks of a higher rank preempt the lower ones
+    fn rank(&self) -> (u8, u8) {
+        match *self {
+            Class::Idle => (0, 0),
+            Class::Fair { .. } => (1, 0),
+            Class::Fifo { priority } | Class::RoundRobin { priority } => (2, priority),
+        }
+    }
+}
+
+/// Scheduling state of a task
+#[derive(Default, Clone)]
+pub struct SchedEntity {
+    pub class: Class,
+    /// Ticks run, weighted by the nice value, the fair task with the lowest one runs first
+    pub vruntime: u64,
+    /// Ticks left before the task is switched out
+    pub slice: u32,
+    /// Ticks run since the task started
+    pub runtime: u64,
+}
+
+pub trait Policy {
+    /// Whether tasks of this class are managed by this policy
+    fn owns(&self, class: Class) -> bool;
+
+    /// Index of the task to run among the runnable ones of this policy
+    fn pick_next(&self, tasks: &[Task], current: usize) -> Option<usize>;
+
+    /// Account for a tick of the running task, returns true if it used up its time slice
+    fn tick(&self, entity: &mut SchedEntity) -> bool;
+
+    /// The task at `index` comes back to the run queue, after being created or woken up
+    fn enqueue(&self, _tasks: &mut [Task], _index: usize) {}
+}
+
+/// Indices of the tasks this CPU can run, starting after the current one which comes last
+/// Taking the first of the equally good ones makes them take turns
+/// Called with the task list locked, so interrupts are disabled and the CPU cannot change
+fn runnable(tasks: &[Task], current: usize) -> impl Iterator<Item = usize> + '_ {
+    let cpu = smp::cpu_id();
+    (1..=tasks.len())
+        .map(move |i| (current + i) % tasks.len())
+        .filter(move |&i| tasks[i].state == TaskState::Runnable && tasks[i].available_on(cpu))
+}
+
+/// Use up one tick of the slice
+fn consume_slice(entity: &mut SchedEntity) -> bool {
+    entity.slice = entity.slice.saturating_sub(1);
+    entity.slice == 0
+}
+
+/// Weighted virtual runtime, the CPU is shared in proportion to the weights of the nice values
+struct Fair;
+
+/// Weight of nice 0, each nice level is about 10% more or less CPU time
+const NICE_0_WEIGHT: u64 = 1024;
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+/// How far behind the others a task coming back from sleep can be, so that it runs soon
+/// without taking the CPU for as long as it slept
+const SLEEPER_CREDIT: u64 = TIME_SLICE as u64 * NICE_0_WEIGHT / 2;
+
+fn weight(nice: i8) -> u64 {
+    NICE_TO_WEIGHT[(nice.clamp(-20, 19) + 20) as usize]
+}
+
+impl Policy for Fair {
+    fn owns(&self, class: Class) -> bool {
+        matches!(class, Class::Fair { .. })
+    }
+
+    fn pick_next(&self, tasks: &[Task], current: usize) -> Option<usize> {
+        runnable(tasks, current)
+            .filter(|&i| self.owns(tasks[i].sched.class))
+            .min_by_key(|&i| tasks[i].sched.vruntime)
+    }
+
+    fn tick(&self, entity: &mut SchedEntity) -> bool {
+        if let Class::Fair { nice } = entity.class {
+            entity.vruntime += NICE_0_WEIGHT * NICE_0_WEIGHT / weight(nice);
+        }
+        consume_slice(entity)
+    }
+
+    fn enqueue(&self, tasks: &mut [Task], index: usize) {
+        let min = tasks
+            .iter()
+            .enumerate()
+            .filter(|(i, t)| {
+                *i != index && t.state == TaskState::Runnable && self.owns(t.sched.class)
+            })
+            .map(|(_, t)| t.sched.vruntime)
+            .min();
+        if let Some(min) = min {
+            let entity = &mut tasks[index].sched;
+            entity.vruntime = entity.vruntime.max(min.saturating_sub(SLEEPER_CREDIT));
+        }
+    }
+}
+
+/// Fixed priorities, the highest runnable one always runs
+struct RealTime;
+
+impl Policy for RealTime {
+    fn owns(&self, class: Class) -> bool {
+        matches!(class, Class::Fifo { .. } | Class::RoundRobin { .. })
+    }
+
+    fn pick_next(&self, tasks: &[Task], current: usize) -> Option<usize> {
+        runnable(tasks, current)
+            .filter(|&i| self.owns(tasks[i].sched.class))
+            // max_by_key would take the last of the equal ones
+            .fold(None, |best: Option<usize>, i| match best {
+                Some(b) if tasks[b].sched.class.rank() >= tasks[i].sched.class.rank() => best,
+                _ => Some(i),
+            })
+    }
+
+    fn tick(&self, entity: &mut SchedEntity) -> bool {
+        match entity.class {
+            Class::RoundRobin { .. } => consume_slice(entity),
+            _ => false,
+        }
+    }
+}
+
+/// By precedence
+static POLICIES: [&(dyn Policy + Sync); 2] = [&RealTime, &Fair];
+
+fn policy_of(class: Class) -> Option<&'static (dyn Policy + Sync)> {
+    POLICIES.iter().copied().find(|p| p.owns(class))
+}
+
+/// Index of the task to run next, the idle task of this CPU if nothing else is runnable
+pub fn pick_next(tasks: &[Task], current: usize) -> usize {
+    let cpu = smp::cpu_id();
+    POLICIES
+        .iter()
+        .find_map(|p| p.pick_next(tasks, current))
+        .or_else(|| {
+            tasks
+                .iter()
+                .position(|t| t.sched.class == Class::Idle && t.pinned == Some(cpu))
+        })
+        .unwrap_or(current)
+}
+
+/// Account for a timer tick of the current task, returns true if it should be switched out
+pub fn tick(tasks: &mut [Task], current: usize) -> bool {
+    let class = tasks[current].sched.class;
+    tasks[current].sched.runtime += 1;
+    let expired = match policy_of(class) {
+        Some(policy) => policy.tick(&mut tasks[current].sched),
+        None => false,
+    };
+    // Something more important may have woken up
+    let next = pick_next(tasks, current);
+    expired || tasks[next].sched.class.rank() > class.rank()
+}
+
+/// A task was created or woken up
+pub fn enqueue(tasks: &mut [Task], index: usize) {
+    if let Some(policy) = policy_of(tasks[index].sched.class) {
+        policy.enqueue(tasks, index);
+    }
+}
@@ -0,0 +1,47 @@
+//! Preemption control, the timer does not switch tasks while it is disabled
+//! Spin locks disable it while they are held, otherwise the other tasks could spin on them for
+//! a whole time slice
+
+use crate::arch;
+use crate::arch::smp::{self, MAX_CPUS};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+const FALSE: AtomicBool = AtomicBool::new(false);
+/// Nesting depth of `disable`, by CPU
+static COUNT: [AtomicUsize; MAX_CPUS] = [ZERO; MAX_CPUS];
+/// Set when the timer wanted to switch tasks while preemption was disabled, by CPU
+static NEED_RESCHED: [AtomicBool; MAX_CPUS] = [FALSE; MAX_CPUS];
+
+// The task must not move to another CPU between reading the CPU number and using it, which
+// it cannot do anymore once the count is raised
+
+#[inline]
+pub fn disable() {
+    arch::without_interrupts(|| COUNT[smp::cpu_id()].fetch_add(1, Ordering::Acquire));
+}
+
+/// Once preemption is enabled again, the switch the timer missed happens right away
+pub fn enable() {
+    let (prev, need) = arch::without_interrupts(|| {
+        let cpu = smp::cpu_id();
+        let prev = COUNT[cpu].fetch_sub(1, Ordering::Release);
+        (prev, NEED_RESCHED[cpu].load(Ordering::Relaxed))
+    });
+    debug_assert!(prev > 0, "Unbalanced preempt::enable");
+    // Not from an interrupt handler or with an irqsave lock held, the tick will do it
+    if prev == 1 && need && arch::interrupts_enabled() {
+        let _ = crate::proc::schedule::schedule();
+    }
+}
+
+/// Whether the current task may be switched out
+#[inline]
+pub fn enabled() -> bool {
+    arch::without_interrupts(|| COUNT[smp::cpu_id()].load(Ordering::Relaxed) == 0)
+}
+
+/// Called by the scheduler, with interrupts disabled
+pub fn set_need_resched(need: bool) {
+    NEED_RESCHED[smp::cpu_id()].store(need, Ordering::Relaxed);
+}
@@ -0,0 +1,184 @@
+use super::schedule;
+use super::wait::WaitQueue;
+use crate::error::{codes::*, Result};
+use crate::klib::lock::SpinLock;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub type Pid = usize;
+pub type Uid = u32;
+
+/// Orphans are adopted by this process
+pub const INIT_PID: Pid = 1;
+/// Parent of the processes started by the kernel itself
+pub const KERNEL_PID: Pid = 0;
+/// The superuser, owner of the processes started by the kernel
+pub const ROOT_UID: Uid = 0;
+
+// Signal numbers, used for the exit status of killed processes
+pub const SIGILL: i32 = 4;
+pub const SIGTRAP: i32 = 5;
+pub const SIGFPE: i32 = 8;
+pub const SIGSEGV: i32 = 11;
+
+/// Exit status as returned by wait, for a process that called exit
+#[inline]
+pub fn exited(code: i32) -> i32 {
+    (code & 0xff) << 8
+}
+
+/// Exit status as returned by wait, for a process killed by a signal
+#[inline]
+pub fn signaled(signal: i32) -> i32 {
+    signal & 0x7f
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ProcessState {
+    Running,
+    /// Exited, waiting for its parent to collect the status
+    Zombie(i32),
+}
+
+pub struct Process {
+    pub parent: Pid,
+    pub children: Vec<Pid>,
+    pub state: ProcessState,
+    /// Inherited from the parent
+    pub uid: Uid,
+}
+
+static PROCESSES: SpinLock<BTreeMap<Pid, Process>> = SpinLock::new(BTreeMap::new());
+static NEXT_PID: AtomicUsize = AtomicUsize::new(INIT_PID);
+/// Woken up each time a process exits, for the parents waiting for it
+pub static EXITED: WaitQueue = WaitQueue::new();
+
+/// Register a new running process, child of `parent`
+pub fn create(parent: Pid) -> Pid {
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    let mut processes = PROCESSES.lock();
+    let uid = match processes.get_mut(&parent) {
+        Some(p) => {
+            p.children.push(pid);
+            p.uid
+        }
+        None => ROOT_UID,
+    };
+    processes.insert(
+        pid,
+        Process {
+            parent,
+            children: Vec::new(),
+            state: ProcessState::Running,
+            uid,
+        },
+    );
+    pid
+}
+
+/// Owner of a process
+pub fn uid_of(pid: Pid) -> Option<Uid> {
+    PROCESSES.lock().get(&pid).map(|p| p.uid)
+}
+
+/// Change the owner of a process, returns false if it does not exist
+pub fn set_uid(pid: Pid, uid: Uid) -> bool {
+    match PROCESSES.lock().get_mut(&pid) {
+        Some(p) => {
+            p.uid = uid;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Parent of a process
+pub fn parent_of(pid: Pid) -> Option<Pid> {
+    PROCESSES.lock().get(&pid).map(|p| p.parent)
+}
+
+/// Turn the process into a zombie, its children are adopted by init
+/// Processes without a parent to wait for them are forgotten right away
+fn exit(pid: Pid, status: i32) {
+    let mut processes = PROCESSES.lock();
+    let (parent, children) = match processes.get_mut(&pid) {
+        Some(p) => {
+            p.state = ProcessState::Zombie(status);
+            (p.parent, core::mem::take(&mut p.children))
+        }
+        None => return,
+    };
+
+    let adopter = if processes.contains_key(&INIT_PID) {
+        INIT_PID
+    } else {
+        KERNEL_PID
+    };
+    for child in children {
+        let zombie = match processes.get_mut(&child) {
+            Some(c) => {
+                c.parent = adopter;
+                matches!(c.state, ProcessState::Zombie(_))
+            }
+            None => continue,
+        };
+        match processes.get_mut(&adopter) {
+            Some(init) => init.children.push(child),
+            // Nobody will ever wait for it
+            None if zombie => {
+                processes.remove(&child);
+            }
+            None => {}
+        }
+    }
+
+    if !processes.contains_key(&parent) {
+        processes.remove(&pid);
+    }
+}
+
+/// Terminate the current task with the wait status `status`
+/// Open files and memory are released right away, the kernel stack once another task runs
+pub fn exit_current(status: i32) -> ! {
+    if let Some(pid) = schedule::current_pid() {
+        if pid == INIT_PID {
+            panic!("init exited with status {:x}", status);
+        }
+        let resources = schedule::take_current_resources();
+        drop(resources);
+        exit(pid, status);
+        EXITED.wake_all();
+    }
+    schedule::exit_current()
+}
+
+/// Collect the status of an exited child of `parent`
+/// `pid` is either a child, or -1 for any of them
+/// Returns None if the matching children are still running
+pub fn try_wait(parent: Pid, pid: isize) -> Result<Option<(Pid, i32)>> {
+    let mut processes = PROCESSES.lock();
+    let children = match processes.get(&parent) {
+        Some(p) => p.children.clone(),
+        None => return Err(ECHILD),
+    };
+    // TODO process groups, 0 and < -1 are treated as any child
+    let candidates: Vec<Pid> = children
+        .into_iter()
+        .filter(|&c| pid <= 0 || c == pid as Pid)
+        .collect();
+    if candidates.is_empty() {
+        return Err(ECHILD);
+    }
+
+    for child in candidates {
+        if let Some(ProcessState::Zombie(status)) = processes.get(&child).map(|c| c.state) {
+            processes.remove(&child);
+            if let Some(p) = processes.get_mut(&parent) {
+                p.children.retain(|&c| c != child);
+            }
+            return Ok(Some((child, status)));
+        }
+    }
+    Ok(None)
+}
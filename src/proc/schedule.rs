@@ -1,27 +1,101 @@
 use crate::arch;
 use crate::arch::context;
 use crate::arch::context::Context;
+use crate::arch::paging;
+use crate::arch::smp::{self, MAX_CPUS};
+use crate::arch::trap::TrapFrame;
+use crate::driver::timer;
+use crate::error::{self, codes::*};
+use crate::fs::fd::FdTable;
 use crate::irq::request_irq_top;
-use crate::klib::lock::{RwLock, RwLockWriteGuard};
+use crate::klib::lock::{RwLock, SpinLock, SpinLockGuard};
+use crate::memory::vmm::space::AddressSpace;
+use crate::proc::policy::{self, Class, SchedEntity};
+use crate::proc::preempt;
+use crate::proc::process::{self, Pid, KERNEL_PID};
 
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub enum TaskState {
+    #[default]
+    Runnable,
+    /// Waiting on a wait queue, not scheduled until woken up
+    Sleeping,
+    /// Suspended until continued, wake ups do not make it runnable
+    Stopped,
+    /// Will never run again, freed by the scheduler once another task runs
+    Dead,
+}
+
+/// Identifies a task for its whole life, unlike its index in the task list
+pub type TaskId = usize;
+
+static NEXT_TASK_ID: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Default)]
 pub struct Task {
+    pub id: TaskId,
+    /// For debugging, kernel threads are named after what they do
+    pub name: String,
+    pub state: TaskState,
+    pub sched: SchedEntity,
+    /// Set when the task is woken up, so that a wake up coming before it actually sleeps is
+    /// not lost
+    wakeup: bool,
     pub context: Context,
+    /// User address space, kernel threads only use the kernel half
+    pub space: Option<Arc<RwLock<AddressSpace>>>,
+    /// Open files, kernel threads do not have any
+    pub files: Option<Arc<RwLock<FdTable>>>,
+    /// Process the task runs, None for kernel threads
+    pub pid: Option<Pid>,
+    /// What a kernel thread runs, taken when it starts
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// CPU the task is running on, it cannot be picked by another one meanwhile
+    pub cpu: Option<usize>,
+    /// Only runs on this CPU, for the idle tasks
+    pub pinned: Option<usize>,
 }
 
 impl Task {
     fn new() -> Self {
         Task {
+            id: NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed),
+            name: String::new(),
+            state: TaskState::Runnable,
+            sched: SchedEntity::default(),
+            wakeup: false,
             context: Context::default(),
+            space: None,
+            files: None,
+            pid: None,
+            entry: None,
+            cpu: None,
+            pinned: None,
         }
     }
+
+    /// Whether the CPU `cpu` may run the task, if it is runnable
+    pub fn available_on(&self, cpu: usize) -> bool {
+        self.cpu.map_or(true, |c| c == cpu) && self.pinned.map_or(true, |c| c == cpu)
+    }
 }
 
-static TASKS: RwLock<Vec<Task>> = RwLock::new(Vec::new());
-static mut GUARD: Option<RwLockWriteGuard<'static, Vec<Task>>> = None;
-static mut CURRENT: usize = 0;
+/// A spin lock, a real time task must not be switched in to spin on it on the same CPU
+static TASKS: SpinLock<Vec<Task>> = SpinLock::new(Vec::new());
+const NO_GUARD: Option<SpinLockGuard<'static, Vec<Task>>> = None;
+/// By CPU, held across the switch until `unlock_scheduler`
+static mut GUARD: [Option<SpinLockGuard<'static, Vec<Task>>>; MAX_CPUS] = [NO_GUARD; MAX_CPUS];
+/// Index of the task running on each CPU
+static mut CURRENT: [usize; MAX_CPUS] = [0; MAX_CPUS];
+/// Bit set for each CPU halted in the idle loop
+static IDLE_CPUS: AtomicUsize = AtomicUsize::new(0);
 // static mut TASKS: Vec<Task>:
 
 #[no_mangle]
@@ -30,27 +104,297 @@ pub static mut CONTEXT_CHANGE: u32 = 0;
 pub fn schedule() -> Result<(), ()> {
     // klog!("Shedule tick start");
     arch::disable_interrupts();
+    // A spin lock is held, the switch happens once it is released
+    if !preempt::enabled() {
+        preempt::set_need_resched(true);
+        arch::enable_interrupts();
+        return Ok(());
+    }
+    preempt::set_need_resched(false);
+    let cpu = smp::cpu_id();
     unsafe {
-        GUARD = Some(TASKS.write().unwrap());
-        let tasks = GUARD.as_mut().unwrap();
-        let prev = CURRENT;
-        CURRENT += 1;
-        if prev == CURRENT {
+        GUARD[cpu] = Some(TASKS.lock());
+        let tasks = GUARD[cpu].as_mut().unwrap();
+        reap(tasks);
+        let prev = CURRENT[cpu];
+        if tasks.is_empty() {
+            unlock_scheduler();
             return Ok(());
         }
-        if CURRENT == tasks.len() {
-            CURRENT = 0;
+        let next = policy::pick_next(tasks, prev);
+        CURRENT[cpu] = next;
+        let sched = &mut tasks[next].sched;
+        if sched.slice == 0 {
+            sched.slice = policy::TIME_SLICE;
+        }
+        if prev == next {
+            unlock_scheduler();
+            return Ok(());
         }
-        let c2 = tasks[prev].context.clone();
+        // Other CPUs may pick the previous task once the guard is released
+        tasks[prev].cpu = None;
+        tasks[next].cpu = Some(cpu);
+        // The guard is held until the switch is done, so the list cannot change under us
+        // A dead task never comes back from switch, nothing must be left owned on its stack
+        let next = &tasks[next].context as *const Context;
         let c1 = &mut tasks[prev].context;
-        context::switch(c1, c2);
+        context::switch(c1, &*next);
     }
     Ok(())
 }
 
+/// Timer handler, switches tasks once the current one used up its time slice or a more
+/// important one is runnable
+fn tick() -> Result<(), ()> {
+    let resched = with_tasks(|tasks, current| !tasks.is_empty() && policy::tick(tasks, current));
+    if resched {
+        schedule()
+    } else {
+        Ok(())
+    }
+}
+
+/// Add a new task to the list
+fn push_task(tasks: &mut Vec<Task>, task: Task) {
+    tasks.push(task);
+    let index = tasks.len() - 1;
+    policy::enqueue(tasks, index);
+    kick_idle_cpus();
+}
+
+/// Get the halted CPUs to look for a task that became runnable
+fn kick_idle_cpus() {
+    let idle = IDLE_CPUS.load(Ordering::Acquire) & !(1 << smp::cpu_id());
+    for cpu in (0..MAX_CPUS).filter(|cpu| idle & (1 << cpu) != 0) {
+        smp::wake_cpu(cpu);
+    }
+}
+
+/// Free the dead tasks, except the ones still on their kernel stack on some CPU
+unsafe fn reap(tasks: &mut Vec<Task>) {
+    let mut i = 0;
+    while i < tasks.len() {
+        if tasks[i].state == TaskState::Dead && tasks[i].cpu.is_none() {
+            tasks.remove(i);
+            for current in CURRENT.iter_mut().filter(|c| i < **c) {
+                *current -= 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// Run `f` on the task list and the index of the current task
+/// Interrupts are disabled meanwhile, the timer would otherwise try to schedule while the
+/// list is locked
+fn with_tasks<R>(f: impl FnOnce(&mut Vec<Task>, usize) -> R) -> R {
+    arch::without_interrupts(|| {
+        let mut tasks = TASKS.lock();
+        f(&mut tasks, unsafe { CURRENT[smp::cpu_id()] })
+    })
+}
+
+/// Id of the current task, after clearing its pending wake up
+/// Called before the task registers on a wait queue, see `sleep`
+pub fn prepare_sleep() -> TaskId {
+    with_tasks(|tasks, current| {
+        let task = &mut tasks[current];
+        task.wakeup = false;
+        task.id
+    })
+}
+
+/// Put the current task to sleep until it is woken up
+/// Returns right away if it already was since `prepare_sleep`
+pub fn sleep() {
+    if !preempt::enabled() {
+        panic!("Sleeping with a spin lock held");
+    }
+    let woken = with_tasks(|tasks, current| {
+        let task = &mut tasks[current];
+        if !task.wakeup {
+            task.state = TaskState::Sleeping;
+        }
+        task.wakeup
+    });
+    if !woken {
+        let _ = schedule();
+    }
+}
+
+/// Make a sleeping task runnable again, safe to call from interrupt handlers
+/// Returns false if the task does not exist anymore
+pub fn wake(id: TaskId) -> bool {
+    with_tasks(|tasks, _| match tasks.iter().position(|t| t.id == id) {
+        Some(i) => {
+            tasks[i].wakeup = true;
+            if tasks[i].state == TaskState::Sleeping {
+                tasks[i].state = TaskState::Runnable;
+                policy::enqueue(tasks, i);
+                kick_idle_cpus();
+            }
+            true
+        }
+        None => false,
+    })
+}
+
+fn change_class(tasks: &mut [Task], index: usize, class: Class) {
+    tasks[index].sched.class = class;
+    tasks[index].sched.slice = 0;
+    policy::enqueue(tasks, index);
+}
+
+/// Change the scheduling class of a task, returns false if it does not exist
+pub fn set_class(id: TaskId, class: Class) -> bool {
+    with_tasks(|tasks, _| match tasks.iter().position(|t| t.id == id) {
+        Some(i) => {
+            change_class(tasks, i, class);
+            true
+        }
+        None => false,
+    })
+}
+
+/// Index of the task running the process `pid`, the current one for 0
+fn task_of(tasks: &[Task], current: usize, pid: Pid) -> Option<usize> {
+    match pid {
+        0 => Some(current),
+        pid => tasks.iter().position(|t| t.pid == Some(pid)),
+    }
+}
+
+/// Scheduling class of the process `pid`, 0 for the current task
+pub fn class_of(pid: Pid) -> Option<Class> {
+    with_tasks(|tasks, current| task_of(tasks, current, pid).map(|i| tasks[i].sched.class))
+}
+
+/// Change the scheduling class of the process `pid`, 0 for the current task
+/// Returns false if there is no such process
+pub fn set_process_class(pid: Pid, class: Class) -> bool {
+    let found = with_tasks(|tasks, current| match task_of(tasks, current, pid) {
+        Some(i) => {
+            change_class(tasks, i, class);
+            true
+        }
+        None => false,
+    });
+    // The caller may not be the most important task anymore
+    if found {
+        let _ = schedule();
+    }
+    found
+}
+
+/// Stop or continue the tasks running a process
+// TODO used by SIGSTOP and SIGCONT once there are signals
+#[allow(dead_code)]
+pub fn set_stopped(pid: Pid, stopped: bool) {
+    with_tasks(|tasks, _| {
+        for task in tasks.iter_mut().filter(|t| t.pid == Some(pid)) {
+            task.state = match (task.state, stopped) {
+                (TaskState::Runnable | TaskState::Sleeping, true) => TaskState::Stopped,
+                // It goes through its wait loop again, which sleeps if nothing happened
+                (TaskState::Stopped, false) => TaskState::Runnable,
+                (state, _) => state,
+            };
+        }
+    })
+}
+
+/// Address space of the current task, None for kernel threads
+pub fn current_space() -> Option<Arc<RwLock<AddressSpace>>> {
+    with_tasks(|tasks, current| tasks[current].space.clone())
+}
+
+/// File descriptor table of the current task, None for kernel threads
+pub fn current_files() -> Option<Arc<RwLock<FdTable>>> {
+    with_tasks(|tasks, current| tasks[current].files.clone())
+}
+
+/// Switch the current task to a new address space, the previous one is released
+pub fn replace_current_space(space: Arc<RwLock<AddressSpace>>) {
+    let root = space.read().root();
+    let old = with_tasks(|tasks, current| {
+        let task = &mut tasks[current];
+        task.context.cr3 = root as u32;
+        paging::load_page_dir(root);
+        task.space.replace(space)
+    });
+    drop(old);
+}
+
+/// Registers of the current user task, saved when it entered the kernel
+pub fn current_user_frame() -> &'static mut TrapFrame {
+    let frame = with_tasks(|tasks, current| tasks[current].context.user_frame() as *mut TrapFrame);
+    // The frame lives on the task's own kernel stack
+    unsafe { &mut *frame }
+}
+
+/// Id of the current task
+pub fn current_id() -> TaskId {
+    with_tasks(|tasks, current| tasks[current].id)
+}
+
+/// Process of the current task, None for kernel threads
+pub fn current_pid() -> Option<Pid> {
+    with_tasks(|tasks, current| tasks[current].pid)
+}
+
+/// Detach the address space and the open files of the current task, for it to release them
+/// The kernel half stays mapped, so the task can go on until it is switched out
+pub fn take_current_resources() -> (Option<Arc<RwLock<AddressSpace>>>, Option<Arc<RwLock<FdTable>>>) {
+    with_tasks(|tasks, current| {
+        let task = &mut tasks[current];
+        task.context.cr3 = paging::kernel_pd_phys() as u32;
+        (task.space.take(), task.files.take())
+    })
+}
+
+/// Terminate the current task, its resources are released once another task runs
+pub fn exit_current() -> ! {
+    with_tasks(|tasks, current| tasks[current].state = TaskState::Dead);
+    let _ = schedule();
+    unreachable!("Dead task scheduled");
+}
+
+/// Duplicate the current user task, its address space is shared copy on write
+/// The child resumes from the same syscall with 0 as return value
+/// Returns the pid of the child
+pub fn fork() -> error::Result<Pid> {
+    // Copied without the tasks locked, it takes longer the more memory the parent uses
+    let parent_pid = current_pid().ok_or(EINVAL)?;
+    let child_space = current_space().ok_or(EINVAL)?.write().fork()?;
+    let files = match current_files() {
+        Some(files) => files.read().clone(),
+        None => FdTable::new(),
+    };
+    let pid = process::create(parent_pid);
+
+    with_tasks(|tasks, current| {
+        let parent = &tasks[current];
+        let mut frame = *parent.context.user_frame();
+        frame.eax = 0;
+
+        let mut task = Task::new();
+        task.name = parent.name.clone();
+        task.sched.class = parent.sched.class;
+        task.sched.vruntime = parent.sched.vruntime;
+        task.context.cr3 = child_space.root() as u32;
+        task.space = Some(Arc::new(RwLock::new(child_space)));
+        task.files = Some(Arc::new(RwLock::new(files)));
+        task.context.init_stack();
+        task.context.init_fork_frame(&frame);
+        task.pid = Some(pid);
+        push_task(tasks, task);
+    });
+    Ok(pid)
+}
+
 pub extern "C" fn unlock_scheduler() {
     unsafe {
-        let g = GUARD.take();
+        let g = GUARD[smp::cpu_id()].take();
         if g.is_none() {
             panic!("This should not happen");
         }
@@ -66,9 +410,23 @@ extern "C" fn new_task_wrapper() {
     }
 }
 
-pub fn new_kernel_thread(entry_point: fn()) {
+/// First code run by a kernel thread, the task exits once its entry returns
+extern "C" fn kernel_thread_start() -> ! {
+    let entry = with_tasks(|tasks, current| tasks[current].entry.take());
+    // Run in its own scope, whatever it owns must be dropped before exiting
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit_current()
+}
+
+/// Start a kernel thread running `entry`, see `kthread::spawn` to get its result back
+pub fn new_kernel_thread(name: &str, entry: Box<dyn FnOnce() + Send>) -> TaskId {
     // Create a new stack for that thread
     let mut task = Task::new();
+    task.name = String::from(name);
+    task.entry = Some(entry);
+    let id = task.id;
     // Push the handler's address onto the new stack
     let cont = &mut task.context;
     cont.init_stack();
@@ -94,21 +452,91 @@ pub fn new_kernel_thread(entry_point: fn()) {
 
     cont.push(eflags); // EFLAGS
     cont.push(0x8); // CS
-    cont.push(entry_point as u32); // EIP
-                                   // klog!("ENTRYPOINT {:x}", entry_point as u32);
+    cont.push(kernel_thread_start as *const () as u32); // EIP
 
     cont.push(new_task_wrapper as u32); // Return address from context_switch
 
-    let mut tasks = TASKS.write().unwrap();
-    tasks.push(task);
+    with_tasks(|tasks, _| push_task(tasks, task));
+    id
+}
+
+/// Start a new process in ring 3 at `entry_point` with the stack pointer `stack`, in the
+/// address space `space`
+/// Returns the pid of the process, the first one started is init
+pub fn new_user_task(
+    entry_point: usize,
+    stack: usize,
+    space: Arc<RwLock<AddressSpace>>,
+) -> error::Result<Pid> {
+    let mut task = Task::new();
+    task.context.cr3 = space.read().root() as u32;
+    task.space = Some(space);
+    task.files = Some(Arc::new(RwLock::new(FdTable::with_console())));
+
+    let cont = &mut task.context;
+    // Kernel stack, used by syscalls and interrupts
+    cont.init_stack();
+    cont.init_user_frame(entry_point as u32, stack as u32);
+
+    let pid = process::create(KERNEL_PID);
+    task.pid = Some(pid);
+    with_tasks(|tasks, _| push_task(tasks, task));
+    Ok(pid)
+}
+
+/// Whether a task other than the idle one can run on this CPU
+fn others_runnable(cpu: usize) -> bool {
+    with_tasks(|tasks, _| {
+        tasks.iter().any(|t| {
+            t.state == TaskState::Runnable && t.sched.class != Class::Idle && t.available_on(cpu)
+        })
+    })
+}
+
+/// Halt until an interrupt makes a task runnable, the timer only interrupts for the next
+/// kernel timer meanwhile
+pub fn idle() -> ! {
+    // The idle task never leaves its CPU
+    let cpu = smp::cpu_id();
+    loop {
+        // A task woken up after the check would wait for the next interrupt
+        arch::disable_interrupts();
+        // Before the check, a task woken up afterwards on another CPU sends us an interrupt
+        IDLE_CPUS.fetch_or(1 << cpu, Ordering::AcqRel);
+        if others_runnable(cpu) {
+            arch::enable_interrupts();
+        } else {
+            timer::stop_tick();
+            arch::enable_interrupts_and_halt();
+            timer::restart_tick();
+        }
+        IDLE_CPUS.fetch_and(!(1 << cpu), Ordering::AcqRel);
+        let _ = schedule();
+    }
+}
+
+/// The idle task of `cpu`, the context running this becomes it at the first switch
+fn push_idle(tasks: &mut Vec<Task>, cpu: usize) {
+    let mut task = Task::new();
+    task.name = format!("idle/{}", cpu);
+    task.sched.class = Class::Idle;
+    task.cpu = Some(cpu);
+    task.pinned = Some(cpu);
+    push_task(tasks, task);
+    unsafe {
+        CURRENT[cpu] = tasks.len() - 1;
+    }
 }
 
-fn idle_task() {
-    loop {}
+/// Run the scheduler on an application processor, once its interrupts are set up
+pub fn start_cpu(cpu: usize) -> ! {
+    with_tasks(|tasks, _| push_idle(tasks, cpu));
+    arch::enable_interrupts();
+    idle()
 }
 
 pub fn init() -> Result<(), ()> {
-    request_irq_top(50, schedule)?;
-    new_kernel_thread(idle_task);
+    request_irq_top(crate::irq::TIMER, tick)?;
+    with_tasks(|tasks, _| push_idle(tasks, 0));
     Ok(())
 }
@@ -0,0 +1,100 @@
+//! Minimal in-kernel shell, for when there is no init to run
+
+use super::exec;
+use super::process;
+use crate::arch::io::{self, port};
+use crate::driver::timer;
+use crate::fs::console;
+use crate::fs::vfs::{self, OpenFlags};
+use crate::{kprint, klog};
+use alloc::vec::Vec;
+
+const HELP: &str = "Commands:
+  help              this message
+  ls <dir>          list a directory
+  cat <file>        print a file
+  run <path> [args] run a program and wait for it
+  sleep <ms>        wait for some milliseconds
+  reboot            reset the machine";
+
+/// Kernel thread entry, never returns
+pub fn run() {
+    let mut input = console::open();
+    let mut line = Vec::new();
+    loop {
+        kprint!("# ");
+        line.clear();
+        // The console returns whole lines
+        let mut buf = [0u8; 128];
+        loop {
+            match input.read(&mut buf) {
+                Ok(n) => line.extend_from_slice(&buf[..n]),
+                Err(_) => break,
+            }
+            if line.last() == Some(&b'\n') {
+                break;
+            }
+        }
+        let line = match core::str::from_utf8(&line) {
+            Ok(l) => l,
+            Err(_) => {
+                klog!("Invalid input");
+                continue;
+            }
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["help"] => klog!("{}", HELP),
+            ["ls", dir] => ls(dir),
+            ["ls"] => ls("/"),
+            ["cat", file] => cat(file),
+            ["run", path, ..] => run_program(path, &words[1..]),
+            ["sleep", ms] => match ms.parse() {
+                Ok(ms) => timer::sleep_ms(ms),
+                Err(_) => klog!("sleep: invalid duration {}", ms),
+            },
+            ["reboot"] => io::outb(port::PS2CONTROL, 0xfe),
+            [cmd, ..] => klog!("{}: unknown command, try help", cmd),
+        }
+    }
+}
+
+fn ls(path: &str) {
+    let mut dir = match vfs::vfs_open(path, OpenFlags::DIRECTORY) {
+        Ok(d) => d,
+        Err(code) => return klog!("ls: {}: error {}", path, code),
+    };
+    loop {
+        match dir.ops.readdir() {
+            Ok(Some(entry)) => klog!("{}", core::str::from_utf8(entry.name()).unwrap_or("?")),
+            Ok(None) => break,
+            Err(code) => return klog!("ls: {}: error {}", path, code),
+        }
+    }
+}
+
+fn cat(path: &str) {
+    let mut file = match vfs::vfs_open(path, OpenFlags::RDONLY) {
+        Ok(f) => f,
+        Err(code) => return klog!("cat: {}: error {}", path, code),
+    };
+    let mut buf = [0u8; 512];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => kprint!("{}", alloc::string::String::from_utf8_lossy(&buf[..n])),
+            Err(code) => return klog!("cat: {}: error {}", path, code),
+        }
+    }
+}
+
+fn run_program(path: &str, args: &[&str]) {
+    let argv: Vec<&[u8]> = args.iter().map(|a| a.as_bytes()).collect();
+    let pid = match exec::spawn(path, &argv, &[]) {
+        Ok(pid) => pid,
+        Err(code) => return klog!("run: {}: error {}", path, code),
+    };
+    // Started by the kernel, nobody collects its status so it disappears once it exits
+    process::EXITED.wait_until(|| process::parent_of(pid).is_none());
+}
@@ -0,0 +1,117 @@
+use super::schedule::{self, TaskId};
+use crate::arch;
+use crate::driver::timer;
+use crate::klib::lock::SpinLock;
+use alloc::collections::VecDeque;
+
+/// Tasks sleeping until something happens, like data coming in or a child exiting
+/// Whoever makes it happen wakes them up, interrupt handlers included
+pub struct WaitQueue {
+    // Handlers take it too
+    /// With the key they wait for, if any, see `wait_key_until`
+    waiters: SpinLock<VecDeque<(TaskId, Option<usize>)>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: SpinLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Sleep until `f` returns something, it is called again after each wake up
+    /// The task is on the queue while `f` runs, so a wake up in between is not lost
+    pub fn wait_for<R>(&self, f: impl FnMut() -> Option<R>) -> R {
+        self.wait_keyed(None, f)
+    }
+
+    fn wait_keyed<R>(&self, key: Option<usize>, mut f: impl FnMut() -> Option<R>) -> R {
+        // At boot or in an interrupt handler, nothing could ever wake us up
+        if !arch::interrupts_enabled() {
+            loop {
+                if let Some(ret) = f() {
+                    return ret;
+                }
+            }
+        }
+        loop {
+            let id = schedule::prepare_sleep();
+            self.waiters.lock_irqsave().push_back((id, key));
+            if let Some(ret) = f() {
+                self.remove(id);
+                return ret;
+            }
+            schedule::sleep();
+            // Still queued if something else woke us up
+            self.remove(id);
+        }
+    }
+
+    /// Same as `wait_for`, but gives up once jiffies reach `deadline`, returns None then
+    /// The deadline only passes with interrupts enabled
+    pub fn wait_for_deadline<R>(
+        &self,
+        deadline: u64,
+        mut f: impl FnMut() -> Option<R>,
+    ) -> Option<R> {
+        let id = schedule::current_id();
+        // Wakes us up so that we notice the deadline passed
+        let timer = timer::add_timer(deadline, None, move || {
+            schedule::wake(id);
+        });
+        let ret = self.wait_for(|| match f() {
+            Some(ret) => Some(Some(ret)),
+            None if timer::get_jiffies() >= deadline => Some(None),
+            None => None,
+        });
+        timer::cancel_timer(timer);
+        ret
+    }
+
+    /// Sleep until `cond` holds
+    pub fn wait_until(&self, mut cond: impl FnMut() -> bool) {
+        self.wait_for(|| cond().then_some(()))
+    }
+
+    /// Same as `wait_until`, but only `wake_key` with the same key, or `wake_all`, wakes the
+    /// task up, eg. for the holder of a ticket
+    pub fn wait_key_until(&self, key: usize, mut cond: impl FnMut() -> bool) {
+        self.wait_keyed(Some(key), || cond().then_some(()))
+    }
+
+    fn remove(&self, id: TaskId) {
+        self.waiters.lock_irqsave().retain(|&(t, _)| t != id);
+    }
+
+    /// Wake up the task waiting for the longest time, returns false if there was none
+    #[allow(dead_code)]
+    pub fn wake_one(&self) -> bool {
+        let mut waiters = self.waiters.lock_irqsave();
+        // Tasks that died meanwhile are skipped
+        while let Some((id, _)) = waiters.pop_front() {
+            if schedule::wake(id) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Wake up every waiting task
+    pub fn wake_all(&self) {
+        let mut waiters = self.waiters.lock_irqsave();
+        for (id, _) in waiters.drain(..) {
+            schedule::wake(id);
+        }
+    }
+
+    /// Wake up the tasks waiting for `key` only
+    pub fn wake_key(&self, key: usize) {
+        let mut waiters = self.waiters.lock_irqsave();
+        waiters.retain(|&(id, k)| {
+            if k == Some(key) {
+                schedule::wake(id);
+            }
+            k != Some(key)
+        });
+    }
+}
@@ -0,0 +1,209 @@
+use super::{check_user_access, copy_from_user, copy_to_user, put_user_i32, user_str, Args};
+use crate::error::{codes::*, Result};
+use crate::fs::fd::{FdTable, FileRef};
+use crate::fs::pipe;
+use crate::fs::vfs::{self, OpenFlags, Whence, PATH_MAX};
+use crate::klib::lock::{Mutex, RwLock};
+use crate::memory::PAGE_SIZE;
+use crate::proc::schedule;
+use alloc::sync::Arc;
+use alloc::vec;
+
+/// Most read or written at once through the kernel buffer
+const IO_CHUNK: usize = 16 * PAGE_SIZE;
+
+/// Descriptor table of the calling task
+fn files() -> Result<Arc<RwLock<FdTable>>> {
+    schedule::current_files().ok_or(EBADF)
+}
+
+/// The file open on `fd` in the calling task
+/// The table is not kept locked, so that other descriptors can be used meanwhile
+fn file(fd: usize) -> Result<FileRef> {
+    files()?.read().get(fd)
+}
+
+/// open(path, flags, mode)
+pub fn sys_open(args: &Args) -> Result<usize> {
+    let [path, flags, mode, ..] = args.0;
+    let path = user_str(path, PATH_MAX)?;
+    let flags = OpenFlags::from_bits_retain(flags as u32);
+    // TODO umask
+    let file = if flags.contains(OpenFlags::CREAT) {
+        vfs::vfs_create(&path, flags, mode as u16)?
+    } else {
+        vfs::vfs_open(&path, flags)?
+    };
+    let cloexec = flags.contains(OpenFlags::CLOEXEC);
+    let fd = files()?.write().insert(Arc::new(Mutex::new(file)), cloexec)?;
+    Ok(fd)
+}
+
+/// close(fd)
+pub fn sys_close(args: &Args) -> Result<usize> {
+    files()?.write().remove(args.0[0])?;
+    Ok(0)
+}
+
+/// read(fd, buf, count)
+/// The file is not kept locked while copying, filling a page of the buffer may need it
+pub fn sys_read(args: &Args) -> Result<usize> {
+    let [fd, buf, count, ..] = args.0;
+    let file = file(fd)?;
+    if !file.lock().flags.readable() {
+        return Err(EBADF);
+    }
+    if count == 0 {
+        return Ok(0);
+    }
+    // What was read would be lost
+    check_user_access(buf, count, true)?;
+    let mut data = vec![0; count.min(IO_CHUNK)];
+    let mut done = 0;
+    while done < count {
+        let len = (count - done).min(IO_CHUNK);
+        let n = match file.lock().read(&mut data[..len]) {
+            Ok(n) => n,
+            Err(_) if done > 0 => break,
+            Err(e) => return Err(e),
+        };
+        copy_to_user(buf + done, &data[..n])?;
+        done += n;
+        if n < len {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+/// write(fd, buf, count)
+/// Same as read, the file is only locked to write what was copied
+pub fn sys_write(args: &Args) -> Result<usize> {
+    let [fd, buf, count, ..] = args.0;
+    let file = file(fd)?;
+    if !file.lock().flags.writable() {
+        return Err(EBADF);
+    }
+    if count == 0 {
+        return Ok(0);
+    }
+    let mut data = vec![0; count.min(IO_CHUNK)];
+    let mut done = 0;
+    while done < count {
+        let len = (count - done).min(IO_CHUNK);
+        match copy_from_user(&mut data[..len], buf + done) {
+            Ok(()) => {}
+            Err(_) if done > 0 => break,
+            Err(e) => return Err(e),
+        }
+        let n = match file.lock().write(&data[..len]) {
+            Ok(n) => n,
+            Err(_) if done > 0 => break,
+            Err(e) => return Err(e),
+        };
+        done += n;
+        if n < len {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+/// lseek(fd, offset, whence), returns the new position
+pub fn sys_lseek(args: &Args) -> Result<usize> {
+    let [fd, offset, whence, ..] = args.0;
+    let whence = Whence::try_from(whence)?;
+    let file = file(fd)?;
+    let mut file = file.lock();
+    // off_t is 32 bits wide on i386
+    let pos = file.lseek(offset as i32 as i64, whence)?;
+    usize::try_from(pos).map_err(|_| EOVERFLOW)
+}
+
+/// ioctl(fd, cmd, arg)
+pub fn sys_ioctl(args: &Args) -> Result<usize> {
+    let [fd, cmd, arg, ..] = args.0;
+    let file = file(fd)?;
+    let mut file = file.lock();
+    file.ops.ioctl(cmd, arg)
+}
+
+/// dup(oldfd), the new descriptor shares the open file and its position
+pub fn sys_dup(args: &Args) -> Result<usize> {
+    let files = files()?;
+    let mut files = files.write();
+    let file = files.get(args.0[0])?;
+    files.insert(file, false)
+}
+
+/// dup2(oldfd, newfd), newfd is closed first if needed
+pub fn sys_dup2(args: &Args) -> Result<usize> {
+    let [oldfd, newfd, ..] = args.0;
+    let files = files()?;
+    let mut files = files.write();
+    let file = files.get(oldfd)?;
+    if oldfd != newfd {
+        files.insert_at(newfd, file, false)?;
+    }
+    Ok(newfd)
+}
+
+/// pipe2(int fds[2], flags), fds receives the read end then the write end
+pub fn sys_pipe2(args: &Args) -> Result<usize> {
+    let [fds, flags, ..] = args.0;
+    let flags = OpenFlags::from_bits_retain(flags as u32);
+    if !(OpenFlags::NONBLOCK | OpenFlags::CLOEXEC).contains(flags) {
+        return Err(EINVAL);
+    }
+    let (read, write) = pipe::new_pair(flags);
+
+    let files = files()?;
+    let mut files = files.write();
+    let cloexec = flags.contains(OpenFlags::CLOEXEC);
+    let rfd = files.insert(Arc::new(Mutex::new(read)), cloexec)?;
+    let wfd = match files.insert(Arc::new(Mutex::new(write)), cloexec) {
+        Ok(fd) => fd,
+        Err(e) => {
+            let _ = files.remove(rfd);
+            return Err(e);
+        }
+    };
+    let copied = put_user_i32(fds, rfd as i32).and_then(|_| put_user_i32(fds + 4, wfd as i32));
+    if let Err(e) = copied {
+        let _ = files.remove(rfd);
+        let _ = files.remove(wfd);
+        return Err(e);
+    }
+    Ok(0)
+}
+
+/// pipe(int fds[2])
+pub fn sys_pipe(args: &Args) -> Result<usize> {
+    sys_pipe2(&Args([args.0[0], 0, 0, 0, 0, 0]))
+}
+
+/// unlink(path)
+pub fn sys_unlink(args: &Args) -> Result<usize> {
+    vfs::vfs_unlink(&user_str(args.0[0], PATH_MAX)?)?;
+    Ok(0)
+}
+
+/// mkdir(path, mode)
+pub fn sys_mkdir(args: &Args) -> Result<usize> {
+    let [path, mode, ..] = args.0;
+    vfs::vfs_mkdir(&user_str(path, PATH_MAX)?, mode as u16)?;
+    Ok(0)
+}
+
+/// rmdir(path)
+pub fn sys_rmdir(args: &Args) -> Result<usize> {
+    vfs::vfs_rmdir(&user_str(args.0[0], PATH_MAX)?)?;
+    Ok(0)
+}
+
+/// rename(oldpath, newpath)
+pub fn sys_rename(args: &Args) -> Result<usize> {
+    let [old, new, ..] = args.0;
+    vfs::vfs_rename(&user_str(old, PATH_MAX)?, &user_str(new, PATH_MAX)?)?;
+    Ok(0)
+}
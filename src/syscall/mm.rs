@@ -0,0 +1,119 @@
+use super::{copy_from_user, Args};
+use crate::error::{codes::*, Result};
+use crate::fs::fd::FileRef;
+use crate::memory::vmm::area::Backing;
+use crate::memory::vmm::mapper::Prot;
+use crate::memory::PAGE_SIZE;
+use crate::proc::schedule;
+
+// prot
+const PROT_READ: usize = 1;
+const PROT_WRITE: usize = 2;
+const PROT_EXEC: usize = 4;
+
+// flags
+const MAP_SHARED: usize = 0x01;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+
+/// Access rights from the PROT_* bits, x86 cannot have a writable or executable page that is
+/// not readable
+fn prot_from_user(prot: usize) -> Result<Prot> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(EINVAL);
+    }
+    let mut p = Prot::empty();
+    if prot & PROT_WRITE != 0 {
+        p |= Prot::WRITE | Prot::READ;
+    }
+    if prot & PROT_EXEC != 0 {
+        p |= Prot::EXEC | Prot::READ;
+    }
+    if prot & PROT_READ != 0 {
+        p |= Prot::READ;
+    }
+    Ok(p)
+}
+
+/// File open on a descriptor of the current task
+fn file_of(fd: usize) -> Result<FileRef> {
+    let files = schedule::current_files().ok_or(EBADF)?;
+    let file = files.read().get(fd)?;
+    if !file.lock().flags.readable() {
+        return Err(EACCES);
+    }
+    Ok(file)
+}
+
+fn do_mmap(
+    address: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: u64,
+) -> Result<usize> {
+    let prot = prot_from_user(prot)?;
+    // Shared mappings would need a page cache
+    if flags & (MAP_SHARED | MAP_PRIVATE) != MAP_PRIVATE {
+        return Err(EINVAL);
+    }
+    let backing = if flags & MAP_ANONYMOUS != 0 {
+        Backing::Anonymous
+    } else {
+        if offset % PAGE_SIZE as u64 != 0 {
+            return Err(EINVAL);
+        }
+        Backing::File {
+            file: file_of(fd)?,
+            offset,
+        }
+    };
+    let space = schedule::current_space().ok_or(EINVAL)?;
+    let mut space = space.write();
+    space.map(address, len, prot, backing, flags & MAP_FIXED != 0)
+}
+
+/// mmap(struct mmap_arg_struct *), the old i386 interface with the arguments in memory
+pub fn sys_mmap(args: &Args) -> Result<usize> {
+    let mut bytes = [0; 6 * core::mem::size_of::<u32>()];
+    copy_from_user(&mut bytes, args.0[0])?;
+    let mut a = [0 as usize; 6];
+    for (i, chunk) in bytes.chunks(4).enumerate() {
+        a[i] = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as usize;
+    }
+    do_mmap(a[0], a[1], a[2], a[3], a[4], a[5] as u64)
+}
+
+/// mmap2(addr, length, prot, flags, fd, pgoffset), the offset is in pages
+pub fn sys_mmap2(args: &Args) -> Result<usize> {
+    let [address, len, prot, flags, fd, pgoffset] = args.0;
+    do_mmap(
+        address,
+        len,
+        prot,
+        flags,
+        fd,
+        pgoffset as u64 * PAGE_SIZE as u64,
+    )
+}
+
+/// munmap(addr, length)
+pub fn sys_munmap(args: &Args) -> Result<usize> {
+    let [address, len, ..] = args.0;
+    let space = schedule::current_space().ok_or(EINVAL)?;
+    let mut space = space.write();
+    space.unmap(address, len)?;
+    Ok(0)
+}
+
+/// mprotect(addr, length, prot)
+pub fn sys_mprotect(args: &Args) -> Result<usize> {
+    let [address, len, prot, ..] = args.0;
+    let prot = prot_from_user(prot)?;
+    let space = schedule::current_space().ok_or(EINVAL)?;
+    let mut space = space.write();
+    space.protect(address, len, prot)?;
+    Ok(0)
+}
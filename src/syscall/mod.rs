@@ -0,0 +1,212 @@
+//! System call interface
+//! Programs call the kernel with `int 0x80`, using the i386 Linux convention:
+//! eax holds the syscall number, ebx, ecx, edx, esi, edi and ebp hold the arguments.
+//! The result is returned in eax, errors as the negated `error::codes` value
+
+mod fs;
+mod mm;
+mod proc;
+mod time;
+
+use crate::arch::paging;
+use crate::arch::KERNEL_LINEAR_START;
+use crate::memory::PAGE_SIZE;
+use crate::proc::schedule;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::error::{codes::*, Result};
+
+/// Syscall numbers, matching the i386 Linux ones so that ported programs can be built
+/// against an existing libc
+#[allow(dead_code)]
+pub mod nr {
+    pub const EXIT: usize = 1;
+    pub const FORK: usize = 2;
+    pub const READ: usize = 3;
+    pub const WRITE: usize = 4;
+    pub const OPEN: usize = 5;
+    pub const CLOSE: usize = 6;
+    pub const WAITPID: usize = 7;
+    pub const UNLINK: usize = 10;
+    pub const EXECVE: usize = 11;
+    pub const LSEEK: usize = 19;
+    pub const GETPID: usize = 20;
+    pub const SETUID: usize = 23;
+    pub const GETUID: usize = 24;
+    pub const NICE: usize = 34;
+    pub const RENAME: usize = 38;
+    pub const MKDIR: usize = 39;
+    pub const RMDIR: usize = 40;
+    pub const DUP: usize = 41;
+    pub const PIPE: usize = 42;
+    pub const IOCTL: usize = 54;
+    pub const DUP2: usize = 63;
+    pub const GETPPID: usize = 64;
+    pub const GETTIMEOFDAY: usize = 78;
+    pub const MMAP: usize = 90;
+    pub const MUNMAP: usize = 91;
+    pub const WAIT4: usize = 114;
+    pub const MPROTECT: usize = 125;
+    pub const SCHED_SETSCHEDULER: usize = 156;
+    pub const SCHED_YIELD: usize = 158;
+    pub const NANOSLEEP: usize = 162;
+    pub const MMAP2: usize = 192;
+    pub const EXIT_GROUP: usize = 252;
+    pub const CLOCK_GETTIME: usize = 265;
+    pub const PIPE2: usize = 331;
+}
+
+/// Raw syscall arguments
+pub struct Args(pub [usize; 6]);
+
+type Handler = fn(&Args) -> Result<usize>;
+
+const NSYSCALLS: usize = 384;
+
+/// The dispatch table, indexed by syscall number
+static TABLE: [Option<Handler>; NSYSCALLS] = build_table();
+
+const fn build_table() -> [Option<Handler>; NSYSCALLS] {
+    let mut t: [Option<Handler>; NSYSCALLS] = [None; NSYSCALLS];
+    t[nr::EXIT] = Some(proc::sys_exit);
+    t[nr::FORK] = Some(proc::sys_fork);
+    t[nr::READ] = Some(fs::sys_read);
+    t[nr::WRITE] = Some(fs::sys_write);
+    t[nr::OPEN] = Some(fs::sys_open);
+    t[nr::CLOSE] = Some(fs::sys_close);
+    t[nr::WAITPID] = Some(proc::sys_waitpid);
+    t[nr::UNLINK] = Some(fs::sys_unlink);
+    t[nr::EXECVE] = Some(proc::sys_execve);
+    t[nr::LSEEK] = Some(fs::sys_lseek);
+    t[nr::GETPID] = Some(proc::sys_getpid);
+    t[nr::SETUID] = Some(proc::sys_setuid);
+    t[nr::GETUID] = Some(proc::sys_getuid);
+    t[nr::NICE] = Some(proc::sys_nice);
+    t[nr::RENAME] = Some(fs::sys_rename);
+    t[nr::MKDIR] = Some(fs::sys_mkdir);
+    t[nr::RMDIR] = Some(fs::sys_rmdir);
+    t[nr::DUP] = Some(fs::sys_dup);
+    t[nr::PIPE] = Some(fs::sys_pipe);
+    t[nr::IOCTL] = Some(fs::sys_ioctl);
+    t[nr::DUP2] = Some(fs::sys_dup2);
+    t[nr::GETPPID] = Some(proc::sys_getppid);
+    t[nr::GETTIMEOFDAY] = Some(time::sys_gettimeofday);
+    t[nr::MMAP] = Some(mm::sys_mmap);
+    t[nr::MUNMAP] = Some(mm::sys_munmap);
+    t[nr::WAIT4] = Some(proc::sys_wait4);
+    t[nr::MPROTECT] = Some(mm::sys_mprotect);
+    t[nr::SCHED_SETSCHEDULER] = Some(proc::sys_sched_setscheduler);
+    t[nr::SCHED_YIELD] = Some(proc::sys_sched_yield);
+    t[nr::NANOSLEEP] = Some(time::sys_nanosleep);
+    t[nr::MMAP2] = Some(mm::sys_mmap2);
+    t[nr::EXIT_GROUP] = Some(proc::sys_exit);
+    t[nr::CLOCK_GETTIME] = Some(time::sys_clock_gettime);
+    t[nr::PIPE2] = Some(fs::sys_pipe2);
+    t
+}
+
+/// Run the handler for the syscall number, returns the value to put in the caller's eax
+pub fn dispatch(number: usize, args: &Args) -> isize {
+    let handler = match TABLE.get(number) {
+        Some(Some(h)) => h,
+        _ => return -(ENOSYS as isize),
+    };
+    match handler(args) {
+        Ok(value) => value as isize,
+        Err(code) => -(code as isize),
+    }
+}
+
+/// Check that a buffer passed by a program lies in user space
+pub fn check_user_range(address: usize, len: usize) -> Result<()> {
+    let end = address.checked_add(len).ok_or(EFAULT)?;
+    if address == 0 || end > KERNEL_LINEAR_START {
+        return Err(EFAULT);
+    }
+    Ok(())
+}
+
+/// Check that the current task can access a buffer it passed
+/// Pages it may not touch are kept out, even if the kernel could copy them
+fn check_user_access(address: usize, len: usize, write: bool) -> Result<()> {
+    check_user_range(address, len)?;
+    let space = schedule::current_space().ok_or(EFAULT)?;
+    let allowed = space.read().check_access(address, len, write);
+    if allowed {
+        Ok(())
+    } else {
+        Err(EFAULT)
+    }
+}
+
+/// Copy a buffer passed by a program
+/// Its memory is never borrowed, it may be unmapped or fail to fill meanwhile
+pub fn copy_from_user(dst: &mut [u8], address: usize) -> Result<()> {
+    check_user_access(address, dst.len(), false)?;
+    paging::read_user(dst, address)
+}
+
+/// Copy to a buffer passed by a program
+pub fn copy_to_user(address: usize, src: &[u8]) -> Result<()> {
+    check_user_access(address, src.len(), true)?;
+    paging::write_user(address, src)
+}
+
+/// Read an int passed by address
+pub fn get_user_i32(address: usize) -> Result<i32> {
+    let mut bytes = [0; 4];
+    copy_from_user(&mut bytes, address)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+/// Write an int passed by address
+pub fn put_user_i32(address: usize, value: i32) -> Result<()> {
+    copy_to_user(address, &value.to_le_bytes())
+}
+
+/// Copy a NUL terminated string passed by a program, without the terminator
+/// Fails with ENAMETOOLONG if it is longer than `max` bytes
+pub fn user_str(address: usize, max: usize) -> Result<String> {
+    String::from_utf8(user_cstr(address, max)?).map_err(|_| EINVAL)
+}
+
+/// Copy a NULL terminated array of strings passed by a program, eg. argv
+/// Fails with E2BIG if there are more than `max` of them
+pub fn user_cstr_array(address: usize, max: usize) -> Result<Vec<Vec<u8>>> {
+    let mut strings = Vec::new();
+    // A NULL array is accepted as an empty one
+    if address == 0 {
+        return Ok(strings);
+    }
+    loop {
+        let pointer = get_user_i32(address + strings.len() * 4)? as u32 as usize;
+        if pointer == 0 {
+            return Ok(strings);
+        }
+        if strings.len() == max {
+            return Err(E2BIG);
+        }
+        strings.push(user_cstr(pointer, PAGE_SIZE * 32)?);
+    }
+}
+
+/// Copy a NUL terminated byte string passed by a program, without the terminator
+/// Fails with ENAMETOOLONG if it is longer than `max` bytes
+pub fn user_cstr(address: usize, max: usize) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut chunk = [0u8; 256];
+    loop {
+        let start = address.checked_add(bytes.len()).ok_or(EFAULT)?;
+        // Never past the end of the page, the next one may not be mapped
+        let len = chunk.len().min(PAGE_SIZE - start % PAGE_SIZE);
+        copy_from_user(&mut chunk[..len], start)?;
+        let end = chunk[..len].iter().position(|&b| b == 0);
+        bytes.extend_from_slice(&chunk[..end.unwrap_or(len)]);
+        if bytes.len() > max {
+            return Err(ENAMETOOLONG);
+        }
+        if end.is_some() {
+            return Ok(bytes);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use super::{check_user_access, get_user_i32, put_user_i32, user_cstr_array, user_str, Args};
+use crate::fs::vfs::PATH_MAX;
+use crate::error::{codes::*, Result};
+use crate::proc::policy::Class;
+use crate::proc::process::{Uid, ROOT_UID};
+use crate::proc::{exec, process, schedule};
+use alloc::vec::Vec;
+
+/// sched_yield()
+pub fn sys_sched_yield(_args: &Args) -> Result<usize> {
+    // Nothing to do if no other task is ready
+    let _ = schedule::schedule();
+    Ok(0)
+}
+
+/// Owner of the calling process
+fn current_uid() -> Result<Uid> {
+    process::uid_of(schedule::current_pid().ok_or(ESRCH)?).ok_or(ESRCH)
+}
+
+/// nice(inc), only fair tasks have a nice value
+/// Only root may lower it, to get more CPU time than the others
+pub fn sys_nice(args: &Args) -> Result<usize> {
+    let inc = args.0[0] as i32;
+    if inc < 0 && current_uid()? != ROOT_UID {
+        return Err(EPERM);
+    }
+    if let Class::Fair { nice } = schedule::class_of(0).ok_or(ESRCH)? {
+        let nice = (nice as i32).saturating_add(inc).clamp(-20, 19) as i8;
+        schedule::set_process_class(0, Class::Fair { nice });
+    }
+    Ok(0)
+}
+
+// Policies for sched_setscheduler
+const SCHED_OTHER: usize = 0;
+const SCHED_FIFO: usize = 1;
+const SCHED_RR: usize = 2;
+
+/// sched_setscheduler(pid, policy, *param), param only holds the priority
+/// Only root may enter a real time class, which would starve the others, or change the
+/// processes of another user
+pub fn sys_sched_setscheduler(args: &Args) -> Result<usize> {
+    let [pid, policy, param, ..] = args.0;
+    let uid = current_uid()?;
+    if uid != ROOT_UID && pid != 0 && process::uid_of(pid).ok_or(ESRCH)? != uid {
+        return Err(EPERM);
+    }
+    let priority = get_user_i32(param)?;
+    let class = match (policy, priority) {
+        // Keep the nice value
+        (SCHED_OTHER, 0) => match schedule::class_of(pid).ok_or(ESRCH)? {
+            Class::Fair { nice } => Class::Fair { nice },
+            _ => Class::Fair { nice: 0 },
+        },
+        (SCHED_FIFO, 1..=99) => Class::Fifo {
+            priority: priority as u8,
+        },
+        (SCHED_RR, 1..=99) => Class::RoundRobin {
+            priority: priority as u8,
+        },
+        _ => return Err(EINVAL),
+    };
+    if uid != ROOT_UID && !matches!(class, Class::Fair { .. }) {
+        return Err(EPERM);
+    }
+    if !schedule::set_process_class(pid, class) {
+        return Err(ESRCH);
+    }
+    Ok(0)
+}
+
+/// fork(), returns the id of the child, 0 in the child
+pub fn sys_fork(_args: &Args) -> Result<usize> {
+    schedule::fork()
+}
+
+/// exit(status), also used for exit_group as processes have a single task
+pub fn sys_exit(args: &Args) -> Result<usize> {
+    process::exit_current(process::exited(args.0[0] as i32))
+}
+
+/// getpid()
+pub fn sys_getpid(_args: &Args) -> Result<usize> {
+    schedule::current_pid().ok_or(ESRCH)
+}
+
+/// getuid()
+pub fn sys_getuid(_args: &Args) -> Result<usize> {
+    Ok(current_uid()? as usize)
+}
+
+/// setuid(uid), only root may take another user id, and does not get it back
+pub fn sys_setuid(args: &Args) -> Result<usize> {
+    let uid = args.0[0] as Uid;
+    let current = current_uid()?;
+    if current != ROOT_UID && uid != current {
+        return Err(EPERM);
+    }
+    process::set_uid(schedule::current_pid().ok_or(ESRCH)?, uid);
+    Ok(0)
+}
+
+/// getppid()
+pub fn sys_getppid(_args: &Args) -> Result<usize> {
+    let pid = schedule::current_pid().ok_or(ESRCH)?;
+    process::parent_of(pid).ok_or(ESRCH)
+}
+
+const WNOHANG: usize = 1;
+
+/// waitpid(pid, *status, options), returns the pid of the collected child, 0 if none
+/// exited yet with WNOHANG
+pub fn sys_waitpid(args: &Args) -> Result<usize> {
+    let [pid, status, options, ..] = args.0;
+    let parent = schedule::current_pid().ok_or(ECHILD)?;
+    // Checked before waiting, the child must not be collected for nothing
+    if status != 0 {
+        check_user_access(status, core::mem::size_of::<i32>(), true)?;
+    }
+    let collected = process::EXITED.wait_for(|| match process::try_wait(parent, pid as isize) {
+        Ok(None) if options & WNOHANG == 0 => None,
+        ret => Some(ret),
+    })?;
+    match collected {
+        Some((child, code)) => {
+            if status != 0 {
+                put_user_i32(status, code)?;
+            }
+            Ok(child)
+        }
+        None => Ok(0),
+    }
+}
+
+/// wait4(pid, *status, options, *rusage), resource usage is not reported
+pub fn sys_wait4(args: &Args) -> Result<usize> {
+    sys_waitpid(args)
+}
+
+/// execve(path, argv, envp), only returns on failure
+pub fn sys_execve(args: &Args) -> Result<usize> {
+    let [path, argv, envp, ..] = args.0;
+    let path = user_str(path, PATH_MAX)?;
+    let argv = user_cstr_array(argv, exec::MAX_ARGS)?;
+    let envp = user_cstr_array(envp, exec::MAX_ARGS)?;
+    let argv: Vec<&[u8]> = argv.iter().map(|s| s.as_slice()).collect();
+    let envp: Vec<&[u8]> = envp.iter().map(|s| s.as_slice()).collect();
+    exec::exec(&path, &argv, &envp, schedule::current_user_frame())?;
+    Ok(0)
+}
@@ -0,0 +1,57 @@
+//! Clocks and sleeps, with the 32 bits time_t of i386
+
+use super::{copy_from_user, copy_to_user, Args};
+use crate::driver::timer;
+use crate::error::{codes::*, Result};
+use crate::klib::time::{self, NSEC_PER_SEC};
+
+const CLOCK_REALTIME: usize = 0;
+const CLOCK_MONOTONIC: usize = 1;
+
+/// Write a timespec or a timeval, both are two longs
+fn put_time(address: usize, secs: u64, frac: u64) -> Result<()> {
+    let mut out = [0; 8];
+    out[..4].copy_from_slice(&(secs as i32).to_le_bytes());
+    out[4..].copy_from_slice(&(frac as i32).to_le_bytes());
+    copy_to_user(address, &out)
+}
+
+/// clock_gettime(clockid, tp)
+pub fn sys_clock_gettime(args: &Args) -> Result<usize> {
+    let [clock, tp, ..] = args.0;
+    let ns = match clock {
+        CLOCK_REALTIME => time::realtime_ns(),
+        CLOCK_MONOTONIC => time::monotonic_ns(),
+        // TODO process and thread CPU time clocks
+        _ => return Err(EINVAL),
+    };
+    put_time(tp, ns / NSEC_PER_SEC, ns % NSEC_PER_SEC)?;
+    Ok(0)
+}
+
+/// gettimeofday(tv, tz), the timezone is always UTC
+pub fn sys_gettimeofday(args: &Args) -> Result<usize> {
+    let [tv, tz, ..] = args.0;
+    let ns = time::realtime_ns();
+    if tv != 0 {
+        put_time(tv, ns / NSEC_PER_SEC, ns % NSEC_PER_SEC / 1000)?;
+    }
+    if tz != 0 {
+        // Minutes west of Greenwich and type of DST correction
+        copy_to_user(tz, &[0; 8])?;
+    }
+    Ok(0)
+}
+
+/// nanosleep(req, rem), rem is left alone since nothing interrupts a sleep yet
+pub fn sys_nanosleep(args: &Args) -> Result<usize> {
+    let mut req = [0; 8];
+    copy_from_user(&mut req, args.0[0])?;
+    let secs = i32::from_le_bytes([req[0], req[1], req[2], req[3]]);
+    let nsecs = i32::from_le_bytes([req[4], req[5], req[6], req[7]]);
+    if secs < 0 || !(0..NSEC_PER_SEC as i32).contains(&nsecs) {
+        return Err(EINVAL);
+    }
+    timer::sleep_ns(secs as u64 * NSEC_PER_SEC + nsecs as u64);
+    Ok(0)
+}